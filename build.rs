@@ -0,0 +1,47 @@
+//! build script: embed the kernel symbol table used by `backtrace` symbolization.
+//!
+//! `KROS_SYMBOL_MAP=<file>`: output of `nm -n -C --defined-only <kernel elf>` from a previous build.
+//! the symbol table change the kernel layout, so build twice with the map of the last build
+//! (table size don't change the second time -> address stable).
+//! without the env the table is empty and backtrace only print raw address.
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=KROS_SYMBOL_MAP");
+
+    let mut symbols: Vec<(u64, String)> = Vec::new();
+    if let Ok(path) = env::var("KROS_SYMBOL_MAP") {
+        println!("cargo:rerun-if-changed={}", path);
+        let text = fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("read KROS_SYMBOL_MAP `{}` failed: {}", path, err));
+        symbols = parse_nm(&text);
+    }
+
+    // sorted by address: backtrace binary search the function containing an address.
+    symbols.sort();
+    symbols.dedup_by_key(|(addr, _)| *addr);
+
+    let mut out = String::from("pub static KERNEL_SYMBOLS: &[(u64, &str)] = &[\n");
+    for (addr, name) in &symbols {
+        out.push_str(&format!("    ({:#x}, {:?}),\n", addr, name));
+    }
+    out.push_str("];\n");
+
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("symbols.rs");
+    fs::write(out_path, out).expect("write symbol table failed");
+}
+
+/// parse `nm` lines `<addr> <type> <name>`, keep only text(code) symbols.
+fn parse_nm(text: &str) -> Vec<(u64, String)> {
+    text.lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, ' ');
+            let addr = u64::from_str_radix(parts.next()?, 16).ok()?;
+            let kind = parts.next()?;
+            let name = parts.next()?.trim();
+            matches!(kind, "t" | "T" | "w" | "W").then(|| (addr, name.to_string()))
+        })
+        .collect()
+}
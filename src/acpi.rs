@@ -0,0 +1,190 @@
+//! this module impl minimal ACPI table discovery.
+//! `
+//!     - RSDP: "RSD PTR " on a 16 byte boundary, first 1 KiB of EBDA or 0xE0000..0x100000
+//!     - revision 0: RSDT (32 bit table pointers), revision >= 2: XSDT (64 bit table pointers)
+//!     - find_table: walk RSDT / XSDT, match signature, verify checksum
+//!     - local_apics: MADT ("APIC") processor local APIC entries, local APIC address (+ 64 bit override)
+//! `
+//! tables are read through the physical memory mapping: need `memory::OffsetPageTableWarper::init`.
+
+use core::mem::size_of;
+
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// real mode segment of EBDA at 0x40E
+const EBDA_SEGMENT_PTR: u64 = 0x40E;
+const EBDA_SEARCH_SIZE: u64 = 1024;
+const BIOS_AREA: (u64, u64) = (0xE0000, 0x100000);
+
+// MADT interrupt controller structure types
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+// local APIC flags: enabled, or can be enabled at runtime
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// define why an ACPI table can't be found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// physical memory not mapped yet.
+    NoPhysicalMapping,
+    /// no valid RSDP in the BIOS areas.
+    NoRsdp,
+    /// table with this signature not in RSDT / XSDT.
+    NotFound([u8; 4]),
+    /// table bytes don't sum to 0.
+    BadChecksum([u8; 4]),
+}
+
+/// root system description pointer (revision 2 layout, revision 0 only use the first 20 bytes).
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub signature: [u8; 8],
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    pub rsdt_address: u32,
+    pub length: u32,
+    pub xsdt_address: u64,
+    pub extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// header shared by every system description table.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// multiple APIC description table: header, then variable length interrupt controller structures.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct MadtHeader {
+    pub header: SdtHeader,
+    /// 32 bit physical address of the local APIC of every processor.
+    pub local_apic_address: u32,
+    pub flags: u32,
+}
+
+/// processor local APIC structure (type 0) of the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub flags: u32,
+}
+
+/// read a `T` at physical address `addr` through the physical memory mapping.
+///
+/// # Safety
+/// `addr..addr + size_of::<T>()` must be readable physical memory and any bit pattern a valid `T`.
+pub unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> Result<T, AcpiError> {
+    let virt = memory::phys_to_virt(addr).ok_or(AcpiError::NoPhysicalMapping)?;
+    Ok(virt.as_ptr::<T>().read_unaligned())
+}
+
+// bytes of `addr..addr + len` sum to 0 (mod 256).
+unsafe fn checksum_ok(addr: PhysAddr, len: usize) -> Result<bool, AcpiError> {
+    let virt = memory::phys_to_virt(addr).ok_or(AcpiError::NoPhysicalMapping)?;
+    let bytes = core::slice::from_raw_parts(virt.as_ptr::<u8>(), len);
+    Ok(bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0)
+}
+
+/// find the RSDP in EBDA or the BIOS read-only area.
+pub fn find_rsdp() -> Result<(PhysAddr, Rsdp), AcpiError> {
+    let ebda = u64::from(unsafe { read_phys::<u16>(PhysAddr::new(EBDA_SEGMENT_PTR))? }) << 4;
+    let areas = [(ebda, ebda + EBDA_SEARCH_SIZE), BIOS_AREA];
+
+    for (start, end) in areas.into_iter().filter(|&(start, _)| start != 0) {
+        for addr in (start..end).step_by(16).map(PhysAddr::new) {
+            let rsdp: Rsdp = unsafe { read_phys(addr)? };
+            if &rsdp.signature != RSDP_SIGNATURE {
+                continue;
+            }
+            // revision 0: checksum cover the first 20 bytes only
+            let len = if rsdp.revision >= 2 { rsdp.length as usize } else { 20 };
+            if unsafe { checksum_ok(addr, len)? } {
+                return Ok((addr, rsdp));
+            }
+        }
+    }
+    Err(AcpiError::NoRsdp)
+}
+
+/// find the table with `signature`(e.g. `b"HPET"`), return its physical address and header.
+pub fn find_table(signature: &[u8; 4]) -> Result<(PhysAddr, SdtHeader), AcpiError> {
+    let (_, rsdp) = find_rsdp()?;
+    // XSDT: 64 bit entries, RSDT: 32 bit entries
+    let (root, entry_size) = match rsdp.revision {
+        0 | 1 => (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4),
+        _ => (PhysAddr::new(rsdp.xsdt_address), 8),
+    };
+    let header: SdtHeader = unsafe { read_phys(root)? };
+    let entries = (header.length as usize).saturating_sub(size_of::<SdtHeader>()) / entry_size;
+
+    for i in 0..entries {
+        let entry = root + size_of::<SdtHeader>() + i * entry_size;
+        let addr = match entry_size {
+            4 => u64::from(unsafe { read_phys::<u32>(entry)? }),
+            _ => unsafe { read_phys::<u64>(entry)? },
+        };
+        let table = PhysAddr::new(addr);
+        let header: SdtHeader = unsafe { read_phys(table)? };
+        if &header.signature != signature {
+            continue;
+        }
+        if !unsafe { checksum_ok(table, header.length as usize)? } {
+            return Err(AcpiError::BadChecksum(*signature));
+        }
+        return Ok((table, header));
+    }
+    Err(AcpiError::NotFound(*signature))
+}
+
+/// call `visit` for each usable processor of the MADT, return the local APIC physical address.
+///
+/// disabled processors (neither enabled nor online capable) are skipped.
+pub fn local_apics(mut visit: impl FnMut(LocalApic)) -> Result<PhysAddr, AcpiError> {
+    let (table, _) = find_table(b"APIC")?;
+    let madt: MadtHeader = unsafe { read_phys(table)? };
+    let mut local_apic = PhysAddr::new(u64::from(madt.local_apic_address));
+
+    let end = table + madt.header.length as usize;
+    let mut entry = table + size_of::<MadtHeader>();
+    while entry + 2u64 <= end {
+        let (kind, length): (u8, u8) = unsafe { (read_phys(entry)?, read_phys(entry + 1u64)?) };
+        if length < 2 {
+            break;
+        }
+        match kind {
+            MADT_LOCAL_APIC if length >= 8 => {
+                let processor_id: u8 = unsafe { read_phys(entry + 2u64)? };
+                let apic_id: u8 = unsafe { read_phys(entry + 3u64)? };
+                let flags: u32 = unsafe { read_phys(entry + 4u64)? };
+                if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                    visit(LocalApic { processor_id, apic_id, flags });
+                }
+            }
+            // reserved u16, then the 64 bit address
+            MADT_LOCAL_APIC_OVERRIDE if length >= 12 => {
+                local_apic = PhysAddr::new(unsafe { read_phys::<u64>(entry + 4u64)? });
+            }
+            _ => {}
+        }
+        entry += u64::from(length);
+    }
+    Ok(local_apic)
+}
@@ -24,12 +24,13 @@ use x86_64::{
 };
 
 use alloc::alloc::Layout;
-use spin::{mutex::Mutex, MutexGuard};
+use crate::sync::{IrqSpinlock, IrqSpinlockGuard};
 
 
 // 由于GlobalAlloc 参数是&self,而我们需要对&mut self进行操作，=> Warper(Allocator) => 足够通用可以放置在allocator 父模块中
+// 持锁时关中断：调度器等关中断的路径也会分配，持锁线程不能被时钟中断抢占
 pub struct Locked<T> {
-    inner: Mutex<T>
+    inner: IrqSpinlock<T>
 }
 
 impl <T> Locked<T> {
@@ -37,12 +38,13 @@ impl <T> Locked<T> {
     /// Lock the underlying mutex.
     pub const fn new(value: T) -> Self {
         Locked {
-            inner: Mutex::new(value),
+            inner: IrqSpinlock::new(value),
         }
     }
     
-    /// Lock the underlying mutex.
-    pub fn lock(&self) -> MutexGuard<T> {
+    /// Lock the underlying mutex with interrupts disabled, caller location recorded as holder.
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
         self.inner.lock()
     }
 }
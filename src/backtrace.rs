@@ -0,0 +1,139 @@
+//! this module impl kros kernel stack backtrace.
+//! `
+//!     - frame pointer walk(target spec force `frame-pointer`):
+//!         [rbp]     -> caller rbp
+//!         [rbp + 8] -> return address
+//!     - symbolize: KERNEL_SYMBOLS embedded at build time (build.rs, `KROS_SYMBOL_MAP`)
+//! `
+
+use core::arch::asm;
+use core::fmt;
+
+use x86_64::structures::idt::InterruptStackFrame;
+
+// generated by build.rs: sorted (address, name)
+include!(concat!(env!("OUT_DIR"), "/symbols.rs"));
+
+/// max frames capture once.
+pub const MAX_FRAMES: usize = 32;
+// a frame pointer chain must stay on one stack: stop when the walk run away.
+const MAX_STACK_WALK: u64 = 1024 * 1024;
+
+/// read the frame pointer of the caller (must be inline into the caller).
+#[inline(always)]
+fn read_rbp() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// captured return addresses of a stack.
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// capture the backtrace of the current call stack.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut backtrace = Backtrace::empty();
+        unsafe { backtrace.walk(read_rbp()) };
+        backtrace
+    }
+
+    /// capture the backtrace of the code interrupted by an exception.
+    ///
+    /// must be called directly in the `extern "x86-interrupt"` handler: the handler
+    /// prologue saved the interrupted rbp at [rbp].
+    #[inline(always)]
+    pub fn from_exception(stack_frame: &InterruptStackFrame) -> Self {
+        let interrupted_rbp = unsafe { *(read_rbp() as *const u64) };
+        Self::from_frame(stack_frame.instruction_pointer.as_u64(), interrupted_rbp)
+    }
+
+    /// capture the backtrace start at instruction `rip` with frame pointer `rbp`.
+    pub fn from_frame(rip: u64, rbp: u64) -> Self {
+        let mut backtrace = Backtrace::empty();
+        backtrace.push(rip);
+        unsafe { backtrace.walk(rbp) };
+        backtrace
+    }
+
+    const fn empty() -> Self {
+        Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, rip: u64) -> bool {
+        if self.len == MAX_FRAMES {
+            return false;
+        }
+        self.frames[self.len] = rip;
+        self.len += 1;
+        true
+    }
+
+    /// follow the frame pointer chain.
+    ///
+    /// the chain can be corrupted: stop on null / unaligned / not grow up / too far pointer.
+    unsafe fn walk(&mut self, mut rbp: u64) {
+        let start = rbp;
+        while rbp != 0 && rbp & 0x7 == 0 && rbp - start < MAX_STACK_WALK {
+            let return_addr = *((rbp + 8) as *const u64);
+            if return_addr == 0 || !self.push(return_addr) {
+                break;
+            }
+            let caller_rbp = *(rbp as *const u64);
+            if caller_rbp <= rbp {
+                break;
+            }
+            rbp = caller_rbp;
+        }
+    }
+
+    /// captured return addresses, innermost first.
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+/// find the function containing `addr`, return its name and the offset in it.
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    let index = match KERNEL_SYMBOLS.binary_search_by_key(&addr, |&(start, _)| start) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let (start, name) = KERNEL_SYMBOLS[index];
+    Some((name, addr - start))
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "backtrace:")?;
+        for (index, &rip) in self.frames().iter().enumerate() {
+            match symbolize(rip) {
+                Some((name, offset)) => writeln!(f, "  #{:<2} {:#018x} {}+{:#x}", index, rip, name, offset)?,
+                None => writeln!(f, "  #{:<2} {:#018x} <unknown>", index, rip)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[test_case]
+fn test_capture_backtrace() {
+    let backtrace = Backtrace::capture();
+    // at least: this test -> Testable::run
+    assert!(backtrace.frames().len() >= 2);
+}
+
+#[test_case]
+fn test_from_frame_first_rip() {
+    let backtrace = Backtrace::from_frame(0xdead_beef, 0);
+    assert_eq!(backtrace.frames(), &[0xdead_beef]);
+}
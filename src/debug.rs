@@ -0,0 +1,51 @@
+//! this module impl kros in-kernel debugging support.
+//! `
+//!     - gdb: GDB remote serial protocol stub over COM2
+//!     - monitor: interactive breakpoint monitor on the serial console(COM1)
+//!     - watchpoint: hardware watchpoints with debug registers DR0-DR7
+//!     - memory access checked by page table walk: debugger input can't fault the kernel
+//! `
+
+pub mod gdb;
+pub mod monitor;
+pub mod watchpoint;
+
+pub use watchpoint::{clear_watchpoint, set_watchpoint, WatchKind, WatchpointError};
+
+use x86_64::VirtAddr;
+
+use crate::memory;
+
+/// define a debugger memory access hit an unmapped address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadAddress(pub u64);
+
+/// read kernel memory at `addr` into `buf`, fail if any byte is unmapped.
+pub fn read_memory(addr: u64, buf: &mut [u8]) -> Result<(), BadAddress> {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        let virt = checked_addr(addr, i)?;
+        memory::translate_addr(virt).ok_or(BadAddress(virt.as_u64()))?;
+        *byte = unsafe { virt.as_ptr::<u8>().read_volatile() };
+    }
+    Ok(())
+}
+
+/// write `data` to kernel memory at `addr`, fail if any byte is unmapped.
+///
+/// write through the physical memory mapping: read-only pages (kernel code, breakpoints) can be patched.
+pub fn write_memory(addr: u64, data: &[u8]) -> Result<(), BadAddress> {
+    for (i, &byte) in data.iter().enumerate() {
+        let virt = checked_addr(addr, i)?;
+        let alias = memory::translate_addr(virt)
+            .and_then(memory::phys_to_virt)
+            .ok_or(BadAddress(virt.as_u64()))?;
+        unsafe { alias.as_mut_ptr::<u8>().write_volatile(byte) };
+    }
+    Ok(())
+}
+
+// `addr + offset` as canonical virtual address.
+fn checked_addr(addr: u64, offset: usize) -> Result<VirtAddr, BadAddress> {
+    let addr = addr.checked_add(offset as u64).ok_or(BadAddress(addr))?;
+    VirtAddr::try_new(addr).map_err(|_| BadAddress(addr))
+}
@@ -0,0 +1,407 @@
+//! this module impl a GDB remote serial protocol(RSP) stub over COM2.
+//! `
+//!     - packet: `$<data>#<checksum>` ack `+` / nack `-`
+//!     - enter: int3 / single step(#DB) when enabled -> report `S05`, serve packets until resume
+//!     - commands:
+//!         ?               last stop reason
+//!         g / G           read / write registers
+//!         m / M           read / write memory
+//!         Z0 / z0         insert / remove software breakpoint (int3)
+//!         c / s           continue / single step (RFLAGS.TF)
+//!         D / k           detach / kill
+//! `
+//! qemu: `-serial stdio -serial tcp::1234,server,nowait` then `target remote :1234` in gdb.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sync::Spinlock;
+use uart_16550::SerialPort;
+
+use crate::interrupts::trap::{TrapFrame, RFLAGS_TRAP};
+use crate::serial::SERIAL_2;
+
+use super::{read_memory, write_memory};
+
+/// max packet data size, announced by `qSupported`.
+pub const PACKET_SIZE: usize = 0x400;
+/// max software breakpoints set at the same time.
+pub const MAX_BREAKPOINTS: usize = 32;
+
+const INT3: u8 = 0xcc;
+// SIGTRAP: breakpoint and single step stop reason
+const SIGTRAP: u8 = 5;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// gdb resumed the kernel with `c` / `s` and is waiting for a stop reply.
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+/// define a software breakpoint: address and the original byte replaced by int3.
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    saved: u8,
+}
+
+static BREAKPOINTS: Spinlock<[Option<Breakpoint>; MAX_BREAKPOINTS]> = Spinlock::new([None; MAX_BREAKPOINTS]);
+
+/// enable the stub: next int3 / single step trap is reported to gdb.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// enable the stub and stop here, wait for gdb to attach.
+pub fn breakpoint() {
+    enable();
+    x86_64::instructions::interrupts::int3();
+}
+
+/// enter the stub from a trap, return when gdb resume the kernel.
+pub fn handle_trap(frame: &mut TrapFrame) {
+    let mut port = SERIAL_2.lock();
+    let mut packet = Packet::new();
+    let mut reply = Packet::new();
+
+    // stop reply: only when gdb is waiting for it, a new gdb ask with `?`.
+    if RESUMED.swap(false, Ordering::SeqCst) {
+        let _ = write!(reply, "S{:02x}", SIGTRAP);
+        send_packet(&mut port, reply.as_bytes());
+    }
+
+    loop {
+        receive_packet(&mut port, &mut packet);
+        reply.clear();
+        match execute(packet.as_bytes(), frame, &mut reply) {
+            Resume::Stay => send_packet(&mut port, reply.as_bytes()),
+            Resume::Continue => {
+                RESUMED.store(true, Ordering::SeqCst);
+                return;
+            }
+            Resume::Detach => {
+                send_packet(&mut port, b"OK");
+                ENABLED.store(false, Ordering::SeqCst);
+                return;
+            }
+        }
+    }
+}
+
+/// what to do after executing one command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Stay,
+    Continue,
+    Detach,
+}
+
+/// execute one command packet, write the reply data.
+fn execute(command: &[u8], frame: &mut TrapFrame, reply: &mut Packet) -> Resume {
+    let (&kind, args) = match command.split_first() {
+        Some(split) => split,
+        None => return Resume::Stay,
+    };
+
+    match kind {
+        b'?' => {
+            let _ = write!(reply, "S{:02x}", SIGTRAP);
+        }
+        b'g' => {
+            let (wide, narrow) = gdb_registers(frame);
+            wide.iter().for_each(|&value| reply.push_hex_le(value, 8));
+            narrow.iter().for_each(|&value| reply.push_hex_le(value, 4));
+        }
+        b'G' => match set_gdb_registers(frame, args) {
+            Some(()) => reply.push_str("OK"),
+            None => reply.push_str("E01"),
+        },
+        b'm' => {
+            let mut buf = [0u8; PACKET_SIZE / 2];
+            match parse_addr_len(args).filter(|&(_, len)| len <= buf.len()) {
+                Some((addr, len)) => match read_memory(addr, &mut buf[..len]) {
+                    Ok(()) => buf[..len].iter().for_each(|&byte| reply.push_hex_le(u64::from(byte), 1)),
+                    Err(_) => reply.push_str("E14"),
+                },
+                None => reply.push_str("E01"),
+            }
+        }
+        b'M' => {
+            let mut buf = [0u8; PACKET_SIZE / 2];
+            let mut parts = args.splitn(2, |&c| c == b':');
+            let header = parts.next().and_then(parse_addr_len);
+            let data = parts.next();
+            match (header, data) {
+                (Some((addr, len)), Some(data)) if len <= buf.len() && decode_hex(data, &mut buf[..len]) => {
+                    match write_memory(addr, &buf[..len]) {
+                        Ok(()) => reply.push_str("OK"),
+                        Err(_) => reply.push_str("E14"),
+                    }
+                }
+                _ => reply.push_str("E01"),
+            }
+        }
+        // only software breakpoint(type 0), hardware breakpoint / watchpoint -> empty reply
+        b'Z' | b'z' => if let Some((b'0', rest)) = args.split_first() {
+            let addr = rest.strip_prefix(b",").and_then(parse_addr_len).map(|(addr, _)| addr);
+            let result = match addr {
+                Some(addr) if kind == b'Z' => insert_breakpoint(addr),
+                Some(addr) => remove_breakpoint(addr),
+                None => false,
+            };
+            reply.push_str(if result { "OK" } else { "E01" });
+        },
+        b'c' | b's' => {
+            if let Some(addr) = parse_hex(args) {
+                frame.rip = addr;
+            }
+            if kind == b's' {
+                frame.rflags |= RFLAGS_TRAP;
+            } else {
+                frame.rflags &= !RFLAGS_TRAP;
+            }
+            return Resume::Continue;
+        }
+        b'D' | b'k' => {
+            frame.rflags &= !RFLAGS_TRAP;
+            return Resume::Detach;
+        }
+        b'H' => reply.push_str("OK"),
+        b'q' => {
+            if args.starts_with(b"Supported") {
+                let _ = write!(reply, "PacketSize={:x}", PACKET_SIZE);
+            } else if args.starts_with(b"Attached") {
+                reply.push_str("1");
+            }
+        }
+        // unsupported command -> empty reply
+        _ => {}
+    }
+    Resume::Stay
+}
+
+/// gdb x86_64 `g` packet layout: 17 registers of 8 bytes, 7 registers of 4 bytes.
+fn gdb_registers(frame: &TrapFrame) -> ([u64; 17], [u64; 7]) {
+    let wide = [
+        frame.rax, frame.rbx, frame.rcx, frame.rdx, frame.rsi, frame.rdi, frame.rbp, frame.rsp,
+        frame.r8, frame.r9, frame.r10, frame.r11, frame.r12, frame.r13, frame.r14, frame.r15,
+        frame.rip,
+    ];
+    // eflags, cs, ss, ds, es, fs, gs: data segments are unused (0) in long mode
+    let narrow = [frame.rflags, frame.cs, frame.ss, 0, 0, 0, 0];
+    (wide, narrow)
+}
+
+fn set_gdb_registers(frame: &mut TrapFrame, hex: &[u8]) -> Option<()> {
+    let mut values = [0u64; 18];
+    for (i, value) in values.iter_mut().enumerate() {
+        // 17 registers of 8 bytes, then eflags of 4 bytes
+        let size = if i < 17 { 8 } else { 4 };
+        let chunk = hex.get(i * 16..i * 16 + size * 2)?;
+        let mut bytes = [0u8; 8];
+        if !decode_hex(chunk, &mut bytes[..size]) {
+            return None;
+        }
+        *value = u64::from_le_bytes(bytes);
+    }
+
+    let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip, rflags] = values;
+    *frame = TrapFrame {
+        rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip,
+        // only the arithmetic / trap flags can be changed, keep IF and reserved bits
+        rflags: (frame.rflags & !0xdd5) | (rflags & 0xdd5),
+        ..*frame
+    };
+    Some(())
+}
+
+fn insert_breakpoint(addr: u64) -> bool {
+    let mut breakpoints = BREAKPOINTS.lock();
+    if breakpoints.iter().flatten().any(|bp| bp.addr == addr) {
+        return true;
+    }
+    let slot = match breakpoints.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => slot,
+        None => return false,
+    };
+    let mut saved = [0u8];
+    if read_memory(addr, &mut saved).is_err() || write_memory(addr, &[INT3]).is_err() {
+        return false;
+    }
+    *slot = Some(Breakpoint { addr, saved: saved[0] });
+    true
+}
+
+fn remove_breakpoint(addr: u64) -> bool {
+    let mut breakpoints = BREAKPOINTS.lock();
+    match breakpoints.iter_mut().find(|slot| slot.is_some_and(|bp| bp.addr == addr)) {
+        Some(slot) => {
+            let bp = slot.take().unwrap();
+            write_memory(bp.addr, &[bp.saved]).is_ok()
+        }
+        None => false,
+    }
+}
+
+/// fixed size packet buffer: no heap in trap context.
+struct Packet {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    const fn new() -> Self {
+        Packet {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == PACKET_SIZE {
+            return false;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|byte| {
+            self.push(byte);
+        });
+    }
+
+    /// push the low `size` bytes of `value` as little endian hex (gdb target byte order).
+    fn push_hex_le(&mut self, value: u64, size: usize) {
+        for byte in &value.to_le_bytes()[..size] {
+            self.push(HEX_DIGITS[usize::from(byte >> 4)]);
+            self.push(HEX_DIGITS[usize::from(byte & 0xf)]);
+        }
+    }
+}
+
+impl fmt::Write for Packet {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// parse big endian hex number (addresses, lengths).
+fn parse_hex(hex: &[u8]) -> Option<u64> {
+    if hex.is_empty() || hex.len() > 16 {
+        return None;
+    }
+    hex.iter().try_fold(0u64, |acc, &c| Some(acc << 4 | u64::from(hex_value(c)?)))
+}
+
+/// parse `addr,len` (the `,kind` of breakpoint packets is the len).
+fn parse_addr_len(args: &[u8]) -> Option<(u64, usize)> {
+    let mut parts = args.splitn(2, |&c| c == b',');
+    let addr = parse_hex(parts.next()?)?;
+    let len = parse_hex(parts.next()?)?;
+    Some((addr, usize::try_from(len).ok()?))
+}
+
+/// decode hex byte pairs into `out`, the input must fill `out` exactly.
+fn decode_hex(hex: &[u8], out: &mut [u8]) -> bool {
+    if hex.len() != out.len() * 2 {
+        return false;
+    }
+    for (byte, pair) in out.iter_mut().zip(hex.chunks(2)) {
+        match (hex_value(pair[0]), hex_value(pair[1])) {
+            (Some(high), Some(low)) => *byte = high << 4 | low,
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// wait for one valid packet, ack it, store its data in `packet`.
+fn receive_packet(port: &mut SerialPort, packet: &mut Packet) {
+    loop {
+        // skip until packet start: acks, ctrl-c(0x03) of a running target
+        while port.receive() != b'$' {}
+
+        packet.clear();
+        let mut overflow = false;
+        loop {
+            match port.receive() {
+                b'#' => break,
+                byte => overflow |= !packet.push(byte),
+            }
+        }
+        let expected = (hex_value(port.receive()), hex_value(port.receive()));
+
+        match expected {
+            (Some(high), Some(low)) if !overflow && high << 4 | low == checksum(packet.as_bytes()) => {
+                port.send(b'+');
+                return;
+            }
+            _ => port.send(b'-'),
+        }
+    }
+}
+
+/// send one packet, resend until gdb ack it.
+fn send_packet(port: &mut SerialPort, data: &[u8]) {
+    let sum = checksum(data);
+    loop {
+        port.send(b'$');
+        data.iter().for_each(|&byte| port.send(byte));
+        port.send(b'#');
+        port.send(HEX_DIGITS[usize::from(sum >> 4)]);
+        port.send(HEX_DIGITS[usize::from(sum & 0xf)]);
+
+        match port.receive() {
+            b'-' => continue,
+            _ => return,
+        }
+    }
+}
+
+
+#[test_case]
+fn test_parse_packet_args() {
+    assert_eq!(parse_hex(b"ffff8000"), Some(0xffff_8000));
+    assert_eq!(parse_hex(b"xyz"), None);
+    assert_eq!(parse_addr_len(b"201000,4"), Some((0x20_1000, 4)));
+
+    let mut out = [0u8; 2];
+    assert!(decode_hex(b"cc90", &mut out));
+    assert_eq!(out, [0xcc, 0x90]);
+    assert!(!decode_hex(b"cc9", &mut out));
+}
+
+#[test_case]
+fn test_checksum_and_register_packet() {
+    assert_eq!(checksum(b"OK"), 0x9a);
+
+    let frame = TrapFrame { rax: 0x1122, rip: 0x20_1000, rflags: 0x202, ..TrapFrame::default() };
+    let mut reply = Packet::new();
+    execute(b"g", &mut frame.clone(), &mut reply);
+    // 17 * 8 + 7 * 4 bytes, 2 hex digits each
+    assert_eq!(reply.as_bytes().len(), (17 * 8 + 7 * 4) * 2);
+    assert!(reply.as_bytes().starts_with(b"2211000000000000"));
+}
@@ -0,0 +1,216 @@
+//! this module impl an interactive breakpoint monitor on the serial console(COM1).
+//! `
+//!     - enter: int3 / single step(#DB) when enabled -> `kmon>` prompt
+//!     - commands:
+//!         regs                        register dump
+//!         x <addr> [len]              memory hexdump
+//!         peek <addr> [size]          read 1/2/4/8 bytes
+//!         poke <addr> <value> [size]  write 1/2/4/8 bytes
+//!         translate <addr>            page table walk of an address
+//!         bt                          backtrace of the trapped code
+//!         c / continue, s / step      resume / single step
+//! `
+//! numbers: `0x` prefix hex, else decimal.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::VirtAddr;
+
+use crate::backtrace::Backtrace;
+use crate::interrupts::trap::{TrapFrame, RFLAGS_TRAP};
+use crate::serial::SERIAL_1;
+use crate::{memory, serial_print, serial_println};
+
+use super::{read_memory, write_memory};
+
+const LINE_SIZE: usize = 128;
+const HEXDUMP_DEFAULT: usize = 64;
+const HEXDUMP_MAX: usize = 4096;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// enable the monitor: next int3 / single step trap open the prompt.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// what to do after executing one command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Stay,
+    Continue,
+    Step,
+}
+
+/// enter the monitor from a trap, return when the user continue / step.
+pub fn handle_trap(frame: &mut TrapFrame) {
+    serial_println!("\nkmon: trap {} at {:#018x}, `help` for commands", frame.vector, frame.rip);
+    let mut line = [0u8; LINE_SIZE];
+
+    loop {
+        serial_print!("kmon> ");
+        let len = read_line(&mut line);
+        let command = core::str::from_utf8(&line[..len]).unwrap_or("");
+        match execute(command, frame) {
+            Action::Stay => {}
+            Action::Continue => {
+                frame.rflags &= !RFLAGS_TRAP;
+                return;
+            }
+            Action::Step => {
+                frame.rflags |= RFLAGS_TRAP;
+                return;
+            }
+        }
+    }
+}
+
+/// read one line with echo and backspace, return its length.
+fn read_line(line: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        // don't hold SERIAL_1 while printing
+        let byte = SERIAL_1.lock().receive();
+        match byte {
+            b'\r' | b'\n' => {
+                serial_println!();
+                return len;
+            }
+            0x08 | 0x7f if len > 0 => {
+                len -= 1;
+                serial_print!("\x08 \x08");
+            }
+            0x20..=0x7e if len < line.len() => {
+                line[len] = byte;
+                len += 1;
+                serial_print!("{}", byte as char);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn execute(command: &str, frame: &mut TrapFrame) -> Action {
+    let mut args = command.split_whitespace();
+    let name = match args.next() {
+        Some(name) => name,
+        None => return Action::Stay,
+    };
+    // up to 3 arguments, missing -> None
+    let arg = [args.next(), args.next(), args.next()].map(|arg| arg.map(parse_number));
+
+    match (name, arg) {
+        ("c" | "continue", _) => return Action::Continue,
+        ("s" | "step", _) => return Action::Step,
+        ("regs" | "r", _) => serial_println!("{:#?}", frame),
+        ("bt", _) => serial_println!("{}", Backtrace::from_frame(frame.rip, frame.rbp)),
+        ("x", [Some(Some(addr)), len, _]) => {
+            let len = match len {
+                Some(Some(len)) => len as usize,
+                _ => HEXDUMP_DEFAULT,
+            };
+            hexdump(addr, len.min(HEXDUMP_MAX));
+        }
+        ("peek", [Some(Some(addr)), size, _]) => match access_size(size) {
+            Some(size) => {
+                let mut bytes = [0u8; 8];
+                match read_memory(addr, &mut bytes[..size]) {
+                    Ok(()) => serial_println!("{:#018x}: {:#x}", addr, u64::from_le_bytes(bytes)),
+                    Err(err) => serial_println!("bad address {:#x}", err.0),
+                }
+            }
+            None => serial_println!("size must be 1, 2, 4 or 8"),
+        },
+        ("poke", [Some(Some(addr)), Some(Some(value)), size]) => match access_size(size) {
+            Some(size) => match write_memory(addr, &value.to_le_bytes()[..size]) {
+                Ok(()) => serial_println!("{:#018x} <- {:#x}", addr, value),
+                Err(err) => serial_println!("bad address {:#x}", err.0),
+            },
+            None => serial_println!("size must be 1, 2, 4 or 8"),
+        },
+        ("translate" | "t", [Some(Some(addr)), _, _]) => translate(addr),
+        ("help" | "h", _) => serial_println!(
+            "regs | x <addr> [len] | peek <addr> [size] | poke <addr> <value> [size] | translate <addr> | bt | c | s"
+        ),
+        _ => serial_println!("unknown command or bad argument: `{}`", command),
+    }
+    Action::Stay
+}
+
+/// parse `0x` prefix hex or decimal.
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// peek / poke access size, default 8 bytes.
+fn access_size(size: Option<Option<u64>>) -> Option<usize> {
+    match size {
+        None => Some(8),
+        Some(Some(size @ (1 | 2 | 4 | 8))) => Some(size as usize),
+        Some(_) => None,
+    }
+}
+
+fn hexdump(addr: u64, len: usize) {
+    let mut line = [0u8; 16];
+    for offset in (0..len).step_by(16) {
+        let count = (len - offset).min(16);
+        let line_addr = addr.wrapping_add(offset as u64);
+        if let Err(err) = read_memory(line_addr, &mut line[..count]) {
+            serial_println!("bad address {:#x}", err.0);
+            return;
+        }
+        serial_print!("{:#018x}:", line_addr);
+        line[..count].iter().for_each(|byte| serial_print!(" {:02x}", byte));
+        (count..16).for_each(|_| serial_print!("   "));
+        serial_print!("  |");
+        for &byte in &line[..count] {
+            let c = if (0x20..0x7f).contains(&byte) { byte as char } else { '.' };
+            serial_print!("{}", c);
+        }
+        serial_println!("|");
+    }
+}
+
+fn translate(addr: u64) {
+    let virt = match VirtAddr::try_new(addr) {
+        Ok(virt) => virt,
+        Err(_) => return serial_println!("{:#x} is not a canonical address", addr),
+    };
+    let phys = memory::walk_page_table(virt, |level, entry| {
+        serial_println!("  P{} entry: {:?}", level, entry);
+    });
+    match phys {
+        Some(phys) => serial_println!("{:?} -> {:?}", virt, phys),
+        None => serial_println!("{:?} -> not mapped", virt),
+    }
+}
+
+
+#[test_case]
+fn test_parse_number() {
+    assert_eq!(parse_number("0xb8000"), Some(0xb8000));
+    assert_eq!(parse_number("42"), Some(42));
+    assert_eq!(parse_number("zz"), None);
+    assert_eq!(access_size(Some(Some(3))), None);
+}
+
+#[test_case]
+fn test_execute_resume_commands() {
+    let mut frame = TrapFrame::default();
+    assert_eq!(execute("", &mut frame), Action::Stay);
+    assert_eq!(execute("peek 0x1000 3", &mut frame), Action::Stay);
+    assert_eq!(execute("continue", &mut frame), Action::Continue);
+    assert_eq!(execute("s", &mut frame), Action::Step);
+}
@@ -0,0 +1,206 @@
+//! this module impl hardware watchpoints with the x86 debug registers.
+//! `
+//!     - DR0-DR3: watched linear address (4 slots)
+//!     - DR7: per slot enable, condition(write / read-write / execute), length(1 / 2 / 4 / 8)
+//!     - DR6: which slot hit, checked by the #DB handler in `interrupts`
+//! `
+//! data watchpoint is a trap: reported rip is the instruction after the access.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sync::Spinlock;
+use x86_64::registers::debug::{
+    BreakpointCondition, BreakpointSize, DebugAddressRegister, DebugAddressRegisterNumber,
+    Dr0, Dr1, Dr2, Dr3, Dr6Flags, Dr7, Dr7Flags,
+};
+use x86_64::VirtAddr;
+
+use crate::interrupts::trap::TrapFrame;
+use crate::serial_println;
+
+use super::read_memory;
+
+/// number of debug address registers.
+pub const MAX_WATCHPOINTS: usize = 4;
+
+/// RFLAGS.RF: don't trigger instruction breakpoint again on resume.
+const RFLAGS_RESUME: u64 = 1 << 16;
+
+/// define the access that trigger a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    ReadWrite,
+    Execute,
+}
+
+impl WatchKind {
+    fn condition(self) -> BreakpointCondition {
+        match self {
+            WatchKind::Write => BreakpointCondition::DataWrites,
+            WatchKind::ReadWrite => BreakpointCondition::DataReadsWrites,
+            WatchKind::Execute => BreakpointCondition::InstructionExecution,
+        }
+    }
+}
+
+/// define why a watchpoint can't be set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchpointError {
+    /// all 4 debug address registers in use.
+    NoFreeSlot,
+    /// len must be 1, 2, 4 or 8 (execute: 1).
+    BadLength,
+    /// addr must be aligned to len.
+    Unaligned,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Watchpoint {
+    addr: u64,
+    len: usize,
+    kind: WatchKind,
+    // value at set / last hit: report old -> new
+    value: u64,
+}
+
+static WATCHPOINTS: Spinlock<[Option<Watchpoint>; MAX_WATCHPOINTS]> = Spinlock::new([None; MAX_WATCHPOINTS]);
+static HITS: [AtomicU64; MAX_WATCHPOINTS] = [const { AtomicU64::new(0) }; MAX_WATCHPOINTS];
+
+/// program a free debug register to trap on `kind` access of `len` bytes at `addr`, return the slot.
+pub fn set_watchpoint(addr: VirtAddr, len: usize, kind: WatchKind) -> Result<usize, WatchpointError> {
+    let size = BreakpointSize::new(len).ok_or(WatchpointError::BadLength)?;
+    if kind == WatchKind::Execute && len != 1 {
+        return Err(WatchpointError::BadLength);
+    }
+    if addr.as_u64() & (len as u64 - 1) != 0 {
+        return Err(WatchpointError::Unaligned);
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut watchpoints = WATCHPOINTS.lock();
+        let slot = watchpoints
+            .iter()
+            .position(Option::is_none)
+            .ok_or(WatchpointError::NoFreeSlot)?;
+        let number = register_number(slot);
+
+        let value = match kind {
+            WatchKind::Execute => 0,
+            _ => read_value(addr.as_u64(), len),
+        };
+        watchpoints[slot] = Some(Watchpoint { addr: addr.as_u64(), len, kind, value });
+        HITS[slot].store(0, Ordering::Relaxed);
+
+        write_address_register(number, addr.as_u64());
+        let mut dr7 = Dr7::read();
+        dr7.set_condition(number, kind.condition());
+        dr7.set_size(number, size);
+        dr7.insert_flags(Dr7Flags::global_breakpoint_enable(number));
+        Dr7::write(dr7);
+        Ok(slot)
+    })
+}
+
+/// disable the watchpoint in `slot`.
+pub fn clear_watchpoint(slot: usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut watchpoints = WATCHPOINTS.lock();
+        if let Some(watchpoint) = watchpoints.get_mut(slot) {
+            let number = register_number(slot);
+            let mut dr7 = Dr7::read();
+            dr7.remove_flags(Dr7Flags::global_breakpoint_enable(number));
+            Dr7::write(dr7);
+            write_address_register(number, 0);
+            *watchpoint = None;
+        }
+    });
+}
+
+/// number of hits of the watchpoint in `slot` since it was set.
+pub fn hits(slot: usize) -> u64 {
+    HITS[slot].load(Ordering::Relaxed)
+}
+
+/// report the watchpoint hits recorded in DR6, called by the #DB handler.
+///
+/// Returns whether any watchpoint was hit.
+pub fn handle_hits(frame: &mut TrapFrame, dr6: Dr6Flags) -> bool {
+    // locked by the interrupted code (setting a watchpoint): can't report detail
+    let mut watchpoints = match WATCHPOINTS.try_lock() {
+        Some(watchpoints) => watchpoints,
+        None => return dr6.intersects(Dr6Flags::TRAP),
+    };
+
+    let mut hit = false;
+    for (slot, entry) in watchpoints.iter_mut().enumerate() {
+        let watchpoint = match entry {
+            Some(watchpoint) if dr6.contains(Dr6Flags::trap(register_number(slot))) => watchpoint,
+            _ => continue,
+        };
+        hit = true;
+        HITS[slot].fetch_add(1, Ordering::Relaxed);
+
+        if watchpoint.kind == WatchKind::Execute {
+            serial_println!("WATCHPOINT {}: execute {:#x}", slot, watchpoint.addr);
+            frame.rflags |= RFLAGS_RESUME; // fault-like: resume without hit again
+            continue;
+        }
+        let new = read_value(watchpoint.addr, watchpoint.len);
+        serial_println!(
+            "WATCHPOINT {}: {:?} {:#x} len {}, rip {:#018x} (after access), value {:#x} -> {:#x}",
+            slot, watchpoint.kind, watchpoint.addr, watchpoint.len, frame.rip, watchpoint.value, new
+        );
+        watchpoint.value = new;
+    }
+    hit
+}
+
+/// DR6 is sticky: clear it after the #DB handler has read it.
+pub fn clear_status() {
+    unsafe { asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack, preserves_flags)) };
+}
+
+fn register_number(slot: usize) -> DebugAddressRegisterNumber {
+    DebugAddressRegisterNumber::new(slot as u8).expect("watchpoint slot out of range")
+}
+
+fn write_address_register(number: DebugAddressRegisterNumber, addr: u64) {
+    match number {
+        DebugAddressRegisterNumber::Dr0 => Dr0::write(addr),
+        DebugAddressRegisterNumber::Dr1 => Dr1::write(addr),
+        DebugAddressRegisterNumber::Dr2 => Dr2::write(addr),
+        DebugAddressRegisterNumber::Dr3 => Dr3::write(addr),
+    }
+}
+
+// current value of the watched bytes, 0 if unreadable.
+fn read_value(addr: u64, len: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    match read_memory(addr, &mut bytes[..len]) {
+        Ok(()) => u64::from_le_bytes(bytes),
+        Err(_) => 0,
+    }
+}
+
+
+#[test_case]
+fn test_write_watchpoint_hit() {
+    static WATCHED: AtomicU64 = AtomicU64::new(0);
+
+    let slot = set_watchpoint(VirtAddr::from_ptr(&WATCHED), 8, WatchKind::Write).expect("set watchpoint failed");
+    WATCHED.store(42, Ordering::SeqCst);
+    WATCHED.store(43, Ordering::SeqCst);
+    clear_watchpoint(slot);
+    WATCHED.store(44, Ordering::SeqCst);
+    assert_eq!(hits(slot), 2);
+}
+
+#[test_case]
+fn test_watchpoint_argument_check() {
+    let addr = VirtAddr::new(0x1001);
+    assert_eq!(set_watchpoint(addr, 3, WatchKind::Write), Err(WatchpointError::BadLength));
+    assert_eq!(set_watchpoint(addr, 4, WatchKind::ReadWrite), Err(WatchpointError::Unaligned));
+    assert_eq!(set_watchpoint(addr, 8, WatchKind::Execute), Err(WatchpointError::BadLength));
+}
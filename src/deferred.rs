@@ -0,0 +1,104 @@
+//! this module impl kros deferred work (interrupt bottom halves).
+//! `
+//!     - WorkItem: small unit of work (fn + arg) enqueued by interrupt handlers
+//!     - WORK_QUEUE: lock-free bounded queue (interrupt -> worker)
+//!     - run_pending: drain the queue with interrupts enabled
+//!     - run_worker: kernel idle loop, drain queue then `hlt`
+//! `
+//! interrupt handler only do the minimal hardware work (read port, EOI) and push
+//! a `WorkItem`, the heavy part (decode, print, lock WRITER) run in worker context.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+/// max pending work items, push fail(drop) when full.
+pub const WORK_QUEUE_SIZE: usize = 128;
+
+// queue need heap memory -> init after `allocator::init_heap`
+static WORK_QUEUE: OnceCell<ArrayQueue<WorkItem>> = OnceCell::uninit();
+// count work items dropped: queue full or uninit.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// define one deferred work: `func(arg)` run later in worker context.
+#[derive(Debug, Clone, Copy)]
+pub struct WorkItem {
+    func: fn(usize),
+    arg: usize,
+}
+
+impl WorkItem {
+    pub const fn new(func: fn(usize), arg: usize) -> Self {
+        WorkItem { func, arg }
+    }
+
+    /// execute the work item.
+    pub fn run(self) {
+        (self.func)(self.arg)
+    }
+}
+
+/// init the deferred work queue, must called once after heap init.
+pub fn init() {
+    WORK_QUEUE
+        .try_init_once(|| ArrayQueue::new(WORK_QUEUE_SIZE))
+        .expect("deferred::init should only be called once");
+}
+
+/// enqueue one work item, safe to call from interrupt context (don't lock, don't alloc).
+///
+/// Returns the item back if the queue is full or not initialized yet.
+pub fn schedule(item: WorkItem) -> Result<(), WorkItem> {
+    let result = match WORK_QUEUE.try_get() {
+        Ok(queue) => queue.push(item),
+        Err(_) => Err(item),
+    };
+    if result.is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    result
+}
+
+/// drain all pending work items, return the number of executed items.
+///
+/// must be called with interrupts enabled from non-interrupt context.
+pub fn run_pending() -> usize {
+    let queue = match WORK_QUEUE.try_get() {
+        Ok(queue) => queue,
+        Err(_) => return 0,
+    };
+
+    let mut count = 0;
+    while let Some(item) = queue.pop() {
+        item.run();
+        count += 1;
+    }
+    count
+}
+
+/// whether there is pending work in the queue.
+pub fn has_pending() -> bool {
+    WORK_QUEUE.try_get().is_ok_and(|queue| !queue.is_empty())
+}
+
+/// number of work items dropped since boot.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// kernel worker idle loop: run pending work, sleep with `hlt` when nothing to do.
+pub fn run_worker() -> ! {
+    loop {
+        run_pending();
+
+        // disable interrupt before check: avoid lost wakeup between check and hlt.
+        interrupts::disable();
+        if has_pending() {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt(); // `sti; hlt` atomic
+        }
+    }
+}
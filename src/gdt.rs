@@ -1,14 +1,19 @@
 //! Global Descriptor Table: [u64; 8]  u64 -> VirtAddr pointer
+//!     - segments: kernel code, kernel data, user data, user code (order required by sysret), TSS
 //!     - load TSS
-//!         - privilege_stack_table [VirtAddr; 3],
-//!         - interrupt_stack_table [VirtAddr; 7],
+//!         - privilege_stack_table [VirtAddr; 3],  # [0]: kernel stack of ring 3 -> ring 0, switched with threads
+//!         - interrupt_stack_table [VirtAddr; 7],  # double fault, nmi, machine check, page fault
 //!         - io_map_base u16
+//!     - IST stack guard pages
+//!     - CpuTables: GDT + TSS of an application processor (`smp`), same selectors, its own stacks
 
-use core::ptr::addr_of; // static addr
+use alloc::boxed::Box;
+use core::ptr::{addr_of, addr_of_mut}; // static addr
 
 /// Task Status Segment (TSS)
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
+use x86_64::structures::paging::{mapper::UnmapError, Mapper, Page, Size4KiB};
 use lazy_static::lazy_static;
 
 /// Global Descriptor Table (GDT)
@@ -18,31 +23,126 @@ use x86_64::structures::gdt::{
     SegmentSelector
 };
 
-/// builder once TSS 
+/// builder once TSS: interrupt stack table(IST) index
+///     - double fault
+///     - non-maskable interrupt(NMI)
+///     - machine check(#MC)
+///     - page fault(#PF): kernel stack overflow -> page fault still have a safe stack
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const PAGE_FAULT_IST_INDEX: u16 = 3;
 
-lazy_static!{
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // build stack base addr.
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            // stack start -> end
-            let stack_start = VirtAddr::from_ptr(unsafe {addr_of!(STACK)});
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        // init interrupt stack table finish
-        tss
-    };
+/// number of IST stacks of each processor.
+pub const IST_STACK_COUNT: usize = 4;
+const IST_STACK_NAMES: [&str; IST_STACK_COUNT] = ["double fault", "nmi", "machine check", "page fault"];
+
+const PAGE_SIZE: usize = 4096;
+const IST_STACK_SIZE: usize = PAGE_SIZE * 5;
+
+/// kernel stack of ring 3 -> ring 0 before any thread stack is set (`set_kernel_stack`).
+const PRIVILEGE_STACK_SIZE: usize = PAGE_SIZE * 5;
+
+#[repr(C, align(16))]
+struct PrivilegeStack([u8; PRIVILEGE_STACK_SIZE]);
+
+static mut PRIVILEGE_STACK: PrivilegeStack = PrivilegeStack([0; PRIVILEGE_STACK_SIZE]);
+
+/// one IST stack: low page is guard page (unmapped by `protect_guard_pages`), stack grow down to it.
+#[repr(C, align(4096))]
+struct IstStack {
+    guard: [u8; PAGE_SIZE],
+    stack: [u8; IST_STACK_SIZE],
+}
+
+static mut IST_STACKS: [IstStack; IST_STACK_COUNT] = [const {
+    IstStack {
+        guard: [0; PAGE_SIZE],
+        stack: [0; IST_STACK_SIZE],
+    }
+}; IST_STACK_COUNT];
+
+/// IST stack area: [guard_start, stack_start) guard page, [stack_start, stack_end) usable stack.
+#[derive(Debug, Clone, Copy)]
+pub struct IstStackInfo {
+    pub index: u16,
+    pub name: &'static str,
+    pub guard_start: VirtAddr,
+    pub stack_start: VirtAddr,
+    pub stack_end: VirtAddr,
+}
+
+impl IstStackInfo {
+    /// whether `addr` hit the guard page -> the stack was exhausted.
+    pub fn in_guard_page(&self, addr: VirtAddr) -> bool {
+        addr >= self.guard_start && addr < self.stack_start
+    }
+
+    /// whether `addr` inside the stack or its guard page.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.guard_start && addr < self.stack_end
+    }
+}
+
+/// get the info of IST stack `index`.
+pub fn ist_stack(index: u16) -> IstStackInfo {
+    let stack = unsafe { addr_of!(IST_STACKS[index as usize]) };
+    let guard_start = VirtAddr::from_ptr(stack);
+    let stack_start = guard_start + PAGE_SIZE;
+    IstStackInfo {
+        index,
+        name: IST_STACK_NAMES[index as usize],
+        guard_start,
+        stack_start,
+        stack_end: stack_start + IST_STACK_SIZE,
+    }
+}
+
+/// find the IST stack which `addr` belong to (stack or guard page).
+pub fn find_ist_stack(addr: VirtAddr) -> Option<IstStackInfo> {
+    (0..IST_STACK_COUNT as u16)
+        .map(ist_stack)
+        .find(|info| info.contains(addr))
+}
+
+/// unmap the guard page of every IST stack: stack overflow -> page fault, not silent corruption.
+///
+/// must called after paging init, the guard frame is part of kernel image so not return to allocator.
+pub fn protect_guard_pages(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), UnmapError> {
+    for index in 0..IST_STACK_COUNT as u16 {
+        let page: Page<Size4KiB> = Page::containing_address(ist_stack(index).guard_start);
+        let (_frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+    }
+    Ok(())
+}
+
+// TSS of the bootstrap processor: written by `init` before load, then only `privilege_stack_table[0]`
+// (interrupts disabled). read by the syscall entry: rsp0 at offset 4
+pub(crate) static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// top of the default ring 0 stack (boot flow, no thread stack).
+pub fn default_kernel_stack() -> VirtAddr {
+    let stack = addr_of!(PRIVILEGE_STACK);
+    VirtAddr::from_ptr(stack) + PRIVILEGE_STACK_SIZE
+}
+
+/// set the stack the bootstrap processor switch to on interrupt / syscall from ring 3: the running thread stack top.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { (*addr_of_mut!(TSS)).privilege_stack_table[0] = top };
+}
+
+/// current ring 0 stack of ring 3 -> ring 0.
+pub fn kernel_stack() -> VirtAddr {
+    let stacks = unsafe { (*addr_of!(TSS)).privilege_stack_table }; // packed struct: copy out
+    stacks[0]
 }
 
 
 // load TSS
 // 我们已经创建了一个TSS，现在的问题就是怎么让CPU使用它。不幸的是这事有点繁琐，因为TSS用到了分段系统（历史原因）。
 // 但我们可以不直接加载，而是在[全局描述符表]
+#[derive(Debug)]
 struct GdtSelector {
     gdt: GlobalDescriptorTable,
     selector: Selector,
@@ -57,42 +157,149 @@ impl GdtSelector {
     }
 }
 
+#[derive(Debug)]
 struct Selector {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-impl Selector {
-    fn new(code_selector: SegmentSelector, tss_selector: SegmentSelector) -> Self {
-        Selector {
-            code_selector,
-            tss_selector,
-        }
+
+// GDT with its TSS descriptor pointing to `tss`: every processor get the same selectors
+fn build_gdt(tss: &'static TaskStateSegment) -> GdtSelector {
+    // build GDT 
+    let mut gdt = GlobalDescriptorTable::new();
+    // segment: sysret load user data = kernel code + 16, user code = kernel code + 24 (STAR)
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    // group
+    let selector = Selector {
+        code_selector,
+        data_selector,
+        user_data_selector,
+        user_code_selector,
+        tss_selector,
+    };
+    GdtSelector::new(gdt, selector)
+}
+
+lazy_static!{
+    static ref GDT_SELECTOR: GdtSelector = build_gdt(unsafe { &*addr_of!(TSS) });
+}
+
+// load `gdt`, its TSS and the kernel segments on the calling processor
+fn load(gdt: &'static GlobalDescriptorTable, selector: &Selector) {
+    use x86_64::instructions::tables::load_tss;
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
+
+    gdt.load(); // load gdt
+    unsafe {
+        load_tss(selector.tss_selector); // load tss
+        CS::set_reg(selector.code_selector); // load code
+        // data / stack: bootloader (or trampoline) selectors index another GDT
+        SS::set_reg(selector.data_selector);
+        DS::set_reg(selector.data_selector);
+        ES::set_reg(selector.data_selector);
     }
 }
 
+/// kernel code segment (RPL 0).
+pub fn kernel_code_selector() -> SegmentSelector {
+    GDT_SELECTOR.selector.code_selector
+}
 
-lazy_static!{
-    static ref GDT_SELECTOR: GdtSelector = {
-        // build GDT 
-        let mut gdt = GlobalDescriptorTable::new();
-        // segment
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        // group
-        let selector = Selector::new(code_selector, tss_selector);
-        GdtSelector::new(gdt, selector)
-    };
+/// kernel data / stack segment (RPL 0).
+pub fn kernel_data_selector() -> SegmentSelector {
+    GDT_SELECTOR.selector.data_selector
 }
 
-/// load gdt
+/// user code segment (RPL 3).
+pub fn user_code_selector() -> SegmentSelector {
+    GDT_SELECTOR.selector.user_code_selector
+}
+
+/// user data / stack segment (RPL 3).
+pub fn user_data_selector() -> SegmentSelector {
+    GDT_SELECTOR.selector.user_data_selector
+}
+
+/// load gdt (bootstrap processor)
 pub fn init() {
-    use x86_64::instructions::tables::load_tss;
-    use x86_64::instructions::segmentation::{CS, Segment};
-    
-    GDT_SELECTOR.gdt.load(); // load gdt
+    // stack start -> end: stack grow down, set end addr. (before the TSS descriptor is loaded)
     unsafe {
-        load_tss(GDT_SELECTOR.selector.tss_selector); // load tss
-        CS::set_reg(GDT_SELECTOR.selector.code_selector); // load code
+        let tss = &mut *addr_of_mut!(TSS);
+        for index in 0..IST_STACK_COUNT as u16 {
+            tss.interrupt_stack_table[index as usize] = ist_stack(index).stack_end;
+        }
+        tss.privilege_stack_table[0] = default_kernel_stack();
+    }
+
+    load(&GDT_SELECTOR.gdt, &GDT_SELECTOR.selector);
+}
+
+/// define the GDT + TSS of an application processor: the selectors of `init`, its own stacks.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuTables {
+    gdt: &'static GdtSelector,
+    tss: &'static TaskStateSegment,
+}
+
+impl CpuTables {
+    /// tables of one more processor: IST `interrupt_stacks` (tops), ring 0 `kernel_stack`.
+    ///
+    /// never freed: a started processor is never stopped.
+    pub fn new(interrupt_stacks: [VirtAddr; IST_STACK_COUNT], kernel_stack: VirtAddr) -> Self {
+        let mut tss = TaskStateSegment::new();
+        for (index, top) in interrupt_stacks.into_iter().enumerate() {
+            tss.interrupt_stack_table[index] = top;
+        }
+        tss.privilege_stack_table[0] = kernel_stack;
+        let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+        let gdt: &'static GdtSelector = Box::leak(Box::new(build_gdt(tss)));
+        CpuTables { gdt, tss }
+    }
+
+    /// load the tables on the calling processor.
+    pub fn load(&self) {
+        load(&self.gdt.gdt, &self.gdt.selector);
+    }
+
+    pub fn gdt_address(&self) -> VirtAddr {
+        VirtAddr::from_ptr(&self.gdt.gdt)
     }
+
+    pub fn tss_address(&self) -> VirtAddr {
+        VirtAddr::from_ptr(self.tss)
+    }
+}
+
+
+#[test_case]
+fn test_find_ist_stack() {
+    let page_fault = ist_stack(PAGE_FAULT_IST_INDEX);
+    let ist = unsafe { (*addr_of!(TSS)).interrupt_stack_table }; // packed struct: copy out
+    assert_eq!(ist[PAGE_FAULT_IST_INDEX as usize], page_fault.stack_end);
+
+    let found = find_ist_stack(page_fault.stack_end - 8u64).expect("stack top not found");
+    assert_eq!(found.index, PAGE_FAULT_IST_INDEX);
+    assert!(find_ist_stack(page_fault.guard_start).unwrap().in_guard_page(page_fault.guard_start));
+    assert!(!found.in_guard_page(page_fault.stack_start));
+}
+
+#[test_case]
+fn test_user_selectors() {
+    use x86_64::PrivilegeLevel;
+
+    // sysret layout: user data, user code right after the kernel data segment
+    assert_eq!(kernel_data_selector().index(), kernel_code_selector().index() + 1);
+    assert_eq!(user_data_selector().index(), kernel_code_selector().index() + 2);
+    assert_eq!(user_code_selector().index(), kernel_code_selector().index() + 3);
+    assert_eq!(user_code_selector().rpl(), PrivilegeLevel::Ring3);
+    assert_eq!(user_data_selector().rpl(), PrivilegeLevel::Ring3);
+    assert_eq!(kernel_code_selector().rpl(), PrivilegeLevel::Ring0);
 }
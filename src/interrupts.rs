@@ -8,13 +8,26 @@
 //!             - InterruptStackFrameValue
 //!         - Interrupt_index
 //!    - Prom-interrupt-controller(PIC) -> hardware handler  -> IDT interrupts
-//!         Timer
-//!         Keyboard
+//!         Timer(PIT or HPET timer 0) -> time tick counter, timer wheel deadline check, thread time slice
+//!         RTC(IRQ8) -> periodic interrupt, ack register C (HPET legacy mode: timer 1 one shot)
+//!         Keyboard -> scancode queue, wake ScancodeStream
+//!         Spurious IRQ7 / IRQ15 -> check PIC in-service register
+//!    - InterruptStats: count handled / spurious hardware interrupts
+//!    - YIELD_VECTOR: software `int`, switch to the next ready thread
+//!    - syscall::SYSCALL_VECTOR(int 0x80): system call gate, DPL 3
+//!    - exception from ring 3 (page fault, general protection): SIGSEGV to the user process, not the kernel
+//!    - in_interrupt: interrupt handler nesting depth of this processor, lock owner context
 //! `
 
+pub mod trap;
+
 use crate::{
-    gdt, hlt_loop, print, println
+    backtrace::Backtrace,
+    debug::{gdb, monitor, watchpoint},
+    gdt, hlt_loop, keyboard, println, serial, smp, syscall, thread, time, timer, user
 };
+use crate::sync::IrqSpinlock;
+use trap::{TrapFrame, RFLAGS_TRAP};
 
 use x86_64::structures::idt::{
     InterruptDescriptorTable,
@@ -24,9 +37,9 @@ use x86_64::structures::idt::{
 
 // hardware interrupts agent.
 use pic8259::ChainedPics; 
-use spin::mutex::Mutex;
 
 use lazy_static::lazy_static;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 
 // 可编程中断控制器（PIC）
@@ -35,8 +48,8 @@ use lazy_static::lazy_static;
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8; // used ChainedPics::new_contiguous
 
-pub static PICS: Mutex<ChainedPics> = {
-    Mutex::new(unsafe {
+pub static PICS: IrqSpinlock<ChainedPics> = {
+    IrqSpinlock::new(unsafe {
         ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
     })
 };
@@ -49,6 +62,40 @@ pub static PICS: Mutex<ChainedPics> = {
 pub enum InterruptIndex { 
     Timer = PIC_1_OFFSET,  // hardware: Timer interrupt
     Keyboard,   // handler: Keyboard interrupt
+    SpuriousMaster = PIC_1_OFFSET + 7,  // IRQ7: master PIC spurious (or LPT1)
+    Rtc = PIC_2_OFFSET,   // IRQ8: RTC periodic interrupt
+    SpuriousSlave = PIC_2_OFFSET + 7,   // IRQ15: slave PIC spurious (or secondary ATA)
+}
+
+/// software interrupt of `thread::yield_now`: save the frame and switch thread.
+pub const YIELD_VECTOR: u8 = 0x81;
+
+// nested interrupt handlers running on the bootstrap processor, `smp::Cpu` count its own
+static INTERRUPT_DEPTH: AtomicU64 = AtomicU64::new(0);
+
+fn interrupt_depth() -> &'static AtomicU64 {
+    smp::current_ap().map_or(&INTERRUPT_DEPTH, |cpu| cpu.interrupt_depth())
+}
+
+/// whether an interrupt handler is running on this processor: the running thread was interrupted.
+pub fn in_interrupt() -> bool {
+    interrupt_depth().load(Ordering::Relaxed) > 0
+}
+
+/// define the running handler mark, leave the interrupt context on drop.
+struct InterruptContext;
+
+impl InterruptContext {
+    fn enter() -> Self {
+        interrupt_depth().fetch_add(1, Ordering::Relaxed);
+        InterruptContext
+    }
+}
+
+impl Drop for InterruptContext {
+    fn drop(&mut self) {
+        interrupt_depth().fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl InterruptIndex {
@@ -59,27 +106,137 @@ impl InterruptIndex {
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// PIC irq line(0..16) of this interrupt.
+    fn irq_line(self) -> usize {
+        usize::from(self.as_u8() - PIC_1_OFFSET)
+    }
 }
 
 
+// 8259 command port and OCW3 read in-service register(ISR).
+// spurious irq: PIC raise IRQ7/IRQ15 but the irq is gone when CPU ack it -> ISR bit not set.
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+const PIC_READ_ISR: u8 = 0x0B;
+const PIC_EOI: u8 = 0x20;
+
+/// read the in-service register of one PIC from its command port.
+unsafe fn read_pic_isr(command_port: u16) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    let mut port: Port<u8> = Port::new(command_port);
+    port.write(PIC_READ_ISR);
+    port.read()
+}
+
+/// unmask PIC irq line `line`(0..16), slave line also unmask the cascade(IRQ2).
+pub fn unmask_irq(line: u8) {
+    let mut pics = PICS.lock();
+    let [master, slave] = unsafe { pics.read_masks() };
+    let (master, slave) = match line {
+        0..=7 => (master & !(1 << line), slave),
+        _ => (master & !(1 << 2), slave & !(1 << (line - 8))),
+    };
+    unsafe { pics.write_masks(master, slave) };
+}
+
+/// mask PIC irq line `line`(0..16).
+pub fn mask_irq(line: u8) {
+    let mut pics = PICS.lock();
+    let [master, slave] = unsafe { pics.read_masks() };
+    let (master, slave) = match line {
+        0..=7 => (master | (1 << line), slave),
+        _ => (master, slave | (1 << (line - 8))),
+    };
+    unsafe { pics.write_masks(master, slave) };
+}
+
+/// define which PIC raised a spurious interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Pic {
+    Master = 0,
+    Slave = 1,
+}
+
+/// interrupt statistics: count per PIC irq line and spurious interrupts.
+pub struct InterruptStats {
+    irqs: [AtomicU64; 16],
+    spurious: [AtomicU64; 2],
+}
+
+impl InterruptStats {
+    const fn new() -> Self {
+        InterruptStats {
+            irqs: [const { AtomicU64::new(0) }; 16],
+            spurious: [const { AtomicU64::new(0) }; 2],
+        }
+    }
+
+    fn record_irq(&self, index: InterruptIndex) {
+        self.irqs[index.irq_line()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_spurious(&self, pic: Pic) {
+        self.spurious[pic as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// number of real interrupts handled on PIC irq line `line`(0..16).
+    pub fn irq_count(&self, line: usize) -> u64 {
+        self.irqs[line].load(Ordering::Relaxed)
+    }
+
+    /// number of spurious interrupts raised by `pic`.
+    pub fn spurious_count(&self, pic: Pic) -> u64 {
+        self.spurious[pic as usize].load(Ordering::Relaxed)
+    }
+}
+
+pub static STATS: InterruptStats = InterruptStats::new();
+
+
 // nice func used heap memory -> 'static life, but None. 
 // used lazy load handle: error[E0597]: `idt` does not live long enough
 lazy_static!{
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        // debugger: full register state -> trap entry stub
+        unsafe {
+            idt.breakpoint.set_handler_addr(trap::breakpoint_entry());
+            idt.debug.set_handler_addr(trap::debug_entry());
+        }
 
-        // handler double fault
+        // handler double fault, nmi, machine check, page fault
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 // double fault change safe stack.
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt.set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check.set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+            // kernel stack overflow -> page fault: handler can't use the overflowed stack.
+            idt.page_fault.set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
+        // user code fault: end the thread (kernel fault: panic)
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+
+        // timer / yield: thread context switch -> trap entry stub
+        unsafe {
+            idt[InterruptIndex::Timer.as_usize()].set_handler_addr(trap::timer_entry());
+            idt[usize::from(YIELD_VECTOR)].set_handler_addr(trap::yield_entry());
+            // system call gate: `int 0x80` allowed from ring 3
+            idt[usize::from(syscall::SYSCALL_VECTOR)].set_handler_addr(trap::syscall_entry())
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
         }
 
         // hardware handler
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::SpuriousMaster.as_usize()].set_handler_fn(spurious_master_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::SpuriousSlave.as_usize()].set_handler_fn(spurious_slave_handler);
 
         idt
     };
@@ -92,70 +249,152 @@ pub fn init_idt() {
     IDT.load(); // need lidt(Load Interrupt Descriptor Table Register)
 }
 
-// create func used handle breakpoint.
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+// create func used handle breakpoint: gdb stub / monitor enabled -> hand over to it.
+fn breakpoint_handler(frame: &mut TrapFrame) {
+    if gdb::is_enabled() {
+        gdb::handle_trap(frame);
+        return;
+    }
+    if monitor::is_enabled() {
+        monitor::handle_trap(frame);
+        return;
+    }
+    println!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
+}
+
+// create func used handle debug exception(#DB): watchpoint hit / single step trap.
+fn debug_handler(frame: &mut TrapFrame) {
+    use x86_64::registers::debug::{Dr6, Dr6Flags};
+
+    let dr6 = Dr6::read();
+    watchpoint::clear_status();
+    let watch_hit = dr6.intersects(Dr6Flags::TRAP) && watchpoint::handle_hits(frame, dr6);
+
+    if gdb::is_enabled() {
+        gdb::handle_trap(frame);
+        return;
+    }
+    let stepping = dr6.contains(Dr6Flags::STEP);
+    if monitor::is_enabled() && (stepping || watch_hit) {
+        monitor::handle_trap(frame);
+        return;
+    }
+    if !watch_hit {
+        println!("EXCEPTION: DEBUG\n{:#?}", frame);
+        frame.rflags &= !RFLAGS_TRAP; // nobody stepping: stop trap
+    }
 }
 
 // create func used handle page fault.
-extern "x86-interrupt" fn page_fault_handler(_stack_frame: InterruptStackFrame, _error_code: PageFaultErrorCode) {
+extern "x86-interrupt" fn page_fault_handler(mut _stack_frame: InterruptStackFrame, _error_code: PageFaultErrorCode) {
+    let context = InterruptContext::enter();
+    // C2 register： page fault -> cpu auto write to exception virtual addr.
+    use x86_64::registers::control::Cr2;
+    let accessed = Cr2::read();
+
+    // write to a copy-on-write page (after fork): private copy, retry the write
+    let write_protected = PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION;
+    if _error_code.contains(write_protected) && user::copy_on_write(accessed) {
+        return;
+    }
+
+    // user code: SIGSEGV to the faulting process, kernel keep running
+    if user::from_user(&_stack_frame) {
+        // handled for the thread like a system call: it may exit and never return here
+        drop(context);
+        let kind = user::FaultKind::PageFault { addr: accessed, error_code: _error_code };
+        user::handle_fault(&mut _stack_frame, 14, _error_code.bits(), kind);
+        return;
+    }
+
     println!("EXCEPTION: PAGE_FAULT\n{:#?}", _stack_frame);
     println!("ERROR CODE: {:#?}", _error_code);
+    println!("Accessed Address: {:?}", accessed); // error address 6
 
-    // C2 register： page fault -> cpu auto write to exception virtual addr.
-    use x86_64::registers::control::Cr2;
-    println!("Accessed Address: {:?}", Cr2::read()); // error address 6
+    // hit an IST stack guard page -> that stack is exhausted.
+    if let Some(stack) = gdt::find_ist_stack(accessed).filter(|stack| stack.in_guard_page(accessed)) {
+        println!("STACK OVERFLOW: {} stack exhausted", stack.name);
+    } else if thread::stack::in_guard_page(accessed) {
+        println!("STACK OVERFLOW: thread stack exhausted");
+    } else if accessed.as_u64().abs_diff(_stack_frame.stack_pointer.as_u64()) < 4096 {
+        // fault address next to the interrupted stack pointer -> kernel stack guard page
+        println!("STACK OVERFLOW: kernel stack exhausted");
+    }
+    println!("{}", Backtrace::from_exception(&_stack_frame));
     hlt_loop();
 }
 
+// create func used handle general protection fault: privileged instruction / bad segment from user code.
+extern "x86-interrupt" fn general_protection_fault_handler(mut stack_frame: InterruptStackFrame, error_code: u64) {
+    let context = InterruptContext::enter();
+    if user::from_user(&stack_frame) {
+        drop(context);
+        user::handle_fault(&mut stack_frame, 13, error_code, user::FaultKind::GeneralProtection { error_code });
+        return;
+    }
+    panic!("EXCEPTION: GENERAL PROTECTION FAULT (error code {:#x})\n{:#?}", error_code, stack_frame);
+}
+
 // create func used handle double fault.
+// report which stack was exhausted and the last known stack pointer.
 extern "x86-interrupt" fn double_fault_handler(_stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", _stack_frame);
+    let _context = InterruptContext::enter();
+    use x86_64::registers::control::Cr2;
+
+    let backtrace = Backtrace::from_exception(&_stack_frame);
+    let stack_pointer = _stack_frame.stack_pointer;
+    let accessed = Cr2::read();
+    // fault on guard page: Cr2 in guard; else the stack pointer tell which stack in use.
+    let exhausted = gdt::find_ist_stack(accessed)
+        .filter(|stack| stack.in_guard_page(accessed))
+        .or_else(|| gdt::find_ist_stack(stack_pointer))
+        .map_or("kernel", |stack| stack.name);
+    panic!(
+        "EXCEPTION: DOUBLE FAULT\nstack: {} (last stack pointer: {:?}, last fault address: {:?})\n{:#?}\n{}",
+        exhausted, stack_pointer, accessed, _stack_frame, backtrace
+    );
 }
 
-/// create func used handler hardware.
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+// create func used handle non-maskable interrupt(NMI): hardware error / watchdog.
+// not maskable: the interrupted code may hold WRITER / SERIAL_1, print to COM1 without a lock.
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    let _context = InterruptContext::enter();
+    serial::_print_unlocked(format_args!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}\n", stack_frame));
+}
+
+// create func used handle machine check: hardware error, can't continue.
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    let _context = InterruptContext::enter();
+    panic!("EXCEPTION: MACHINE CHECK\n{:#?}", stack_frame);
+}
+
+/// create func used handler hardware: called by the timer trap entry, return the frame to resume.
+fn timer_interrupt_handler(frame: &mut TrapFrame) -> *mut TrapFrame {
     // print!(".");
+    time::tick();
+    timer::on_tick(time::ticks());
+    STATS.record_irq(InterruptIndex::Timer);
     // used EOI over handler -> unsafe
     unsafe {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
+    // EOI before switch: the next thread get the following ticks
+    thread::on_tick(frame)
 }
 
 /// create func used handler keyboard.
+/// top half: only read the scan code and push it to the scancode queue, decode in async task.
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-
-    use spin::Mutex;
+    let _context = InterruptContext::enter();
     use x86_64::instructions::port::Port;
-    // used pc_keyboard: get scan_code end this interrupt
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-
-    // create once keyboard global.
-    lazy_static!{
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Uk105Key, ScancodeSet1>> = 
-            Mutex::new(
-                Keyboard::new(
-                    ScancodeSet1::new(), 
-                    layouts::Uk105Key, 
-                    HandleControl::Ignore,
-                )
-            );
-    }
-    
-    // ps/2 port addr; USB comp PS/2 .so used ps/2 can normal used. => USB
-    let mut keyboard = KEYBOARD.lock();
-    let mut ps2_port = Port::new(0x60);
+
+    // read scan code end this interrupt, keyboard don't send next until read.
+    let mut ps2_port = Port::new(keyboard::PS2_DATA_PORT);
     let scan_code: u8 = unsafe {ps2_port.read()};
 
-    // pc_keyboard handler scan code.
-    if let Ok(Some(key_event)) = keyboard.add_byte(scan_code) { // Result<Option<KeyEvent>, Error>
-        if let Some(dec_key) = keyboard.process_keyevent(key_event) {
-            match dec_key {
-                DecodedKey::RawKey(dec_key) => print!("{:?}", dec_key),
-                DecodedKey::Unicode(character) => print!("{}", character),
-            }
-        }
-    }
+    // queue full or no stream: drop the scan code (counted in `keyboard::dropped`).
+    keyboard::add_scancode(scan_code);
+    STATS.record_irq(InterruptIndex::Keyboard);
 
     // EOI end keyboard interrupt.
     unsafe {
@@ -164,6 +403,56 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 
 }
 
+/// create func used handler RTC periodic interrupt(IRQ8), HPET timer 1 in legacy replacement mode.
+extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let _context = InterruptContext::enter();
+    if time::hpet::is_legacy() {
+        time::hpet::handle_one_shot();
+    } else {
+        time::rtc::handle_interrupt();
+    }
+    STATS.record_irq(InterruptIndex::Rtc);
+    // slave irq: EOI both PICs
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Rtc.as_u8());
+    }
+}
+
+/// create func used handler master PIC IRQ7.
+/// spurious: ISR bit 7 clear -> don't send EOI (PIC not wait for it).
+extern "x86-interrupt" fn spurious_master_handler(_stack_frame: InterruptStackFrame) {
+    let _context = InterruptContext::enter();
+    use x86_64::instructions::port::Port;
+
+    let _pics = PICS.lock(); // hold lock: command port sequence not interleave
+    let isr = unsafe { read_pic_isr(PIC_1_COMMAND) };
+    if isr & (1 << 7) != 0 {
+        // real IRQ7 (no driver): only ack it
+        STATS.record_irq(InterruptIndex::SpuriousMaster);
+        unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
+    } else {
+        STATS.record_spurious(Pic::Master);
+    }
+}
+
+/// create func used handler slave PIC IRQ15.
+/// spurious: ISR bit 7 clear -> EOI only master, it see a real IRQ2(cascade) from slave.
+extern "x86-interrupt" fn spurious_slave_handler(_stack_frame: InterruptStackFrame) {
+    let _context = InterruptContext::enter();
+    use x86_64::instructions::port::Port;
+
+    let mut pics = PICS.lock();
+    let isr = unsafe { read_pic_isr(PIC_2_COMMAND) };
+    if isr & (1 << 7) != 0 {
+        // real IRQ15 (no driver): ack both PICs
+        STATS.record_irq(InterruptIndex::SpuriousSlave);
+        unsafe { pics.notify_end_of_interrupt(InterruptIndex::SpuriousSlave.as_u8()) };
+    } else {
+        STATS.record_spurious(Pic::Slave);
+        unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
+    }
+}
+
 // test breakpoint
 #[test_case]
 fn test_breakpoint_exception() {
@@ -171,6 +460,16 @@ fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
 
+// test spurious: software `int` don't set the PIC ISR bit -> counted as spurious
+#[test_case]
+fn test_spurious_master_irq() {
+    let spurious = STATS.spurious_count(Pic::Master);
+    let irqs = STATS.irq_count(InterruptIndex::SpuriousMaster.irq_line());
+    unsafe { core::arch::asm!("int 39") }; // PIC_1_OFFSET + 7
+    assert_eq!(STATS.spurious_count(Pic::Master), spurious + 1);
+    assert_eq!(STATS.irq_count(InterruptIndex::SpuriousMaster.irq_line()), irqs);
+}
+
 // // test breakpoint
 // #[test_case]
 // fn test_pagefault_exception() {
@@ -0,0 +1,238 @@
+//! this module impl kros trap entry: save all registers of the interrupted code.
+//! `
+//!     - `extern "x86-interrupt"` handler can't read / write general registers,
+//!       debugger(int3, #DB) need the full register state -> assembly entry stub.
+//!     - trap_entry_<name>: push (error code), vector -> trap_common
+//!     - trap_common: push general registers -> TrapFrame -> trap_dispatch(frame)
+//!     - trap_dispatch return the frame to resume -> pop registers -> iretq
+//!     - timer / yield: the returned frame may belong to another thread -> context switch
+//!     - int 0x80: system call gate callable from ring 3
+//!     - user fault: the exception handler resume trap_entry_user_fault -> same path, vector 13 / 14
+//!     - back to ring 3: pending signals of the process delivered into the frame first
+//! `
+
+use core::arch::global_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use crate::process::signal;
+use crate::{gdt, syscall, thread};
+
+/// saved registers of the interrupted code, lowest address first (pushed last).
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // pushed by entry stub
+    pub vector: u64,
+    pub error_code: u64,
+    // pushed by CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// RFLAGS.TF: trap after each instruction (single step).
+pub const RFLAGS_TRAP: u64 = 1 << 8;
+/// RFLAGS.IF: maskable interrupts enabled.
+pub const RFLAGS_INTERRUPT: u64 = 1 << 9;
+
+impl fmt::Debug for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "TrapFrame {{ vector: {}, error_code: {:#x} }}", self.vector, self.error_code)?;
+        writeln!(f, "  rip: {:#018x} cs: {:#06x} rflags: {:#010x}", self.rip, self.cs, self.rflags)?;
+        writeln!(f, "  rsp: {:#018x} ss: {:#06x}", self.rsp, self.ss)?;
+        writeln!(f, "  rax: {:#018x} rbx: {:#018x} rcx: {:#018x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "  rdx: {:#018x} rsi: {:#018x} rdi: {:#018x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "  rbp: {:#018x} r8:  {:#018x} r9:  {:#018x}", self.rbp, self.r8, self.r9)?;
+        writeln!(f, "  r10: {:#018x} r11: {:#018x} r12: {:#018x}", self.r10, self.r11, self.r12)?;
+        write!(f, "  r13: {:#018x} r14: {:#018x} r15: {:#018x}", self.r13, self.r14, self.r15)
+    }
+}
+
+// entry stub: vector without CPU error code -> push a 0 so every TrapFrame has the same layout.
+macro_rules! trap_entry {
+    ($name:literal, $vector:literal) => {
+        concat!(".global ", $name, "\n", $name, ":\n    push 0\n    push ", $vector, "\n    jmp trap_common\n")
+    };
+    ($name:literal, $vector:literal, error_code) => {
+        concat!(".global ", $name, "\n", $name, ":\n    push ", $vector, "\n    jmp trap_common\n")
+    };
+}
+
+global_asm!(
+    trap_entry!("trap_entry_debug", 1),
+    trap_entry!("trap_entry_breakpoint", 3),
+    trap_entry!("trap_entry_timer", 32),
+    trap_entry!("trap_entry_yield", 129),
+    trap_entry!("trap_entry_syscall", 128),
+    // saved vector, error code, iret frame of a user fault back on the thread stack
+    ".global trap_entry_user_fault",
+    "trap_entry_user_fault:",
+    "    push qword ptr [rip + {user_fault} + 48]", // ss
+    "    push qword ptr [rip + {user_fault} + 40]", // rsp
+    "    push qword ptr [rip + {user_fault} + 32]", // rflags
+    "    push qword ptr [rip + {user_fault} + 24]", // cs
+    "    push qword ptr [rip + {user_fault} + 16]", // rip
+    "    push qword ptr [rip + {user_fault} + 8]",  // error code
+    "    push qword ptr [rip + {user_fault}]",      // vector
+    "trap_common:",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    // CPU align rsp to 16 before push its frame: 22 * 8 bytes pushed -> still aligned for call
+    "    mov rdi, rsp",
+    "    cld",
+    "    call {dispatch}",
+    // resume the returned frame
+    "    mov rsp, rax",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    "    add rsp, 16", // vector, error code
+    "    iretq",
+    dispatch = sym trap_dispatch,
+    user_fault = sym USER_FAULT,
+);
+
+extern "C" {
+    fn trap_entry_debug();
+    fn trap_entry_breakpoint();
+    fn trap_entry_timer();
+    fn trap_entry_yield();
+    fn trap_entry_syscall();
+    fn trap_entry_user_fault();
+}
+
+/// entry stub addresses for `Entry::set_handler_addr`.
+pub fn debug_entry() -> VirtAddr {
+    VirtAddr::from_ptr(trap_entry_debug as *const ())
+}
+
+pub fn breakpoint_entry() -> VirtAddr {
+    VirtAddr::from_ptr(trap_entry_breakpoint as *const ())
+}
+
+pub fn timer_entry() -> VirtAddr {
+    VirtAddr::from_ptr(trap_entry_timer as *const ())
+}
+
+pub fn yield_entry() -> VirtAddr {
+    VirtAddr::from_ptr(trap_entry_yield as *const ())
+}
+
+pub fn syscall_entry() -> VirtAddr {
+    VirtAddr::from_ptr(trap_entry_syscall as *const ())
+}
+
+// vector, error code, rip, cs, rflags, rsp, ss of the user fault being redirected.
+// written by the exception handler, read by trap_entry_user_fault: interrupts disabled in between, one CPU
+static USER_FAULT: [AtomicU64; 7] = [const { AtomicU64::new(0) }; 7];
+
+/// make a fault from ring 3 continue in `trap_entry_user_fault` on the thread stack: the fault
+/// then go through `trap_dispatch` with every register saved (signal delivery, SIGSEGV handler).
+///
+/// the `extern "x86-interrupt"` handler restore the user registers, iretq to the entry stub.
+pub(crate) fn redirect_user_fault(stack_frame: &mut InterruptStackFrame, vector: u8, error_code: u64) {
+    let saved = [
+        u64::from(vector),
+        error_code,
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.code_segment,
+        stack_frame.cpu_flags,
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.stack_segment,
+    ];
+    for (slot, value) in USER_FAULT.iter().zip(saved) {
+        slot.store(value, Ordering::Relaxed);
+    }
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::from_ptr(trap_entry_user_fault as *const ());
+            frame.code_segment = u64::from(gdt::kernel_code_selector().0);
+            frame.cpu_flags = 0x2; // interrupts disabled until USER_FAULT is pushed
+            frame.stack_pointer = gdt::kernel_stack(); // aligned top: 22 pushes keep the call aligned
+            frame.stack_segment = u64::from(gdt::kernel_data_selector().0);
+        });
+    }
+}
+
+// vectors of the entry stubs above
+const TIMER_VECTOR: u64 = super::InterruptIndex::Timer as u64;
+const YIELD_VECTOR: u64 = super::YIELD_VECTOR as u64;
+const SYSCALL_VECTOR: u64 = syscall::SYSCALL_VECTOR as u64;
+const GENERAL_PROTECTION_VECTOR: u64 = 13;
+const PAGE_FAULT_VECTOR: u64 = 14;
+
+/// rust side of every trap: dispatch by vector, return the frame to resume.
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) -> *mut TrapFrame {
+    // yield / system call: a call of the running thread, not an interrupt
+    let requested = frame.vector == YIELD_VECTOR || frame.vector == SYSCALL_VECTOR;
+    let _context = (!requested).then(super::InterruptContext::enter);
+    let next: *mut TrapFrame = match frame.vector {
+        1 => {
+            super::debug_handler(frame);
+            frame
+        }
+        3 => {
+            super::breakpoint_handler(frame);
+            frame
+        }
+        // may switch thread: resume the frame saved on another thread stack
+        TIMER_VECTOR => super::timer_interrupt_handler(frame),
+        YIELD_VECTOR => thread::switch(frame),
+        SYSCALL_VECTOR => {
+            syscall::handle(frame);
+            frame
+        }
+        // user fault already turned into a pending signal (`redirect_user_fault`)
+        GENERAL_PROTECTION_VECTOR | PAGE_FAULT_VECTOR => frame,
+        vector => panic!("unhandled trap vector {}\n{:#?}", vector, frame),
+    };
+    // back to ring 3 (maybe another thread): pending signals first
+    signal::deliver(unsafe { &mut *next });
+    next
+}
@@ -0,0 +1,117 @@
+//! this module impl kros PS/2 keyboard.
+//! `
+//!     - interrupt (top half): read scan code from port 0x60 -> SCANCODE_QUEUE, wake the stream
+//!     - ScancodeStream: `futures_util::Stream` of scan codes, pending when the queue is empty
+//!     - print_keypresses (async task): pc_keyboard decode scan code -> print
+//!     - Ctrl+C: SIGINT to the foreground process (`process::signal::set_foreground`)
+//! `
+//! interrupt handler never lock WRITER: decode and print run in task context.
+
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+
+use crate::print;
+use crate::process::signal;
+
+/// ps/2 data port addr; USB comp PS/2 .so used ps/2 can normal used. => USB
+pub const PS2_DATA_PORT: u16 = 0x60;
+
+/// max scan codes waiting for the stream, push fail(drop) when full.
+pub const SCANCODE_QUEUE_SIZE: usize = 100;
+
+// queue need heap memory -> created by `ScancodeStream::new`
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+// count scan codes dropped: queue full or no stream yet.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// called by the keyboard interrupt handler(also inject scan codes in tests), don't lock, don't alloc.
+pub fn add_scancode(scancode: u8) {
+    let pushed = match SCANCODE_QUEUE.try_get() {
+        Ok(queue) => queue.push(scancode).is_ok(),
+        Err(_) => false,
+    };
+    if pushed {
+        WAKER.wake();
+    } else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// number of scan codes dropped since boot.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// define the stream of keyboard scan codes, only one can exist.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        SCANCODE_QUEUE
+            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
+            .expect("ScancodeStream::new should only be called once");
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
+
+        // fast path
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        // register before the second check: a push in between still wake us
+        WAKER.register(context.waker());
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// async task: decode scan codes and print the keys.
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    let mut keyboard = Keyboard::new(ScancodeSet1::new(), layouts::Uk105Key, HandleControl::MapLettersToUnicode);
+
+    while let Some(scancode) = scancodes.next().await {
+        // pc_keyboard handler scan code.
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) { // Result<Option<KeyEvent>, Error>
+            if let Some(dec_key) = keyboard.process_keyevent(key_event) {
+                match dec_key {
+                    // Ctrl+C: ETX
+                    DecodedKey::Unicode('\u{3}') => {
+                        print!("^C");
+                        signal::interrupt_foreground();
+                    }
+                    DecodedKey::RawKey(dec_key) => print!("{:?}", dec_key),
+                    DecodedKey::Unicode(character) => print!("{}", character),
+                }
+            }
+        }
+    }
+}
@@ -16,6 +16,20 @@ pub mod interrupts; // export
 pub mod gdt; // export
 pub mod memory; // export
 pub mod allocator; // export
+pub mod deferred; // export
+pub mod keyboard; // export
+pub mod backtrace; // export
+pub mod debug; // export
+pub mod acpi; // export
+pub mod time; // export
+pub mod timer; // export
+pub mod task; // export
+pub mod thread; // export
+pub mod sync; // export
+pub mod user; // export
+pub mod syscall; // export
+pub mod process; // export
+pub mod smp; // export
 
 
 #[cfg(test)]
@@ -25,11 +39,15 @@ use bootloader::{entry_point, BootInfo};
 /// - memory
 /// - interrupt
 /// - gdt
+/// - syscall
+/// - time
 pub fn init() {
     gdt::init();
+    syscall::init(); // syscall / sysret MSRs
     interrupts::init_idt();
     // Prom-interrupt-control(PIC) 
     unsafe { interrupts::PICS.lock().initialize() }; // init
+    time::init(); // PIT rate
     x86_64::instructions::interrupts::enable(); // enable interrupt
     // memory init
 }
@@ -66,6 +84,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}", backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -12,6 +12,7 @@ use x86_64::VirtAddr; // virtual address
 
 use kros::println; // point real low inner and param info
 use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper}; // virtual map physical
+use kros::task::{executor::Executor, Task}; // async task
 
 
 // entry point
@@ -36,8 +37,25 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut frame_allocator = unsafe {
         BootInfoFrameAllocator::init(&boot_info.memory_map)
     };
+    // IST stack guard pages: need page table
+    kros::gdt::protect_guard_pages(&mut mapper).expect("IST guard pages unmap failed");
     kros::allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
 
+    // deferred work queue (interrupt bottom halves) used heap
+    kros::deferred::init();
+    // HPET: map registers found by ACPI -> clocksource, PIT keep the tick
+    if let Err(err) = kros::time::init_hpet(&mut mapper, &mut frame_allocator) {
+        println!("HPET unavailable: {:?}", err);
+    }
+    // page table + frame allocator owned by the kernel from here: thread stacks map on spawn
+    kros::memory::init_kernel_memory(mapper, frame_allocator);
+    kros::thread::init().expect("thread init failed");
+
+    // gdb remote stub on COM2: stop here and wait for gdb attach
+    // kros::debug::gdb::breakpoint();
+    // interactive monitor on COM1: every int3 open the `kmon>` prompt
+    // kros::debug::monitor::enable();
+
     // heap allocator
     // kros::allocator::test_space::heap_memory_mapper_allocator(boot_info);
     // kros::allocator::test_space::create_null_box();
@@ -48,7 +66,20 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     #[cfg(test)]
     test_main();
 
-    kros::hlt_loop();
+    // idle: poll async tasks and run deferred work queued by interrupt handlers
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(example_task())).expect("spawn task");
+    executor.spawn(Task::new(kros::keyboard::print_keypresses())).expect("spawn task");
+    executor.run();
+}
+
+async fn async_number() -> u32 {
+    42
+}
+
+async fn example_task() {
+    let number = async_number().await;
+    println!("async number: {}", number);
 }
 
 /// This function is called on panic.
@@ -57,6 +88,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    println!("{}", kros::backtrace::Backtrace::capture());
     kros::hlt_loop();
 }
 
@@ -3,7 +3,12 @@
 //!     - used Recurse Page Table   # 使用
 //!     - translate Some Addr       # 了解地址转化过程
 //!     - FrameAllocator            # 尝试分配
-//!     - BootInfoFrameAllocator    # 尝试分配
+//!     - BootInfoFrameAllocator    # 尝试分配（保留1 MiB以下一帧给实模式代码）
+//!     - translate_addr            # 只读遍历活动页表（调试用）
+//!     - walk_page_table           # 逐级查看页表项（调试用）
+//!     - map_mmio                  # 设备寄存器映射（不缓存）
+//!     - KernelMemory              # 全局页表 + 帧分配器（线程栈等运行时映射）
+//!     - kernel_page_table         # 内核4级页表帧（用户地址空间共享其内核部分）
 
 use bootloader::bootinfo::{
     BootInfo,         // 引导程序传递的内存映射
@@ -12,17 +17,27 @@ use bootloader::bootinfo::{
 };
 use x86_64::{
     structures::paging::{
+        mapper::MapToError, // 映射错误
         FrameAllocator,  // 帧分配器
+        FrameDeallocator, // 帧回收
         Mapper,          // 映射
         OffsetPageTable, // 偏移页表
         Page,            // 页
         PageTable,       // 页表
+        page_table::PageTableEntry, // 页表项
         PhysFrame,       // 物理帧
         Size4KiB,        // 4KiB
         Translate,       // 翻译
     },
     PhysAddr, VirtAddr,
 };
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::IrqSpinlock;
+
+// 物理内存映射偏移量，在`OffsetPageTableWarper::init`时记录，0 表示尚未初始化
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+// 内核4级页表的物理地址，同时记录，0 表示尚未初始化
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
 
 #[allow(dead_code)]
 /// 返回一个对活动的4级表的可变引用。
@@ -61,6 +76,10 @@ impl OffsetPageTableWarper {
     ///
     /// 一个新的OffsetPageTable实例。
     pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+        use x86_64::registers::control::Cr3;
+
+        PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+        KERNEL_PAGE_TABLE.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
         OffsetPageTable::new(
             get_active_level_4_table(physical_memory_offset),
             physical_memory_offset,
@@ -68,6 +87,89 @@ impl OffsetPageTableWarper {
     }
 }
 
+/// 返回记录的物理内存偏移量，页表尚未初始化时返回`None`。
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset)),
+    }
+}
+
+/// 返回内核4级页表所在的帧（内核线程使用的地址空间），页表尚未初始化时返回`None`。
+pub fn kernel_page_table() -> Option<PhysFrame> {
+    match KERNEL_PAGE_TABLE.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+/// 将物理地址转换为物理内存映射中的虚拟地址。
+pub fn phys_to_virt(phys: PhysAddr) -> Option<VirtAddr> {
+    physical_memory_offset().map(|offset| offset + phys.as_u64())
+}
+
+/// 只读遍历活动的4级页表，将虚拟地址转换为物理地址（支持大页）。
+///
+/// 不创建`&mut PageTable`，可在异常/调试上下文中使用；地址未映射或页表尚未初始化时返回`None`。
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    walk_page_table(addr, |_, _| {})
+}
+
+/// 同`translate_addr`，对经过的每一级页表项调用`visit(level, entry)`（level: 4 -> 1）。
+pub fn walk_page_table(addr: VirtAddr, mut visit: impl FnMut(u8, &PageTableEntry)) -> Option<PhysAddr> {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let offset = physical_memory_offset()?;
+    let (level_4_table_frame, _) = Cr3::read();
+    let table_indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    let mut frame_addr = level_4_table_frame.start_address();
+
+    for (depth, &index) in table_indexes.iter().enumerate() {
+        let table_ptr: *const PageTable = (offset + frame_addr.as_u64()).as_ptr();
+        let table = unsafe { &*table_ptr };
+        let entry = &table[index];
+        visit(4 - depth as u8, entry);
+        if !entry.flags().contains(Flags::PRESENT) {
+            return None;
+        }
+        // level 3: 1GiB 大页, level 2: 2MiB 大页
+        if depth > 0 && entry.flags().contains(Flags::HUGE_PAGE) {
+            let page_mask = if depth == 1 { 0x3fff_ffff } else { 0x1f_ffff };
+            return Some(entry.addr() + (addr.as_u64() & page_mask));
+        }
+        frame_addr = entry.addr();
+    }
+
+    Some(frame_addr + u64::from(addr.page_offset()))
+}
+
+/// 设备寄存器(MMIO)映射区域的起始虚拟地址，`map_mmio`依次向上分配
+pub const MMIO_START: u64 = 0x_5555_0000_0000;
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// 将设备寄存器 `phys..phys + size` 映射到 MMIO 区域（不缓存），返回`phys`对应的虚拟地址。
+pub fn map_mmio(
+    phys: PhysAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let first: PhysFrame<Size4KiB> = PhysFrame::containing_address(phys);
+    let last: PhysFrame<Size4KiB> = PhysFrame::containing_address(phys + (size.max(1) - 1));
+    let start = MMIO_NEXT.fetch_add((last - first + 1) * first.size(), Ordering::Relaxed);
+    // 寄存器读写有副作用：不能经过缓存
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::WRITE_THROUGH | Flags::NO_CACHE;
+
+    for (i, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+        let page = Page::containing_address(VirtAddr::new(start + i as u64 * first.size()));
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(VirtAddr::new(start + (phys - first.start_address())))
+}
+
 pub fn translate_some_addr(boot_info: &BootInfo) {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mapper = unsafe { OffsetPageTableWarper::init(phys_mem_offset) };
@@ -130,18 +232,37 @@ pub fn create_example_mapping(
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    // 回收帧链表：下一帧的物理地址存放在帧的前8字节（经物理内存映射访问）
+    free: Option<PhysFrame>,
+    free_count: usize,
+    // 1 MiB以下的保留帧，从不分配
+    real_mode: Option<PhysFrame>,
 }
 
+// 回收帧链表结尾标记
+const FREE_LIST_END: u64 = u64::MAX;
+// 实模式可寻址的物理内存上限
+const REAL_MODE_LIMIT: u64 = 0x10_0000;
+
 impl BootInfoFrameAllocator {
     /// 从传递的内存 map 中创建一个FrameAllocator
     ///
     /// 这个函数是不安全的，因为调用这必须保证传递的内存 map 是有效的
     /// 主要的要求是， 所有在被标记为 "可用" 的帧都是真正未被使用的
+    ///
+    /// 1 MiB以下最高的可用帧在分配之前就被保留（`real_mode_frame`）。
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let mut allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
-        }
+            free: None,
+            free_count: 0,
+            real_mode: None,
+        };
+        allocator.real_mode = allocator.usable_frames()
+            .take_while(|frame| frame.start_address().as_u64() < REAL_MODE_LIMIT)
+            .last();
+        allocator
     }
 
     /// 在我们实现`FrameAllocator`特性之前，我们添加一个辅助方法，将内存映射转换为可用帧的迭代器。
@@ -152,7 +273,20 @@ impl BootInfoFrameAllocator {
         let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
 
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        let real_mode = self.real_mode;
+        frame_addresses
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+            .filter(move |&frame| Some(frame) != real_mode)
+    }
+
+    /// 保留给实模式代码（AP启动跳板，`smp`）的1 MiB以下的帧，没有可用的低端内存时返回`None`。
+    pub fn real_mode_frame(&self) -> Option<PhysFrame> {
+        self.real_mode
+    }
+
+    /// 回收链表中等待复用的帧数量。
+    pub fn free_frames(&self) -> usize {
+        self.free_count
     }
 
     /// 返回可变引用的FrameAllocator
@@ -163,12 +297,54 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        // 优先复用回收的帧
+        if let Some(frame) = self.free {
+            let virt = phys_to_virt(frame.start_address())?;
+            let next = unsafe { virt.as_ptr::<u64>().read() };
+            self.free = (next != FREE_LIST_END).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            self.free_count -= 1;
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// 将帧放入回收链表；物理内存映射尚未记录时无法写入链表，只能泄漏该帧。
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let Some(virt) = phys_to_virt(frame.start_address()) else {
+            return;
+        };
+        let next = self.free.map_or(FREE_LIST_END, |next| next.start_address().as_u64());
+        virt.as_mut_ptr::<u64>().write(next);
+        self.free = Some(frame);
+        self.free_count += 1;
+    }
+}
+
+// ####################### 全局页表 && 帧分配器 #########################
+/// 启动完成后交给内核的页表与帧分配器，供运行时映射（线程栈等）使用。
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+}
+
+static KERNEL_MEMORY: IrqSpinlock<Option<KernelMemory>> = IrqSpinlock::new(None);
+
+/// 移交页表与帧分配器，之后只能通过`with_kernel_memory`访问。
+pub fn init_kernel_memory(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    *KERNEL_MEMORY.lock() = Some(KernelMemory { mapper, frame_allocator });
+}
+
+/// 关中断并持锁调用`f`，尚未调用`init_kernel_memory`时返回`None`。
+///
+/// 持锁期间中断关闭，被打断的代码不会持有该锁：中断处理程序中也可调用。
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    KERNEL_MEMORY.lock().as_mut().map(f)
+}
+
 #[allow(dead_code)]
 /// 使用自定义好的BootInfoFrameAllocator 页帧分配器，实现对虚拟地址新建物理映射关系
 ///
@@ -0,0 +1,359 @@
+//! this module impl kros user processes.
+//! `
+//!     - process table: Pid -> parent, main thread, address space, open resources, state
+//!     - spawn: run a loaded `Program` in a new thread, parent = the calling process (None: the kernel)
+//!     - fork: child with a copy-on-write copy of the caller address space, its resources and registers
+//!     - exit(status): free the address space and resources at once, keep a zombie with the status
+//!     - wait(pid): block until the child is a zombie, reap it (kernel stack freed), return its status
+//!     - orphans: children of an exiting process are adopted by the kernel, reaped at once when they end
+//!     - signal: pending / blocked masks and actions per process, delivered on the way back to ring 3
+//! `
+//! one thread per process. the table is locked with interrupts disabled: user faults exit from
+//! exception handlers.
+
+pub mod resource;
+pub mod signal;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::interrupts::trap::TrapFrame;
+use crate::sync::IrqSpinlock;
+use crate::thread::{self, JoinHandle, Priority, SpawnError, ThreadId, WaitQueue};
+use crate::user::{self, AddressSpace, Program, UserError};
+use resource::{Handle, Resource, ResourceTable};
+use signal::{Signal, SignalState};
+
+/// define a unique process id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+}
+
+/// define how a process ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// `exit(code)`.
+    Exited(u64),
+    /// killed by a signal (default action), e.g. SIGSEGV of a fault from ring 3.
+    Signaled { signal: Signal, core_dumped: bool },
+}
+
+impl ExitStatus {
+    /// Unix wait status: exit code << 8, or the signal number (| 0x80: core dumped).
+    pub fn wait_status(&self) -> u64 {
+        match *self {
+            ExitStatus::Exited(code) => (code & 0xff) << 8,
+            ExitStatus::Signaled { signal, core_dumped } => signal.number() | if core_dumped { 0x80 } else { 0 },
+        }
+    }
+}
+
+/// define the state of a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// ended, not reaped by `wait` yet.
+    Zombie(ExitStatus),
+}
+
+/// define process errors.
+#[derive(Debug)]
+pub enum ProcessError {
+    /// no process with this pid (or already reaped).
+    NoProcess,
+    /// the process is not a child of the caller.
+    NotChild,
+    /// the caller is a kernel thread, not a process.
+    NotProcess,
+    /// no open resource at this handle.
+    BadHandle,
+    /// resource table full.
+    TooManyResources,
+    Spawn(SpawnError),
+    /// address space copy failed.
+    User(UserError),
+    /// bad signal number, SIGKILL action, or bad sigprocmask operation.
+    InvalidSignal,
+    /// sigreturn without a readable, valid SignalFrame.
+    BadSignalFrame,
+}
+
+impl From<SpawnError> for ProcessError {
+    fn from(error: SpawnError) -> Self {
+        ProcessError::Spawn(error)
+    }
+}
+
+impl From<UserError> for ProcessError {
+    fn from(error: UserError) -> Self {
+        ProcessError::User(error)
+    }
+}
+
+/// define a snapshot of one process table entry.
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub thread: Option<ThreadId>,
+    pub state: ProcessState,
+    /// open resources.
+    pub resources: usize,
+}
+
+struct Process {
+    parent: Option<Pid>,
+    // set by the thread itself before it enter ring 3
+    thread: Option<ThreadId>,
+    // set by `spawn` once the thread exist, taken by `wait`
+    handle: Option<JoinHandle<()>>,
+    space: Option<Arc<AddressSpace>>,
+    resources: ResourceTable,
+    signals: SignalState,
+    state: ProcessState,
+    // parent exited: nobody wait, the entry is removed when it end
+    orphan: bool,
+}
+
+struct ProcessTable {
+    processes: BTreeMap<Pid, Process>,
+    threads: BTreeMap<ThreadId, Pid>,
+}
+
+// what an exiting process drop outside the table lock: its space, resources, and the threads of
+// reaped orphans (itself included), detached -> stacks freed by the next spawn
+type Released = (Option<Arc<AddressSpace>>, ResourceTable, Vec<JoinHandle<()>>);
+
+impl ProcessTable {
+    const fn new() -> Self {
+        ProcessTable { processes: BTreeMap::new(), threads: BTreeMap::new() }
+    }
+
+    fn current(&self) -> Option<Pid> {
+        self.threads.get(&thread::current_id()).copied()
+    }
+
+    fn current_mut(&mut self) -> Result<&mut Process, ProcessError> {
+        let pid = self.current().ok_or(ProcessError::NotProcess)?;
+        self.processes.get_mut(&pid).ok_or(ProcessError::NotProcess)
+    }
+
+    fn exit(&mut self, thread: ThreadId, status: ExitStatus) -> Option<Released> {
+        let pid = self.threads.remove(&thread)?;
+        let process = self.processes.get_mut(&pid)?;
+        process.state = ProcessState::Zombie(status);
+        let (space, resources) = (process.space.take(), core::mem::take(&mut process.resources));
+        let (parent, orphan) = (process.parent, process.orphan);
+
+        let children: Vec<Pid> = self.processes.iter()
+            .filter(|(_, child)| child.parent == Some(pid))
+            .map(|(&child, _)| child)
+            .collect();
+        let mut reaped = Vec::new();
+        for child in children {
+            let process = self.processes.get_mut(&child).unwrap();
+            if let ProcessState::Zombie(_) = process.state {
+                reaped.extend(self.processes.remove(&child).and_then(|mut process| process.handle.take()));
+            } else {
+                process.parent = None;
+                process.orphan = true;
+            }
+        }
+        if orphan {
+            reaped.extend(self.processes.remove(&pid).and_then(|mut process| process.handle.take()));
+        } else if let Some(parent) = parent.and_then(|parent| self.processes.get_mut(&parent)) {
+            parent.signals.raise(Signal::SIGCHLD);
+        }
+        Some((space, resources, reaped))
+    }
+
+    // None: `pid` still running
+    fn reap(&mut self, pid: Pid, parent: Option<Pid>) -> Option<Result<(ExitStatus, JoinHandle<()>), ProcessError>> {
+        let Some(process) = self.processes.get_mut(&pid) else {
+            return Some(Err(ProcessError::NoProcess));
+        };
+        if process.parent != parent || process.orphan {
+            return Some(Err(ProcessError::NotChild));
+        }
+        match process.state {
+            ProcessState::Zombie(status) if process.handle.is_some() => {
+                let handle = process.handle.take().unwrap();
+                self.processes.remove(&pid);
+                Some(Ok((status, handle)))
+            }
+            _ => None,
+        }
+    }
+
+    fn info(&self, pid: Pid) -> Option<ProcessInfo> {
+        self.processes.get(&pid).map(|process| ProcessInfo {
+            pid,
+            parent: process.parent,
+            thread: process.thread,
+            state: process.state,
+            resources: process.resources.len(),
+        })
+    }
+}
+
+static TABLE: IrqSpinlock<ProcessTable> = IrqSpinlock::new(ProcessTable::new());
+// woken on every exit: waiting parents check their child again
+static EXITED: WaitQueue = WaitQueue::new();
+
+/// run `program` as a new process, child of the calling process (None: the kernel).
+///
+/// the process own the program address space, it start with the standard resources.
+pub fn spawn(program: Program) -> Result<Pid, ProcessError> {
+    let Program { space, entry, stack_pointer } = program;
+    let frame = user::initial_frame(entry, stack_pointer, 0);
+    start(current(), space, ResourceTable::standard(), SignalState::new(), frame)
+}
+
+/// copy the calling process: the child resume from `frame` (the caller system call) with rax = 0.
+///
+/// writable pages are shared copy-on-write, open resources are shared, signal actions and mask copied.
+pub fn fork(frame: &TrapFrame) -> Result<Pid, ProcessError> {
+    let (parent, space, resources, signals) = {
+        let mut table = TABLE.lock();
+        let parent = table.current().ok_or(ProcessError::NotProcess)?;
+        let process = table.current_mut()?;
+        let space = process.space.clone().ok_or(ProcessError::NotProcess)?;
+        (parent, space, process.resources.clone(), process.signals.fork())
+    };
+    let space = space.fork()?;
+    let mut child = *frame;
+    child.rax = 0;
+    start(Some(parent), space, resources, signals, child)
+}
+
+// new process entry + thread resuming `frame` in `space`
+fn start(
+    parent: Option<Pid>, space: Arc<AddressSpace>, resources: ResourceTable, signals: SignalState, frame: TrapFrame,
+) -> Result<Pid, ProcessError> {
+    let pid = Pid::new();
+    TABLE.lock().processes.insert(pid, Process {
+        parent,
+        thread: None,
+        handle: None,
+        space: Some(space.clone()),
+        resources,
+        signals,
+        state: ProcessState::Running,
+        orphan: false,
+    });
+
+    let handle = thread::try_spawn(move || {
+        let mut table = TABLE.lock();
+        table.threads.insert(thread::current_id(), pid);
+        if let Some(process) = table.processes.get_mut(&pid) {
+            process.thread = Some(thread::current_id());
+        }
+        drop(table);
+        space.activate();
+        // owned locals are never dropped after the jump: the thread and the table keep the space
+        drop(space);
+        unsafe { user::resume(&frame) }
+    }, Priority::Normal);
+
+    let mut table = TABLE.lock();
+    match handle {
+        Ok(handle) => {
+            table.processes.get_mut(&pid).expect("process reaped before spawn returned").handle = Some(handle);
+            Ok(pid)
+        }
+        Err(error) => {
+            let process = table.processes.remove(&pid);
+            drop(table);
+            drop(process);
+            Err(error.into())
+        }
+    }
+}
+
+/// pid of the running thread, None for kernel threads.
+pub fn current() -> Option<Pid> {
+    TABLE.lock().current()
+}
+
+/// end the calling process with `status`, a kernel thread just exit.
+///
+/// the address space and the resources are freed here, the kernel stack when the parent wait.
+pub fn exit(status: ExitStatus) -> ! {
+    let released = TABLE.lock().exit(thread::current_id(), status);
+    if let Some(released) = released {
+        drop(released);
+        // last reference: the kernel page table is loaded, every user frame go back to the allocator
+        thread::set_address_space(None);
+        EXITED.wake_all();
+    }
+    thread::exit()
+}
+
+/// block until child `pid` of the caller end, reap it and return how it ended.
+///
+/// kernel threads wait for processes spawned by the kernel, orphans are reaped when they end.
+pub fn wait(pid: Pid) -> Result<ExitStatus, ProcessError> {
+    let parent = current();
+    let mut reaped = None;
+    EXITED.wait_until(|| {
+        reaped = TABLE.lock().reap(pid, parent);
+        reaped.is_some()
+    });
+    let (status, handle) = reaped.unwrap()?;
+    // dead or dying: join free its kernel stack
+    handle.join();
+    Ok(status)
+}
+
+/// table entry of `pid`, None if not a process or already reaped.
+pub fn info(pid: Pid) -> Option<ProcessInfo> {
+    TABLE.lock().info(pid)
+}
+
+/// every process, zombies included.
+pub fn list() -> Vec<ProcessInfo> {
+    let table = TABLE.lock();
+    table.processes.keys().filter_map(|&pid| table.info(pid)).collect()
+}
+
+/// open `resource` in the calling process, return its handle.
+pub fn open(resource: Arc<dyn Resource>) -> Result<Handle, ProcessError> {
+    TABLE.lock().current_mut()?.resources.open(resource).ok_or(ProcessError::TooManyResources)
+}
+
+/// resource at `handle` of the calling process.
+pub fn resource(handle: Handle) -> Result<Arc<dyn Resource>, ProcessError> {
+    TABLE.lock().current_mut()?.resources.get(handle).ok_or(ProcessError::BadHandle)
+}
+
+/// close `handle` of the calling process.
+pub fn close(handle: Handle) -> Result<(), ProcessError> {
+    let resource = TABLE.lock().current_mut()?.resources.close(handle).ok_or(ProcessError::BadHandle)?;
+    // last holder: dropped outside the table lock
+    drop(resource);
+    Ok(())
+}
+
+
+#[test_case]
+fn test_kernel_thread_not_process() {
+    assert_eq!(current(), None);
+    assert!(matches!(wait(Pid::from_u64(u64::MAX)), Err(ProcessError::NoProcess)));
+    assert!(matches!(close(resource::CONSOLE), Err(ProcessError::NotProcess)));
+}
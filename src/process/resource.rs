@@ -0,0 +1,105 @@
+//! this module impl the open-resource table of a process.
+//! `
+//!     - Resource: anything a process hold open, shared by Arc (the same resource in many tables)
+//!     - Handle: index of a slot, the lowest free slot is reused first
+//!     - close / drop of the table: release the Arc, the resource is dropped with its last holder
+//! `
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// most resources open at once in one process.
+pub const MAX_RESOURCES: usize = 64;
+
+/// handle of the console: open in every new process.
+pub const CONSOLE: Handle = 0;
+
+/// define a slot of a resource table.
+pub type Handle = usize;
+
+/// define a resource a process can hold open.
+pub trait Resource: Send + Sync {
+    /// short name, e.g. in process listings.
+    fn name(&self) -> &str;
+}
+
+/// define the kernel console (VGA + serial output).
+#[derive(Debug)]
+pub struct Console;
+
+impl Resource for Console {
+    fn name(&self) -> &str {
+        "console"
+    }
+}
+
+/// define the open resources of one process.
+#[derive(Clone, Default)]
+pub struct ResourceTable {
+    slots: Vec<Option<Arc<dyn Resource>>>,
+}
+
+impl ResourceTable {
+    pub const fn new() -> Self {
+        ResourceTable { slots: Vec::new() }
+    }
+
+    /// table of a new process: the console at `CONSOLE`.
+    pub fn standard() -> Self {
+        let mut table = Self::new();
+        table.open(Arc::new(Console));
+        table
+    }
+
+    /// put `resource` in the lowest free slot, None if the table is full.
+    pub fn open(&mut self, resource: Arc<dyn Resource>) -> Option<Handle> {
+        if let Some(handle) = self.slots.iter().position(Option::is_none) {
+            self.slots[handle] = Some(resource);
+            return Some(handle);
+        }
+        if self.slots.len() >= MAX_RESOURCES {
+            return None;
+        }
+        self.slots.push(Some(resource));
+        Some(self.slots.len() - 1)
+    }
+
+    /// resource at `handle`.
+    pub fn get(&self, handle: Handle) -> Option<Arc<dyn Resource>> {
+        self.slots.get(handle)?.clone()
+    }
+
+    /// free `handle`, return its resource (drop it outside any lock).
+    pub fn close(&mut self, handle: Handle) -> Option<Arc<dyn Resource>> {
+        let resource = self.slots.get_mut(handle)?.take();
+        while let Some(None) = self.slots.last() {
+            self.slots.pop();
+        }
+        resource
+    }
+
+    /// number of open resources.
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+
+#[test_case]
+fn test_resource_table_reuse_slot() {
+    let mut table = ResourceTable::standard();
+    assert_eq!(table.get(CONSOLE).map(|console| console.name() == "console"), Some(true));
+    let first = table.open(Arc::new(Console)).unwrap();
+    let second = table.open(Arc::new(Console)).unwrap();
+    assert_eq!((first, second), (1, 2));
+    assert!(table.close(first).is_some());
+    assert!(table.close(first).is_none());
+    assert_eq!(table.open(Arc::new(Console)), Some(first));
+    assert_eq!(table.len(), 3);
+    while table.open(Arc::new(Console)).is_some() {}
+    assert_eq!(table.len(), MAX_RESOURCES);
+}
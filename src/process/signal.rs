@@ -0,0 +1,405 @@
+//! this module impl POSIX-like signals of processes.
+//! `
+//!     - pending / blocked masks per process, SIGKILL can't be blocked, caught or ignored
+//!     - actions: default (terminate, ignore, core dump), ignore, user handler + restorer
+//!     - delivery: on the way back to ring 3 (system call, trap, user fault), lowest pending signal first
+//!     - handler: SignalFrame on the user stack, rip = handler(signal, &frame), return address = restorer
+//!     - sigreturn (called by the restorer): registers and blocked mask back from the SignalFrame
+//!     - default terminate / core dump: resume in ring 0 on the thread stack -> `process::exit`
+//!     - sources: `send` (kill), user faults (SIGSEGV, forced), child exit (SIGCHLD), Ctrl+C (SIGINT)
+//! `
+//! a process blocked in the kernel (sleep, wait) see its signals when it return to ring 3.
+
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::VirtAddr;
+
+use super::{ExitStatus, Pid, ProcessError, ProcessState, TABLE};
+use crate::interrupts::trap::{TrapFrame, RFLAGS_INTERRUPT};
+use crate::user::{is_user_range, USER_END, USER_START};
+use crate::{gdt, println};
+
+/// signal numbers are 1..NSIG.
+pub const NSIG: usize = 32;
+
+/// sigaction handler value: default action.
+pub const SIG_DFL: u64 = 0;
+/// sigaction handler value: ignore.
+pub const SIG_IGN: u64 = 1;
+
+/// sigprocmask: add the set to the blocked mask.
+pub const SIG_BLOCK: u64 = 0;
+/// sigprocmask: remove the set from the blocked mask.
+pub const SIG_UNBLOCK: u64 = 1;
+/// sigprocmask: replace the blocked mask.
+pub const SIG_SETMASK: u64 = 2;
+
+// below the interrupted rsp: the System V red zone of the interrupted function
+const RED_ZONE: u64 = 128;
+// RFLAGS bits a handler may change through its SignalFrame: CF PF AF ZF SF DF OF
+const USER_RFLAGS: u64 = 0xcd5;
+
+/// define a signal number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Signal(u8);
+
+impl Signal {
+    pub const SIGHUP: Signal = Signal(1);
+    pub const SIGINT: Signal = Signal(2);
+    pub const SIGQUIT: Signal = Signal(3);
+    pub const SIGILL: Signal = Signal(4);
+    pub const SIGTRAP: Signal = Signal(5);
+    pub const SIGABRT: Signal = Signal(6);
+    pub const SIGBUS: Signal = Signal(7);
+    pub const SIGFPE: Signal = Signal(8);
+    pub const SIGKILL: Signal = Signal(9);
+    pub const SIGUSR1: Signal = Signal(10);
+    pub const SIGSEGV: Signal = Signal(11);
+    pub const SIGUSR2: Signal = Signal(12);
+    pub const SIGPIPE: Signal = Signal(13);
+    pub const SIGALRM: Signal = Signal(14);
+    pub const SIGTERM: Signal = Signal(15);
+    pub const SIGCHLD: Signal = Signal(17);
+    pub const SIGURG: Signal = Signal(23);
+    pub const SIGWINCH: Signal = Signal(28);
+
+    /// signal `number`, None outside 1..NSIG.
+    pub fn new(number: u64) -> Option<Signal> {
+        (1..NSIG as u64).contains(&number).then_some(Signal(number as u8))
+    }
+
+    pub const fn number(&self) -> u64 {
+        self.0 as u64
+    }
+
+    /// what happen when the action is `Action::Default`.
+    pub fn default_action(&self) -> DefaultAction {
+        match *self {
+            Signal::SIGQUIT | Signal::SIGILL | Signal::SIGTRAP | Signal::SIGABRT
+            | Signal::SIGBUS | Signal::SIGFPE | Signal::SIGSEGV => DefaultAction::CoreDump,
+            Signal::SIGCHLD | Signal::SIGURG | Signal::SIGWINCH => DefaultAction::Ignore,
+            _ => DefaultAction::Terminate,
+        }
+    }
+
+    fn bit(&self) -> u64 {
+        1 << self.0
+    }
+}
+
+/// define a set of signals (bit n: signal n).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SigSet(u64);
+
+impl SigSet {
+    pub const fn empty() -> Self {
+        SigSet(0)
+    }
+
+    /// set of the signal bits of `bits`, other bits dropped.
+    pub fn from_bits(bits: u64) -> Self {
+        SigSet(bits & !1 & (u64::MAX >> (64 - NSIG)))
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(&self, signal: Signal) -> bool {
+        self.0 & signal.bit() != 0
+    }
+
+    pub fn insert(&mut self, signal: Signal) {
+        self.0 |= signal.bit();
+    }
+
+    pub fn remove(&mut self, signal: Signal) {
+        self.0 &= !signal.bit();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    // lowest signal in the set
+    fn first(&self) -> Option<Signal> {
+        (!self.is_empty()).then(|| Signal(self.0.trailing_zeros() as u8))
+    }
+}
+
+/// define the default action of a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    Terminate,
+    Ignore,
+    /// terminate, registers dumped to the console.
+    CoreDump,
+}
+
+/// define what a process do with a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Default,
+    Ignore,
+    /// call `handler(signal, &SignalFrame)` in ring 3, it return to `restorer` (sigreturn).
+    Handler { handler: VirtAddr, restorer: VirtAddr },
+}
+
+/// define the frame pushed on the user stack for a handler, read back by sigreturn.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SignalFrame {
+    pub signal: u64,
+    /// blocked mask before the handler.
+    pub blocked: u64,
+    /// registers of the interrupted code.
+    pub registers: TrapFrame,
+}
+
+/// define the signal state of one process.
+#[derive(Debug, Clone)]
+pub(super) struct SignalState {
+    pending: SigSet,
+    blocked: SigSet,
+    actions: [Action; NSIG],
+}
+
+impl SignalState {
+    pub(super) fn new() -> Self {
+        SignalState { pending: SigSet::empty(), blocked: SigSet::empty(), actions: [Action::Default; NSIG] }
+    }
+
+    /// state of a forked child: same actions and mask, nothing pending.
+    pub(super) fn fork(&self) -> Self {
+        SignalState { pending: SigSet::empty(), ..self.clone() }
+    }
+
+    pub(super) fn raise(&mut self, signal: Signal) {
+        self.pending.insert(signal);
+    }
+
+    // next signal to deliver: lowest pending and not blocked
+    fn take(&mut self) -> Option<(Signal, Action)> {
+        let signal = SigSet(self.pending.0 & !self.blocked.0).first()?;
+        self.pending.remove(signal);
+        Some((signal, self.actions[signal.0 as usize]))
+    }
+}
+
+/// send `signal` to process `pid`, nothing happen to a zombie.
+pub fn send(pid: Pid, signal: Signal) -> Result<(), ProcessError> {
+    let mut table = TABLE.lock();
+    let process = table.processes.get_mut(&pid).ok_or(ProcessError::NoProcess)?;
+    if process.state == ProcessState::Running {
+        process.signals.raise(signal);
+    }
+    Ok(())
+}
+
+/// action of `signal` in the calling process.
+pub fn action(signal: Signal) -> Result<Action, ProcessError> {
+    Ok(TABLE.lock().current_mut()?.signals.actions[signal.0 as usize])
+}
+
+/// change the action of `signal` in the calling process, return the old one.
+pub fn set_action(signal: Signal, action: Action) -> Result<Action, ProcessError> {
+    if signal == Signal::SIGKILL {
+        return Err(ProcessError::InvalidSignal);
+    }
+    let mut table = TABLE.lock();
+    let signals = &mut table.current_mut()?.signals;
+    Ok(core::mem::replace(&mut signals.actions[signal.0 as usize], action))
+}
+
+/// change the blocked mask of the calling process (`SIG_BLOCK`, `SIG_UNBLOCK`, `SIG_SETMASK`), return the old one.
+pub fn set_blocked(how: u64, set: SigSet) -> Result<SigSet, ProcessError> {
+    let mut table = TABLE.lock();
+    let signals = &mut table.current_mut()?.signals;
+    let old = signals.blocked;
+    signals.blocked = match how {
+        SIG_BLOCK => SigSet(old.0 | set.0),
+        SIG_UNBLOCK => SigSet(old.0 & !set.0),
+        SIG_SETMASK => set,
+        _ => return Err(ProcessError::InvalidSignal),
+    };
+    signals.blocked.remove(Signal::SIGKILL);
+    Ok(old)
+}
+
+/// signals sent to the calling process, not delivered yet.
+pub fn pending() -> Result<SigSet, ProcessError> {
+    Ok(TABLE.lock().current_mut()?.signals.pending)
+}
+
+// process receiving Ctrl+C, 0: none
+static FOREGROUND: AtomicU64 = AtomicU64::new(0);
+
+/// process receiving SIGINT when Ctrl+C is pressed.
+pub fn set_foreground(pid: Option<Pid>) {
+    FOREGROUND.store(pid.map_or(0, |pid| pid.as_u64()), Ordering::Relaxed);
+}
+
+pub fn foreground() -> Option<Pid> {
+    match FOREGROUND.load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(Pid::from_u64(pid)),
+    }
+}
+
+/// Ctrl+C: SIGINT to the foreground process, false if none (or already reaped).
+pub fn interrupt_foreground() -> bool {
+    foreground().is_some_and(|pid| send(pid, Signal::SIGINT).is_ok())
+}
+
+/// fault of the calling process: `signal` can't be ignored or blocked, pending at once.
+pub(crate) fn force(signal: Signal) {
+    let mut table = TABLE.lock();
+    let Ok(process) = table.current_mut() else {
+        return;
+    };
+    let signals = &mut process.signals;
+    if signals.blocked.contains(signal) || signals.actions[signal.0 as usize] == Action::Ignore {
+        signals.blocked.remove(signal);
+        signals.actions[signal.0 as usize] = Action::Default;
+    }
+    signals.raise(signal);
+}
+
+/// deliver the pending signals of the running process if `frame` return to ring 3.
+///
+/// called with interrupts disabled just before the frame is resumed: no heap, only the
+/// process table and the user stack. default terminate / core dump rewrite the frame to
+/// finish in `signal_exit` (thread context).
+pub(crate) fn deliver(frame: &mut TrapFrame) {
+    if frame.cs & 3 != 3 {
+        return;
+    }
+    loop {
+        let (signal, action, blocked, space) = {
+            let mut table = TABLE.lock();
+            let Ok(process) = table.current_mut() else {
+                return;
+            };
+            let Some((signal, action)) = process.signals.take() else {
+                return;
+            };
+            (signal, action, process.signals.blocked, process.space.clone())
+        };
+        let (handler, restorer) = match action {
+            Action::Ignore => continue,
+            Action::Default => match signal.default_action() {
+                DefaultAction::Ignore => continue,
+                DefaultAction::Terminate => return terminate(frame, signal, false),
+                DefaultAction::CoreDump => return terminate(frame, signal, true),
+            },
+            Action::Handler { handler, restorer } => (handler, restorer),
+        };
+
+        // signal frame below the red zone, handler entered as if called: rsp + 8 aligned to 16
+        let signal_frame = SignalFrame { signal: signal.number(), blocked: blocked.0, registers: *frame };
+        let frame_addr = (frame.rsp.wrapping_sub(RED_ZONE + size_of::<SignalFrame>() as u64)) & !0xf;
+        let return_addr = frame_addr.wrapping_sub(8);
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&signal_frame as *const SignalFrame as *const u8, size_of::<SignalFrame>())
+        };
+        let written = space.is_some_and(|space| {
+            is_user_range(VirtAddr::new_truncate(return_addr), 8 + size_of::<SignalFrame>() as u64)
+                && space.write(VirtAddr::new(return_addr), &restorer.as_u64().to_ne_bytes()).is_ok()
+                && space.write(VirtAddr::new(frame_addr), bytes).is_ok()
+        });
+        if !written {
+            // no room for the frame: the process can't go on
+            println!("SIGNAL: stack overflow delivering signal {}", signal.number());
+            return terminate(frame, Signal::SIGSEGV, true);
+        }
+
+        // the signal is blocked while its handler run
+        if let Ok(process) = TABLE.lock().current_mut() {
+            process.signals.blocked.insert(signal);
+            process.signals.blocked.remove(Signal::SIGKILL);
+        }
+        frame.rip = handler.as_u64();
+        frame.rsp = return_addr;
+        frame.rdi = signal.number();
+        frame.rsi = frame_addr;
+        frame.rflags &= !(1 << 10); // DF clear on function entry
+        return;
+    }
+}
+
+/// sigreturn: restore the registers and mask saved by `deliver` in the SignalFrame at `frame.rsp`.
+///
+/// return the restored rax (the system call result must not change it).
+pub(crate) fn sigreturn(frame: &mut TrapFrame) -> Result<u64, ProcessError> {
+    let space = TABLE.lock().current_mut()?.space.clone().ok_or(ProcessError::NotProcess)?;
+    let mut saved = SignalFrame { signal: 0, blocked: 0, registers: TrapFrame::default() };
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(&mut saved as *mut SignalFrame as *mut u8, size_of::<SignalFrame>())
+    };
+    space.read(VirtAddr::new_truncate(frame.rsp), bytes).map_err(|_| ProcessError::BadSignalFrame)?;
+
+    // iretq / sysretq to a non canonical or kernel address fault in ring 0
+    let registers = saved.registers;
+    let in_user = |addr: u64| (USER_START..=USER_END).contains(&addr);
+    if !in_user(registers.rip) || !in_user(registers.rsp) {
+        return Err(ProcessError::BadSignalFrame);
+    }
+    let (cs, ss, rflags) = (frame.cs, frame.ss, frame.rflags);
+    *frame = TrapFrame {
+        cs,
+        ss,
+        rflags: (rflags & !USER_RFLAGS) | (registers.rflags & USER_RFLAGS) | RFLAGS_INTERRUPT,
+        vector: frame.vector,
+        ..registers
+    };
+    let mut table = TABLE.lock();
+    let signals = &mut table.current_mut()?.signals;
+    signals.blocked = SigSet::from_bits(saved.blocked);
+    signals.blocked.remove(Signal::SIGKILL);
+    Ok(registers.rax)
+}
+
+// end the process by `signal`: resume in ring 0 at `signal_exit` on the top of the thread stack
+// (the frame itself is there, iretq read it first)
+fn terminate(frame: &mut TrapFrame, signal: Signal, core_dump: bool) {
+    if core_dump {
+        println!("SIGNAL {}: core dumped\n{:#?}", signal.number(), frame);
+    }
+    let status = signal.number() | if core_dump { CORE_DUMP } else { 0 };
+    *frame = TrapFrame {
+        rip: VirtAddr::from_ptr(signal_exit as *const ()).as_u64(),
+        cs: u64::from(gdt::kernel_code_selector().0),
+        rflags: RFLAGS_INTERRUPT | 0x2, // bit 1 reserved, always set
+        rsp: gdt::kernel_stack().as_u64() - 8, // as after a call
+        ss: u64::from(gdt::kernel_data_selector().0),
+        rdi: status,
+        ..TrapFrame::default()
+    };
+}
+
+const CORE_DUMP: u64 = 0x80;
+
+extern "C" fn signal_exit(status: u64) -> ! {
+    let signal = Signal::new(status & !CORE_DUMP).expect("bad signal exit status");
+    super::exit(ExitStatus::Signaled { signal, core_dumped: status & CORE_DUMP != 0 })
+}
+
+
+#[test_case]
+fn test_signal_state() {
+    let mut state = SignalState::new();
+    state.raise(Signal::SIGTERM);
+    state.raise(Signal::SIGINT);
+    state.blocked.insert(Signal::SIGINT);
+    // lowest unblocked first, blocked ones stay pending
+    assert_eq!(state.take(), Some((Signal::SIGTERM, Action::Default)));
+    assert_eq!(state.take(), None);
+    assert!(state.pending.contains(Signal::SIGINT));
+    assert!(state.fork().pending.is_empty());
+    assert_eq!(Signal::new(0), None);
+    assert_eq!(Signal::new(NSIG as u64), None);
+    assert_eq!(SigSet::from_bits(u64::MAX).bits(), 0xffff_fffe);
+    assert_eq!(Signal::SIGSEGV.default_action(), DefaultAction::CoreDump);
+    assert_eq!(Signal::SIGCHLD.default_action(), DefaultAction::Ignore);
+}
@@ -1,17 +1,25 @@
 use uart_16550::SerialPort;
-use spin::Mutex;
 use lazy_static::lazy_static;
 
+use crate::sync::IrqSpinlock;
+
 // serial port print
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    // debug(hardware Timer): print res deal lock 
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(||{
-        SERIAL_1.lock().write_fmt(args).expect("Printing to serial failed.");
-    });
+    // IrqSpinlock: interrupt handler print can't deadlock on the held lock
+    SERIAL_1.lock().write_fmt(args).expect("Printing to serial failed.");
+}
+
+/// print to COM1 without the SERIAL_1 lock: lock deadlock / NMI report (the held lock may be SERIAL_1).
+#[doc(hidden)]
+pub fn _print_unlocked(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    // already initialized by SERIAL_1 (first print)
+    let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    let _ = serial_port.write_fmt(args);
 }
 
 #[macro_export]
@@ -26,14 +34,23 @@ macro_rules! serial_println {
     ($fmt: expr, $($arg:tt)*) => {{$crate::serial_print!(concat!($fmt, "\n"), $($arg)*);}};
 }
 
-// define global serial: spinlock and interior-mutability 
+// define global serial: spinlock(interrupts disabled while held) and interior-mutability 
 lazy_static!{
-    pub static ref SERIAL_1: Mutex<SerialPort> = {
+    pub static ref SERIAL_1: IrqSpinlock<SerialPort> = {
         let mut serial_port = unsafe {
             SerialPort::new(0x3F8)    
         };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSpinlock::new(serial_port)
+    };
+
+    /// COM2: debugger channel (gdb remote stub), keep COM1 for log output.
+    pub static ref SERIAL_2: IrqSpinlock<SerialPort> = {
+        let mut serial_port = unsafe {
+            SerialPort::new(0x2F8)
+        };
+        serial_port.init();
+        IrqSpinlock::new(serial_port)
     };
 }
 
@@ -0,0 +1,488 @@
+//! this module impl symmetric multiprocessing bring-up.
+//! `
+//!     - discovery: ACPI MADT local APIC entries (`acpi::local_apics`), bootstrap = the local APIC ID of the caller
+//!     - local APIC: registers mapped by `memory::map_mmio`, ICR send INIT / SIPI to one APIC id
+//!     - trampoline: real mode code copied to the frame reserved below 1 MiB, identity mapped
+//!         16 bit -> long mode at once: kernel CR4, CR3, EFER, CR0, temporary GDT, far jump to 64 bit code
+//!         -> boot stack, `ap_main(&Cpu)`
+//!     - per processor: GDT + TSS (`gdt::CpuTables`), boot + IST stacks (`thread::stack::Stack`), IDT load
+//!     - INIT-SIPI-SIPI one processor at a time: the trampoline hold the stack and `Cpu` of the one starting
+//!     - application processor: GS base = its `Cpu`, report over serial, park in an idle loop (`hlt`)
+//! `
+//! the scheduler, system calls and processes stay on the bootstrap processor: application processors
+//! only take NMI and IPIs (local APIC LVT masked after INIT).
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::hint::spin_loop;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::registers::control::{Cr0, Cr4};
+use x86_64::registers::model_specific::{Efer, GsBase};
+use x86_64::structures::paging::{
+    mapper::{MapToError, TranslateError}, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use crate::acpi::{self, AcpiError};
+use crate::gdt::{CpuTables, IST_STACK_COUNT};
+use crate::sync::{Mutex, Spinlock};
+use crate::thread::{self, stack::Stack, SpawnError, ThreadId};
+use crate::{interrupts, memory, serial_println, time};
+
+/// most processors started, bootstrap included.
+pub const MAX_CPUS: usize = 16;
+
+// local APIC registers
+const APIC_ID: u64 = 0x020;
+const APIC_ICR_LOW: u64 = 0x300;
+const APIC_ICR_HIGH: u64 = 0x310;
+const APIC_REGISTERS_SIZE: u64 = 0x1000;
+
+// ICR: delivery mode INIT / start-up, level assert, send pending
+const ICR_INIT: u32 = 0x0000_4500;
+const ICR_STARTUP: u32 = 0x0000_4600;
+const ICR_PENDING: u32 = 1 << 12;
+
+// MP specification delays
+const INIT_DELAY_NS: u64 = 10_000_000;
+const STARTUP_DELAY_NS: u64 = 200_000;
+// an application processor not online by then is left offline
+const ONLINE_TIMEOUT_NS: u64 = 100_000_000;
+
+/// define why `init` failed.
+#[derive(Debug)]
+pub enum SmpError {
+    Acpi(AcpiError),
+    /// `memory::init_kernel_memory` not called yet.
+    NoKernelMemory,
+    /// no usable frame below 1 MiB for the trampoline.
+    NoRealModeFrame,
+    /// the virtual page of the trampoline already map another frame.
+    TrampolineMapped,
+    /// the kernel L4 table is above 4 GiB: real mode can't load it in CR3.
+    PageTableAbove4GiB,
+    Map(MapToError<Size4KiB>),
+    Stack(SpawnError),
+}
+
+impl From<AcpiError> for SmpError {
+    fn from(error: AcpiError) -> Self {
+        SmpError::Acpi(error)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for SmpError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        SmpError::Map(error)
+    }
+}
+
+impl From<SpawnError> for SmpError {
+    fn from(error: SpawnError) -> Self {
+        SmpError::Stack(error)
+    }
+}
+
+/// define one processor known to `init`.
+#[derive(Debug)]
+pub struct Cpu {
+    /// index in `cpus`, 0: the bootstrap processor.
+    pub index: usize,
+    pub apic_id: u8,
+    // lock owner of the idle loop, never a scheduler thread
+    idle_thread: ThreadId,
+    // None: the bootstrap processor (`gdt::init`)
+    tables: Option<CpuTables>,
+    // boot stack, then the IST stacks: never freed
+    stacks: Vec<Stack>,
+    online: AtomicBool,
+    // `interrupts::in_interrupt` of an application processor
+    interrupt_depth: AtomicU64,
+}
+
+/// define a snapshot of one processor.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuInfo {
+    pub index: usize,
+    pub apic_id: u8,
+    pub bootstrap: bool,
+    pub online: bool,
+    /// its GDT, None: the bootstrap processor one.
+    pub gdt: Option<VirtAddr>,
+    /// its TSS, None: `gdt::TSS`.
+    pub tss: Option<VirtAddr>,
+}
+
+impl Cpu {
+    /// lock owner id of the code running on this processor (`thread::current_id`).
+    pub(crate) fn idle_thread(&self) -> ThreadId {
+        self.idle_thread
+    }
+
+    /// nested interrupt handlers running on this processor.
+    pub(crate) fn interrupt_depth(&self) -> &AtomicU64 {
+        &self.interrupt_depth
+    }
+
+    fn info(&self) -> CpuInfo {
+        CpuInfo {
+            index: self.index,
+            apic_id: self.apic_id,
+            bootstrap: self.tables.is_none(),
+            online: self.online.load(Ordering::Acquire),
+            gdt: self.tables.map(|tables| tables.gdt_address()),
+            tss: self.tables.map(|tables| tables.tss_address()),
+        }
+    }
+}
+
+// every processor, bootstrap first. leaked: a started processor is never stopped
+static CPUS: Spinlock<Vec<&'static Cpu>> = Spinlock::new(Vec::new());
+// one `init` at a time, the trampoline is shared
+static INIT: Mutex<()> = Mutex::new(());
+// set before the first SIPI: GS base identify the processor from then on
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+// trampoline layout: real mode can only address the data by constant offsets
+const TRAMPOLINE_CR0: u64 = 8;
+const TRAMPOLINE_CR3: u64 = 16;
+const TRAMPOLINE_CR4: u64 = 24;
+const TRAMPOLINE_EFER: u64 = 32;
+const TRAMPOLINE_GDTR: u64 = 88;
+
+global_asm!(
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_trampoline_long_jump",
+    ".global ap_trampoline_long_mode",
+    ".global ap_trampoline_gdt",
+    ".global ap_trampoline_gdtr",
+    ".global ap_trampoline_cr0",
+    ".global ap_trampoline_cr3",
+    ".global ap_trampoline_cr4",
+    ".global ap_trampoline_efer",
+    ".global ap_trampoline_stack",
+    ".global ap_trampoline_cpu",
+    ".global ap_trampoline_entry",
+    // copied to the reserved frame, entered by SIPI: cs = frame >> 4, ip = 0
+    ".code16",
+    ".balign 16",
+    "ap_trampoline_start:",
+    "    jmp 2f",
+    "    .balign 8",
+    "ap_trampoline_cr0: .quad 0",
+    "ap_trampoline_cr3: .quad 0",
+    "ap_trampoline_cr4: .quad 0",
+    "ap_trampoline_efer: .quad 0",
+    "ap_trampoline_stack: .quad 0",
+    "ap_trampoline_cpu: .quad 0",
+    "ap_trampoline_entry: .quad 0",
+    // null, 64 bit code, data
+    "ap_trampoline_gdt:",
+    "    .quad 0",
+    "    .quad 0x00af9a000000ffff",
+    "    .quad 0x00cf92000000ffff",
+    "ap_trampoline_gdtr:",
+    "    .word 23",
+    "    .long 0", // patched: linear address of ap_trampoline_gdt
+    "2:  cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    lgdt [{gdtr}]",
+    "    mov eax, dword ptr [{cr4}]",
+    "    mov cr4, eax",
+    "    mov eax, dword ptr [{cr3}]",
+    "    mov cr3, eax",
+    "    mov ecx, 0xc0000080", // EFER: LME (+ NXE, SCE of the kernel)
+    "    mov eax, dword ptr [{efer}]",
+    "    xor edx, edx",
+    "    wrmsr",
+    "    mov eax, dword ptr [{cr0}]",
+    "    mov cr0, eax", // PE + PG: long mode, compatibility code until the far jump
+    // jmp far ptr16:32, offset patched: linear address of ap_trampoline_long_mode
+    "    .byte 0x66, 0xea",
+    "ap_trampoline_long_jump:",
+    "    .long 0",
+    "    .word 0x08",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    xor eax, eax",
+    "    mov fs, ax",
+    "    mov gs, ax",
+    "    mov rsp, [rip + ap_trampoline_stack]",
+    "    mov rdi, [rip + ap_trampoline_cpu]",
+    "    mov rax, [rip + ap_trampoline_entry]",
+    "    call rax",
+    "    ud2",
+    "ap_trampoline_end:",
+    cr0 = const TRAMPOLINE_CR0,
+    cr3 = const TRAMPOLINE_CR3,
+    cr4 = const TRAMPOLINE_CR4,
+    efer = const TRAMPOLINE_EFER,
+    gdtr = const TRAMPOLINE_GDTR,
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_long_jump: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdtr: u8;
+    static ap_trampoline_cr0: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_cr4: u8;
+    static ap_trampoline_efer: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_cpu: u8;
+    static ap_trampoline_entry: u8;
+}
+
+// offset of a trampoline label from ap_trampoline_start
+fn offset(label: *const u8) -> u64 {
+    label as u64 - addr_of!(ap_trampoline_start) as u64
+}
+
+// the trampoline copied to `frame` (through the physical memory mapping)
+struct Trampoline {
+    frame: PhysFrame,
+    base: VirtAddr,
+}
+
+impl Trampoline {
+    // copy the code, patch the addresses and the kernel control registers
+    fn install(frame: PhysFrame, page_table: PhysFrame) -> Result<Self, SmpError> {
+        let base = memory::phys_to_virt(frame.start_address()).ok_or(SmpError::NoKernelMemory)?;
+        let len = offset(addr_of!(ap_trampoline_end)) as usize;
+        assert!(len <= 4096, "AP trampoline larger than a page");
+        unsafe {
+            let code = core::slice::from_raw_parts(addr_of!(ap_trampoline_start), len);
+            core::ptr::copy_nonoverlapping(code.as_ptr(), base.as_mut_ptr::<u8>(), len);
+        }
+        let trampoline = Trampoline { frame, base };
+        let phys = frame.start_address().as_u64();
+        let cr3 = page_table.start_address().as_u64();
+        unsafe {
+            trampoline.write::<u32>(addr_of!(ap_trampoline_long_jump), (phys + offset(addr_of!(ap_trampoline_long_mode))) as u32);
+            trampoline.write::<u32>(addr_of!(ap_trampoline_gdtr).add(2), (phys + offset(addr_of!(ap_trampoline_gdt))) as u32);
+            trampoline.write(addr_of!(ap_trampoline_cr0), Cr0::read_raw());
+            trampoline.write(addr_of!(ap_trampoline_cr3), cr3);
+            trampoline.write(addr_of!(ap_trampoline_cr4), Cr4::read_raw());
+            trampoline.write(addr_of!(ap_trampoline_efer), Efer::read_raw());
+            trampoline.write(addr_of!(ap_trampoline_entry), VirtAddr::from_ptr(ap_main as *const ()).as_u64());
+        }
+        Ok(trampoline)
+    }
+
+    // write `value` at the copy of `label`
+    unsafe fn write<T>(&self, label: *const u8, value: T) {
+        (self.base + offset(label)).as_mut_ptr::<T>().write_volatile(value)
+    }
+
+    // SIPI vector: page number of the trampoline
+    fn vector(&self) -> u32 {
+        (self.frame.start_address().as_u64() >> 12) as u32
+    }
+}
+
+// local APIC registers of the calling processor
+struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, register: u64) -> u32 {
+        (self.base + register).as_ptr::<u32>().read_volatile()
+    }
+
+    unsafe fn write(&self, register: u64, value: u32) {
+        (self.base + register).as_mut_ptr::<u32>().write_volatile(value)
+    }
+
+    fn id(&self) -> u8 {
+        (unsafe { self.read(APIC_ID) } >> 24) as u8
+    }
+
+    // send `command` to the processor `apic_id`, wait until it is accepted
+    fn send_ipi(&self, apic_id: u8, command: u32) {
+        unsafe {
+            self.write(APIC_ICR_HIGH, u32::from(apic_id) << 24);
+            self.write(APIC_ICR_LOW, command);
+            while self.read(APIC_ICR_LOW) & ICR_PENDING != 0 {
+                spin_loop();
+            }
+        }
+    }
+}
+
+// busy wait on the clocksource (interrupts enabled: PIT ticks)
+fn delay(ns: u64) {
+    let deadline = time::monotonic_ns() + ns;
+    while time::monotonic_ns() < deadline {
+        spin_loop();
+    }
+}
+
+/// start every processor of the MADT, return how many are online (bootstrap included).
+///
+/// need heap, `memory::init_kernel_memory` and interrupts enabled (delays read the clocksource).
+/// a processor not answering in time is reported and left offline. called again: only count.
+pub fn init() -> Result<usize, SmpError> {
+    let _init = INIT.lock();
+    if !CPUS.lock().is_empty() {
+        return Ok(online_count());
+    }
+
+    let mut apic_ids = Vec::new();
+    let apic_phys = acpi::local_apics(|local| apic_ids.push(local.apic_id))?;
+    let page_table = memory::kernel_page_table().ok_or(SmpError::NoKernelMemory)?;
+    if page_table.start_address().as_u64() > u64::from(u32::MAX) {
+        return Err(SmpError::PageTableAbove4GiB);
+    }
+    let (apic, frame) = memory::with_kernel_memory(|memory| -> Result<_, SmpError> {
+        let frame = memory.frame_allocator.real_mode_frame().ok_or(SmpError::NoRealModeFrame)?;
+        let base = memory::map_mmio(apic_phys, APIC_REGISTERS_SIZE, &mut memory.mapper, &mut memory.frame_allocator)?;
+        // the processor enable paging with the next instruction at the same (physical) address
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        match memory.mapper.translate_page(page) {
+            Ok(mapped) if mapped == frame => {}
+            Ok(_) | Err(TranslateError::InvalidFrameAddress(_)) => return Err(SmpError::TrampolineMapped),
+            Err(_) => unsafe {
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                memory.mapper.map_to(page, frame, flags, &mut memory.frame_allocator)?.flush();
+            },
+        }
+        Ok((LocalApic { base }, frame))
+    }).ok_or(SmpError::NoKernelMemory)??;
+    let trampoline = Trampoline::install(frame, page_table)?;
+
+    let bootstrap = apic.id();
+    let cpu: &'static Cpu = Box::leak(Box::new(Cpu {
+        index: 0,
+        apic_id: bootstrap,
+        idle_thread: thread::BOOT_THREAD,
+        tables: None,
+        stacks: Vec::new(),
+        online: AtomicBool::new(true),
+        interrupt_depth: AtomicU64::new(0),
+    }));
+    CPUS.lock().push(cpu);
+    STARTED.store(true, Ordering::SeqCst);
+
+    for apic_id in apic_ids.into_iter().filter(|&id| id != bootstrap).take(MAX_CPUS - 1) {
+        let cpu = prepare(CPUS.lock().len(), apic_id)?;
+        CPUS.lock().push(cpu);
+        if !start(&apic, &trampoline, cpu) {
+            serial_println!("SMP: CPU {} (APIC id {}) did not start", cpu.index, apic_id);
+        }
+    }
+    Ok(online_count())
+}
+
+// stacks and tables of the processor `index`
+fn prepare(index: usize, apic_id: u8) -> Result<&'static Cpu, SmpError> {
+    let boot = Stack::new()?;
+    let mut stacks = Vec::new();
+    for _ in 0..IST_STACK_COUNT {
+        stacks.push(Stack::new()?);
+    }
+    let interrupt_stacks = core::array::from_fn(|index| stacks[index].top());
+    // interrupt from ring 3 never happen: the boot stack is also rsp0
+    let tables = CpuTables::new(interrupt_stacks, boot.top());
+    stacks.insert(0, boot);
+    Ok(Box::leak(Box::new(Cpu {
+        index,
+        apic_id,
+        idle_thread: thread::reserve_id(),
+        tables: Some(tables),
+        stacks,
+        online: AtomicBool::new(false),
+        interrupt_depth: AtomicU64::new(0),
+    })))
+}
+
+// INIT-SIPI-SIPI `cpu`, wait until it is online
+fn start(apic: &LocalApic, trampoline: &Trampoline, cpu: &'static Cpu) -> bool {
+    let stack = cpu.stacks[0].top();
+    unsafe {
+        trampoline.write(addr_of!(ap_trampoline_stack), stack.as_u64());
+        trampoline.write(addr_of!(ap_trampoline_cpu), cpu as *const Cpu as u64);
+    }
+    apic.send_ipi(cpu.apic_id, ICR_INIT);
+    delay(INIT_DELAY_NS);
+    for _ in 0..2 {
+        apic.send_ipi(cpu.apic_id, ICR_STARTUP | trampoline.vector());
+        delay(STARTUP_DELAY_NS);
+        if cpu.online.load(Ordering::Acquire) {
+            return true;
+        }
+    }
+    let deadline = time::monotonic_ns() + ONLINE_TIMEOUT_NS;
+    while time::monotonic_ns() < deadline {
+        if cpu.online.load(Ordering::Acquire) {
+            return true;
+        }
+        spin_loop();
+    }
+    false
+}
+
+// application processor entry from the trampoline: boot stack, kernel page table
+extern "C" fn ap_main(cpu: &'static Cpu) -> ! {
+    GsBase::write(VirtAddr::from_ptr(cpu));
+    if let Some(tables) = cpu.tables {
+        tables.load();
+    }
+    interrupts::init_idt();
+    serial_println!("SMP: CPU {} online (APIC id {})", cpu.index, cpu.apic_id);
+    cpu.online.store(true, Ordering::Release);
+    // idle: only NMI and IPIs wake it
+    x86_64::instructions::interrupts::enable();
+    crate::hlt_loop()
+}
+
+/// the application processor running the caller, None on the bootstrap processor.
+pub fn current_ap() -> Option<&'static Cpu> {
+    if !STARTED.load(Ordering::Relaxed) {
+        return None;
+    }
+    // GS base: set by `ap_main`, 0 on the bootstrap processor
+    let cpu = GsBase::read();
+    (!cpu.is_null()).then(|| unsafe { &*cpu.as_ptr::<Cpu>() })
+}
+
+/// processors online, bootstrap included (1 before `init`).
+pub fn online_count() -> usize {
+    let cpus = CPUS.lock();
+    if cpus.is_empty() {
+        return 1;
+    }
+    cpus.iter().filter(|cpu| cpu.online.load(Ordering::Acquire)).count()
+}
+
+/// every processor found by `init`, bootstrap first.
+pub fn cpus() -> Vec<CpuInfo> {
+    CPUS.lock().iter().map(|cpu| cpu.info()).collect()
+}
+
+
+#[test_case]
+fn test_trampoline_layout() {
+    assert!(offset(addr_of!(ap_trampoline_end)) <= 4096);
+    // offsets used by the real mode code
+    assert_eq!(offset(addr_of!(ap_trampoline_cr0)), TRAMPOLINE_CR0);
+    assert_eq!(offset(addr_of!(ap_trampoline_cr3)), TRAMPOLINE_CR3);
+    assert_eq!(offset(addr_of!(ap_trampoline_cr4)), TRAMPOLINE_CR4);
+    assert_eq!(offset(addr_of!(ap_trampoline_efer)), TRAMPOLINE_EFER);
+    assert_eq!(offset(addr_of!(ap_trampoline_gdtr)), TRAMPOLINE_GDTR);
+    assert_eq!(offset(addr_of!(ap_trampoline_gdt)) + 24, TRAMPOLINE_GDTR);
+    assert_eq!(current_ap().map(|cpu| cpu.index), None);
+}
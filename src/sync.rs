@@ -0,0 +1,22 @@
+//! this module impl kros synchronization primitives.
+//! `
+//!     - Spinlock: record the holder, report deadlock over serial instead of hanging (debug build)
+//!     - IrqSpinlock: spin with interrupts disabled, shared by interrupt handlers and threads
+//!     - Mutex / Condvar / RwLock / Semaphore: park the running thread in a `WaitQueue` instead of spinning
+//! `
+//! sleeping primitives block: never lock them in interrupt handlers (`Semaphore::release` and
+//! `Condvar::notify_*` are interrupt safe). before `thread::init` they `hlt` between checks.
+
+pub mod condvar;
+pub mod irq_spinlock;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spinlock;
+
+pub use condvar::Condvar;
+pub use irq_spinlock::{IrqSpinlock, IrqSpinlockGuard};
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use spinlock::{Spinlock, SpinlockGuard};
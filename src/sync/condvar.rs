@@ -0,0 +1,57 @@
+//! this module impl a condition variable for the sleeping `Mutex`.
+//! `
+//!     - wait: enqueue, unlock the mutex, block -> lock again when notified
+//!     - notify_one / notify_all: wake waiters, interrupt safe
+//! `
+//! a waiter can wake without its condition true: check it in a loop (`wait_while`).
+
+use super::mutex::MutexGuard;
+use crate::thread::WaitQueue;
+
+/// define a queue of threads waiting for a condition protected by a `Mutex`.
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar { waiters: WaitQueue::new() }
+    }
+
+    /// unlock `guard`, block until notified, lock again.
+    ///
+    /// a notify after the unlock is not lost: the thread is queued before it.
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        self.waiters.wait_after(|| drop(guard));
+        mutex.lock()
+    }
+
+    /// block while `condition` is true, return with the lock held and `condition` false.
+    pub fn wait_while<'a, T: ?Sized>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// wake one waiter, false if none.
+    pub fn notify_one(&self) -> bool {
+        self.waiters.wake_one()
+    }
+
+    /// wake every waiter, return the number woken.
+    pub fn notify_all(&self) -> usize {
+        self.waiters.wake_all()
+    }
+}
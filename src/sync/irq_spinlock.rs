@@ -0,0 +1,119 @@
+//! this module impl a spinlock held with interrupts disabled.
+//! `
+//!     - lock: save IF, `cli`, spin -> guard
+//!     - guard drop: unlock, then `sti` only if interrupts were enabled before lock (nesting safe)
+//! `
+//! an interrupt handler taking the same lock can't interrupt the holder: no deadlock on one CPU.
+//! inner `Spinlock`: holder recorded, re-entrant lock reported (debug build).
+
+use core::ops::{Deref, DerefMut};
+
+use x86_64::instructions::interrupts;
+
+use super::spinlock::{Holder, Spinlock, SpinlockGuard};
+
+/// define a spinlock usable from both interrupt handlers and threads.
+pub struct IrqSpinlock<T: ?Sized> {
+    inner: Spinlock<T>,
+}
+
+/// define the guard of a held `IrqSpinlock`, interrupts disabled while it live.
+pub struct IrqSpinlockGuard<'a, T: ?Sized + 'a> {
+    // Option: unlock before restore interrupts
+    guard: Option<SpinlockGuard<'a, T>>,
+    enabled: bool,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSpinlock { inner: Spinlock::new(value) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    /// disable interrupts and spin until the lock is free.
+    #[track_caller]
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSpinlockGuard { guard: Some(self.inner.lock()), enabled }
+    }
+
+    /// lock if free, interrupts untouched on failure.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinlockGuard { guard: Some(guard), enabled }),
+            None => {
+                if enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// who hold the lock, None if free.
+    pub fn holder(&self) -> Option<Holder> {
+        self.inner.holder()
+    }
+
+    /// mutable access without locking: `&mut self` prove no guard exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().expect("guard already released")
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().expect("guard already released")
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+
+#[test_case]
+fn test_irq_spinlock_restore_interrupts() {
+    let lock = IrqSpinlock::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut outer = lock.lock();
+        *outer += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+        assert!(!interrupts::are_enabled()); // failed try_lock keep them disabled
+
+        // nested lock: interrupts stay disabled after the inner guard drop
+        let other = IrqSpinlock::new(());
+        drop(other.lock());
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*lock.lock(), 1);
+}
@@ -0,0 +1,48 @@
+//! this module impl a counting semaphore.
+//! `
+//!     - acquire: take one permit, block until one is available
+//!     - release: give one permit back, wake one waiter (interrupt safe: signal a thread from a handler)
+//! `
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::thread::WaitQueue;
+
+/// define a counter of permits, threads block while it is zero.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// block the running thread until a permit is taken.
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            self.waiters.wait_until(|| self.available() > 0);
+        }
+    }
+
+    /// take a permit if one is available.
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| permits.checked_sub(1))
+            .is_ok()
+    }
+
+    /// add a permit and wake one waiter.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
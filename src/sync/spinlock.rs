@@ -0,0 +1,248 @@
+//! this module impl the kernel spinlock with lock debugging.
+//! `
+//!     - lock(#[track_caller]): spin until free, record the owner: caller location, thread, interrupt context
+//!     - debug build: every spin check the owner
+//!         - held by the running thread (re-entrant, or an interrupt over the holder) -> deadlock now
+//!         - spun longer than `spin_timeout` -> deadlock suspected
+//!       report both sides over COM1 without the SERIAL_1 lock, then panic
+//!     - after a report locks are busted: a deadlocked lock is stolen, the panic handler can still print
+//! `
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use crate::{interrupts, serial, thread};
+
+/// check owners and spin time (debug build).
+pub const LOCK_DEBUG: bool = cfg!(debug_assertions);
+/// default spins before a wait is reported as deadlock.
+pub const DEFAULT_SPIN_TIMEOUT: u64 = 1 << 28;
+
+const NO_OWNER: u64 = u64::MAX;
+
+static SPIN_TIMEOUT: AtomicU64 = AtomicU64::new(DEFAULT_SPIN_TIMEOUT);
+// set by the first report: stop checking, steal deadlocked locks
+static BUSTED: AtomicBool = AtomicBool::new(false);
+
+/// spins before a wait is reported as deadlock.
+pub fn spin_timeout() -> u64 {
+    SPIN_TIMEOUT.load(Ordering::Relaxed)
+}
+
+pub fn set_spin_timeout(spins: u64) {
+    SPIN_TIMEOUT.store(spins.max(1), Ordering::Relaxed);
+}
+
+/// define where a lock was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Holder {
+    pub location: &'static Location<'static>,
+    pub thread: thread::ThreadId,
+    pub interrupt: bool,
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let context = if self.interrupt { "interrupt over thread" } else { "thread" };
+        write!(f, "{} {} at {}", context, self.thread.as_u64(), self.location)
+    }
+}
+
+/// define why a lock wait can't end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deadlock {
+    /// the holder is the waiter itself.
+    Reentrant,
+    /// the holder is the code the waiting interrupt handler interrupted.
+    InterruptedHolder,
+    /// spun `spin_timeout` times.
+    Timeout,
+}
+
+/// define a spinlock recording its holder.
+pub struct Spinlock<T: ?Sized> {
+    locked: AtomicBool,
+    // holder, cleared before unlock: a stale holder never match the waiter
+    owner_thread: AtomicU64,
+    owner_interrupt: AtomicBool,
+    owner_location: AtomicPtr<Location<'static>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Spinlock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Spinlock<T> {}
+
+/// define the guard of a held `Spinlock`, unlock on drop.
+pub struct SpinlockGuard<'a, T: ?Sized + 'a> {
+    lock: &'a Spinlock<T>,
+}
+
+impl<T> Spinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Spinlock {
+            locked: AtomicBool::new(false),
+            owner_thread: AtomicU64::new(NO_OWNER),
+            owner_interrupt: AtomicBool::new(false),
+            owner_location: AtomicPtr::new(ptr::null_mut()),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Spinlock<T> {
+    /// spin until the lock is free, report a deadlock instead of hanging (debug build).
+    #[track_caller]
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        let location = Location::caller();
+        let mut spins = 0;
+        while !self.acquire() {
+            if LOCK_DEBUG {
+                if let Some(deadlock) = self.check(spins) {
+                    if BUSTED.swap(true, Ordering::SeqCst) {
+                        // already panicking: take it, the holder never run again
+                        break;
+                    }
+                    self.report(deadlock, location);
+                }
+            }
+            spins += 1;
+            spin_loop();
+        }
+        self.set_owner(location);
+        SpinlockGuard { lock: self }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        let location = Location::caller();
+        self.acquire().then(|| {
+            self.set_owner(location);
+            SpinlockGuard { lock: self }
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// who hold the lock, None if free or owner not recorded yet.
+    pub fn holder(&self) -> Option<Holder> {
+        let thread = self.owner_thread.load(Ordering::Acquire);
+        let location = self.owner_location.load(Ordering::Acquire);
+        if !self.is_locked() || thread == NO_OWNER || location.is_null() {
+            return None;
+        }
+        Some(Holder {
+            location: unsafe { &*location },
+            thread: thread::ThreadId::from_u64(thread),
+            interrupt: self.owner_interrupt.load(Ordering::Relaxed),
+        })
+    }
+
+    /// mutable access without locking: `&mut self` prove no guard exist.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// unlock without a guard.
+    ///
+    /// # Safety
+    ///
+    /// the holder must never touch the data again (its guard is leaked or it will never run).
+    pub unsafe fn force_unlock(&self) {
+        self.release();
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    fn release(&self) {
+        self.owner_thread.store(NO_OWNER, Ordering::Relaxed);
+        self.owner_location.store(ptr::null_mut(), Ordering::Relaxed);
+        self.locked.store(false, Ordering::Release);
+    }
+
+    fn set_owner(&self, location: &'static Location<'static>) {
+        self.owner_interrupt.store(interrupts::in_interrupt(), Ordering::Relaxed);
+        self.owner_location.store(location as *const _ as *mut _, Ordering::Release);
+        self.owner_thread.store(thread::current_id().as_u64(), Ordering::Release);
+    }
+
+    // the wait can't end: held on this CPU by the running thread, or too long
+    fn check(&self, spins: u64) -> Option<Deadlock> {
+        if spins >= spin_timeout() {
+            return Some(Deadlock::Timeout);
+        }
+        let holder = self.holder()?;
+        if holder.thread != thread::current_id() {
+            // another thread: it run again after a switch
+            return None;
+        }
+        if holder.interrupt == interrupts::in_interrupt() {
+            Some(Deadlock::Reentrant)
+        } else {
+            Some(Deadlock::InterruptedHolder)
+        }
+    }
+
+    fn report(&self, deadlock: Deadlock, location: &'static Location<'static>) -> ! {
+        let waiter = Holder { location, thread: thread::current_id(), interrupt: interrupts::in_interrupt() };
+        // the stuck lock may be SERIAL_1 itself
+        serial::_print_unlocked(format_args!("\nLOCK DEADLOCK ({:?})\n  waiter: {}\n", deadlock, waiter));
+        match self.holder() {
+            Some(holder) => serial::_print_unlocked(format_args!("  holder: {}\n", holder)),
+            None => serial::_print_unlocked(format_args!("  holder: unknown\n")),
+        }
+        panic!("lock deadlock ({:?}) at {}", deadlock, location);
+    }
+}
+
+impl<T: ?Sized> Deref for SpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+    }
+}
+
+
+#[test_case]
+fn test_holder_location() {
+    let lock = Spinlock::new(0);
+    assert!(lock.holder().is_none());
+    let guard = lock.lock();
+    let line = line!() - 1;
+    let holder = lock.holder().expect("holder not recorded");
+    assert_eq!(holder.location.line(), line);
+    assert_eq!(holder.location.file(), file!());
+    assert_eq!(holder.thread, thread::current_id());
+    assert!(!holder.interrupt);
+    // held by the running thread: waiting for it can't end
+    assert_eq!(lock.check(0), Some(Deadlock::Reentrant));
+    assert_eq!(lock.check(spin_timeout()), Some(Deadlock::Timeout));
+    drop(guard);
+    assert!(lock.holder().is_none());
+    assert_eq!(lock.check(0), None);
+}
@@ -0,0 +1,389 @@
+//! this module impl the kros system call interface.
+//! `
+//!     - `syscall` (LSTAR -> syscall_entry): switch to the thread kernel stack (TSS rsp0), save a TrapFrame
+//!     - `int 0x80` (DPL 3 gate): same TrapFrame through the trap entry
+//!     - number in rax, arguments in rdi, rsi, rdx, r10, r8, r9, result in rax (-errno on error)
+//!     - dispatch table: write, exit, yield, sleep, time, getpid, wait, kill, sigaction, sigprocmask;
+//!       fork and sigreturn (need the whole frame) before it
+//!     - pointer arguments: checked mapped USER_ACCESSIBLE in the thread address space
+//!     - return: pending signals delivered, then sysretq, iretq when the frame was rewritten (rcx / r11 differ
+//!       from rip / rflags)
+//! `
+//! handlers run with interrupts enabled on the thread kernel stack: they can block and be preempted.
+
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::interrupts::trap::TrapFrame;
+use crate::time::{self, Duration};
+use crate::process::signal::{self, Action, SigSet, Signal};
+use crate::process::{self, ExitStatus, Pid, ProcessError};
+use crate::{gdt, print, thread};
+
+/// `int 0x80` gate vector, also the TrapFrame vector of `syscall`.
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// write(ptr, len) -> len: print UTF-8 bytes to the console.
+pub const SYS_WRITE: u64 = 0;
+/// exit(code) -> never return: end the calling process (kernel spawned user thread: the thread).
+pub const SYS_EXIT: u64 = 1;
+/// yield() -> 0: give the CPU to the next ready thread.
+pub const SYS_YIELD: u64 = 2;
+/// sleep(milliseconds) -> 0: block the calling thread.
+pub const SYS_SLEEP: u64 = 3;
+/// time() -> nanoseconds since boot.
+pub const SYS_TIME: u64 = 4;
+/// getpid() -> pid of the calling process.
+pub const SYS_GETPID: u64 = 5;
+/// wait(pid) -> wait status of the reaped child (`ExitStatus::wait_status`).
+pub const SYS_WAIT: u64 = 6;
+/// fork() -> child pid in the parent, 0 in the child.
+pub const SYS_FORK: u64 = 7;
+/// kill(pid, signal) -> 0: send `signal` to process `pid`.
+pub const SYS_KILL: u64 = 8;
+/// sigaction(signal, handler, restorer) -> old handler: `SIG_DFL`, `SIG_IGN` or a handler returning to `restorer`.
+pub const SYS_SIGACTION: u64 = 9;
+/// sigprocmask(how, set) -> old blocked mask: `SIG_BLOCK`, `SIG_UNBLOCK` or `SIG_SETMASK`.
+pub const SYS_SIGPROCMASK: u64 = 10;
+/// sigreturn() -> never return to the caller: resume the code a signal handler interrupted.
+pub const SYS_SIGRETURN: u64 = 11;
+
+/// longest write (bytes).
+pub const MAX_WRITE: u64 = 4096;
+/// longest sleep (milliseconds): one day.
+pub const MAX_SLEEP_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// define system call errors, returned as -errno.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum SyscallError {
+    /// the caller (or the waited pid) is not a process.
+    NoProcess = 3,
+    /// the waited process is not a child of the caller.
+    NoChild = 10,
+    /// out of frames or kernel memory.
+    NoMemory = 12,
+    /// bad user pointer.
+    Fault = 14,
+    /// bad argument value.
+    Invalid = 22,
+    /// unknown system call number.
+    NoSys = 38,
+}
+
+impl SyscallError {
+    pub fn errno(self) -> u64 {
+        self as u64
+    }
+
+    fn from_errno(errno: u64) -> Option<Self> {
+        match errno {
+            3 => Some(SyscallError::NoProcess),
+            10 => Some(SyscallError::NoChild),
+            12 => Some(SyscallError::NoMemory),
+            14 => Some(SyscallError::Fault),
+            22 => Some(SyscallError::Invalid),
+            38 => Some(SyscallError::NoSys),
+            _ => None,
+        }
+    }
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(error: ProcessError) -> Self {
+        match error {
+            ProcessError::NoProcess | ProcessError::NotProcess => SyscallError::NoProcess,
+            ProcessError::NotChild => SyscallError::NoChild,
+            ProcessError::BadHandle | ProcessError::TooManyResources => SyscallError::Invalid,
+            ProcessError::Spawn(_) | ProcessError::User(_) => SyscallError::NoMemory,
+            ProcessError::InvalidSignal => SyscallError::Invalid,
+            ProcessError::BadSignalFrame => SyscallError::Fault,
+        }
+    }
+}
+
+/// rax value of a result.
+pub fn encode(result: Result<u64, SyscallError>) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(error) => error.errno().wrapping_neg(),
+    }
+}
+
+/// result of a rax value: -4095..-1 are errors.
+pub fn decode(rax: u64) -> Result<u64, SyscallError> {
+    match SyscallError::from_errno(rax.wrapping_neg()) {
+        Some(error) if rax.wrapping_neg() < 4096 => Err(error),
+        _ => Ok(rax),
+    }
+}
+
+type Handler = fn(&[u64; 6]) -> Result<u64, SyscallError>;
+
+// indexed by system call number
+static TABLE: [Handler; 11] = [
+    sys_write, sys_exit, sys_yield, sys_sleep, sys_time, sys_getpid, sys_wait, sys_fork_stub, sys_kill, sys_sigaction,
+    sys_sigprocmask,
+];
+
+// user selectors pushed by syscall_entry (sysret load the same ones from STAR)
+static USER_CS: AtomicU64 = AtomicU64::new(0);
+static USER_SS: AtomicU64 = AtomicU64::new(0);
+// user rsp until pushed: interrupts disabled by SFMASK, one CPU
+static USER_RSP: AtomicU64 = AtomicU64::new(0);
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "    mov [rip + {user_rsp}], rsp",
+    "    mov rsp, [rip + {tss} + 4]", // TSS privilege_stack_table[0]: the thread kernel stack
+    // iretq frame: ss, rsp, rflags (r11), cs, rip (rcx)
+    "    push qword ptr [rip + {user_ss}]",
+    "    push qword ptr [rip + {user_rsp}]",
+    "    push r11",
+    "    push qword ptr [rip + {user_cs}]",
+    "    push rcx",
+    "    push 0",
+    "    push {vector}",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    // 22 * 8 bytes below a 16 byte aligned stack top: aligned for call
+    "    mov rdi, rsp",
+    "    cld",
+    "    call {dispatch}",
+    // sysretq load rip from rcx, rflags from r11: only if the frame still agree
+    "    mov rax, [rsp + 96]",  // rcx
+    "    cmp rax, [rsp + 136]", // rip
+    "    jne 2f",
+    "    mov rax, [rsp + 32]",  // r11
+    "    cmp rax, [rsp + 152]", // rflags
+    "    jne 2f",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    "    mov rsp, [rsp + 40]", // user rsp: after vector, error code, rip, cs, rflags
+    "    sysretq",
+    "2:",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    "    add rsp, 16", // vector, error code
+    "    iretq",
+    user_rsp = sym USER_RSP,
+    user_ss = sym USER_SS,
+    user_cs = sym USER_CS,
+    tss = sym gdt::TSS,
+    vector = const SYSCALL_VECTOR,
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// enable `syscall`: EFER.SCE, STAR selectors, LSTAR entry, SFMASK (IF, TF, DF cleared on entry).
+///
+/// need `gdt::init`: STAR read the GDT layout.
+pub fn init() {
+    USER_CS.store(u64::from(gdt::user_code_selector().0), Ordering::Relaxed);
+    USER_SS.store(u64::from(gdt::user_data_selector().0), Ordering::Relaxed);
+    Star::write(
+        gdt::user_code_selector(),
+        gdt::user_data_selector(),
+        gdt::kernel_code_selector(),
+        gdt::kernel_data_selector(),
+    ).expect("GDT layout not usable by syscall / sysret");
+    LStar::write(VirtAddr::from_ptr(syscall_entry as *const ()));
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+extern "C" fn syscall_dispatch(frame: &mut TrapFrame) {
+    handle(frame);
+    // int 0x80 deliver in trap_dispatch
+    signal::deliver(frame);
+}
+
+/// run the system call saved in `frame` (`syscall` or `int 0x80`), result to `frame.rax`.
+///
+/// entered and left with interrupts disabled.
+pub(crate) fn handle(frame: &mut TrapFrame) {
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+    interrupts::enable();
+    let result = match frame.rax {
+        SYS_FORK => sys_fork(frame),
+        SYS_SIGRETURN => sys_sigreturn(frame),
+        number => dispatch(number, &args),
+    };
+    frame.rax = encode(result);
+    interrupts::disable();
+}
+
+/// call handler `number` of the table.
+pub fn dispatch(number: u64, args: &[u64; 6]) -> Result<u64, SyscallError> {
+    let handler = usize::try_from(number).ok()
+        .and_then(|number| TABLE.get(number))
+        .ok_or(SyscallError::NoSys)?;
+    handler(args)
+}
+
+/// bytes at [ptr, ptr + len) if mapped USER_ACCESSIBLE (+ `flags`) in the calling thread address space.
+fn user_bytes(ptr: u64, len: u64, flags: PageTableFlags) -> Result<&'static [u8], SyscallError> {
+    let addr = VirtAddr::try_new(ptr).map_err(|_| SyscallError::Fault)?;
+    let space = thread::address_space().ok_or(SyscallError::Fault)?;
+    if !space.is_mapped(addr, len, flags) {
+        return Err(SyscallError::Fault);
+    }
+    // mapped in the active space, nothing unmap user pages under a running thread
+    Ok(unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), len as usize) })
+}
+
+fn sys_write(args: &[u64; 6]) -> Result<u64, SyscallError> {
+    let (ptr, len) = (args[0], args[1]);
+    if len > MAX_WRITE {
+        return Err(SyscallError::Invalid);
+    }
+    let bytes = user_bytes(ptr, len, PageTableFlags::empty())?;
+    let text = core::str::from_utf8(bytes).map_err(|_| SyscallError::Invalid)?;
+    print!("{}", text);
+    Ok(len)
+}
+
+fn sys_exit(args: &[u64; 6]) -> Result<u64, SyscallError> {
+    process::exit(ExitStatus::Exited(args[0]))
+}
+
+fn sys_yield(_args: &[u64; 6]) -> Result<u64, SyscallError> {
+    thread::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep(args: &[u64; 6]) -> Result<u64, SyscallError> {
+    if args[0] > MAX_SLEEP_MS {
+        return Err(SyscallError::Invalid);
+    }
+    thread::sleep(Duration::from_millis(args[0]));
+    Ok(0)
+}
+
+fn sys_time(_args: &[u64; 6]) -> Result<u64, SyscallError> {
+    Ok(time::monotonic_ns())
+}
+
+fn sys_getpid(_args: &[u64; 6]) -> Result<u64, SyscallError> {
+    process::current().map(|pid| pid.as_u64()).ok_or(SyscallError::NoProcess)
+}
+
+fn sys_wait(args: &[u64; 6]) -> Result<u64, SyscallError> {
+    let status = process::wait(Pid::from_u64(args[0])).map_err(SyscallError::from)?;
+    Ok(status.wait_status())
+}
+
+fn sys_kill(args: &[u64; 6]) -> Result<u64, SyscallError> {
+    let signal = Signal::new(args[1]).ok_or(SyscallError::Invalid)?;
+    signal::send(Pid::from_u64(args[0]), signal)?;
+    Ok(0)
+}
+
+fn sys_sigaction(args: &[u64; 6]) -> Result<u64, SyscallError> {
+    let signal = Signal::new(args[0]).ok_or(SyscallError::Invalid)?;
+    let action = match (args[1], args[2]) {
+        (signal::SIG_DFL, _) => Action::Default,
+        (signal::SIG_IGN, _) => Action::Ignore,
+        // the handler return to the restorer: it must call sigreturn
+        (_, 0) => return Err(SyscallError::Invalid),
+        (handler, restorer) => Action::Handler {
+            handler: VirtAddr::try_new(handler).map_err(|_| SyscallError::Fault)?,
+            restorer: VirtAddr::try_new(restorer).map_err(|_| SyscallError::Fault)?,
+        },
+    };
+    Ok(match signal::set_action(signal, action)? {
+        Action::Default => signal::SIG_DFL,
+        Action::Ignore => signal::SIG_IGN,
+        Action::Handler { handler, .. } => handler.as_u64(),
+    })
+}
+
+fn sys_sigprocmask(args: &[u64; 6]) -> Result<u64, SyscallError> {
+    let old = signal::set_blocked(args[0], SigSet::from_bits(args[1]))?;
+    Ok(old.bits())
+}
+
+// fork need the caller frame: handled before the table
+fn sys_fork_stub(_args: &[u64; 6]) -> Result<u64, SyscallError> {
+    Err(SyscallError::NoSys)
+}
+
+fn sys_fork(frame: &TrapFrame) -> Result<u64, SyscallError> {
+    let pid = process::fork(frame).map_err(SyscallError::from)?;
+    Ok(pid.as_u64())
+}
+
+fn sys_sigreturn(frame: &mut TrapFrame) -> Result<u64, SyscallError> {
+    // rax of the interrupted code, unchanged
+    Ok(signal::sigreturn(frame)?)
+}
+
+
+#[test_case]
+fn test_syscall_dispatch() {
+    assert_eq!(dispatch(99, &[0; 6]), Err(SyscallError::NoSys));
+    // kernel thread: no user address space
+    assert_eq!(dispatch(SYS_WRITE, &[0x1000, 4, 0, 0, 0, 0]), Err(SyscallError::Fault));
+    assert_eq!(dispatch(SYS_WRITE, &[0, MAX_WRITE + 1, 0, 0, 0, 0]), Err(SyscallError::Invalid));
+    assert!(dispatch(SYS_TIME, &[0; 6]).is_ok());
+    assert_eq!(dispatch(SYS_GETPID, &[0; 6]), Err(SyscallError::NoProcess));
+    assert_eq!(dispatch(SYS_WAIT, &[u64::MAX, 0, 0, 0, 0, 0]), Err(SyscallError::NoProcess));
+    // fork need the caller frame: not in the table
+    assert_eq!(dispatch(SYS_FORK, &[0; 6]), Err(SyscallError::NoSys));
+    assert_eq!(dispatch(SYS_SIGRETURN, &[0; 6]), Err(SyscallError::NoSys));
+    assert_eq!(dispatch(SYS_KILL, &[u64::MAX, 9, 0, 0, 0, 0]), Err(SyscallError::NoProcess));
+    assert_eq!(dispatch(SYS_KILL, &[1, 0, 0, 0, 0, 0]), Err(SyscallError::Invalid));
+    assert_eq!(dispatch(SYS_SIGACTION, &[2, 0x1000, 0, 0, 0, 0]), Err(SyscallError::Invalid));
+    assert_eq!(dispatch(SYS_SIGPROCMASK, &[0; 6]), Err(SyscallError::NoProcess));
+    assert_eq!(decode(encode(Err(SyscallError::Fault))), Err(SyscallError::Fault));
+    assert_eq!(decode(42), Ok(42));
+}
@@ -0,0 +1,160 @@
+//! this module impl the kros async executor.
+//! `
+//!     - tasks: TaskId -> Task, task_queue: woken TaskId (lock-free, waker push from interrupt)
+//!     - spawn: at most TASK_QUEUE_SIZE unfinished tasks, so task_queue never overflow
+//!     - TaskWaker: wake = push TaskId once until polled, cached per task
+//!     - run: poll ready tasks, run deferred work, `sti; hlt` when both queues empty
+//! `
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+use super::{Task, TaskId};
+use crate::deferred;
+
+/// max unfinished tasks, also max woken tasks waiting to be polled.
+pub const TASK_QUEUE_SIZE: usize = 100;
+
+/// define why a task can't be spawned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// `TASK_QUEUE_SIZE` tasks unfinished.
+    Full,
+}
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    /// add a task, polled on the next run.
+    pub fn spawn(&mut self, task: Task) -> Result<(), SpawnError> {
+        // each task queued at most once: task_queue hold every task at worst
+        if self.tasks.len() >= TASK_QUEUE_SIZE {
+            return Err(SpawnError::Full);
+        }
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        // no waker yet: not queued by anything else
+        let _ = self.task_queue.push(task_id);
+        Ok(())
+    }
+
+    /// number of unfinished tasks.
+    pub fn task_count(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// poll every woken task once.
+    fn run_ready_tasks(&mut self) {
+        // destructure `self` to avoid borrow checker errors
+        let Self { tasks, task_queue, waker_cache } = self;
+
+        while let Some(task_id) = task_queue.pop() {
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, // task no longer exists
+            };
+            let task_waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+            // before poll: a wake during the poll queue the task again
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {
+                    // task done -> remove it and its cached waker
+                    tasks.remove(&task_id);
+                    // a waker clone kept by someone never queue the task again
+                    task_waker.queued.store(true, Ordering::Release);
+                    waker_cache.remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    /// poll tasks and run deferred work until nothing is ready, return without `hlt`.
+    pub fn run_until_idle(&mut self) {
+        while !self.task_queue.is_empty() || deferred::has_pending() {
+            self.run_ready_tasks();
+            deferred::run_pending();
+        }
+    }
+
+    /// kernel idle loop: replace `deferred::run_worker`.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            deferred::run_pending();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // disable interrupt before check: a wake between check and hlt would be lost
+        interrupts::disable();
+        if self.task_queue.is_empty() && !deferred::has_pending() {
+            interrupts::enable_and_hlt(); // `sti; hlt` atomic
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    // in task_queue, not polled yet: more wakes are merged
+    queued: AtomicBool,
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Arc<Self> {
+        Arc::new(TaskWaker { task_id, task_queue, queued: AtomicBool::new(false) })
+    }
+
+    // no alloc, no lock, no panic: callable from interrupt handler
+    fn wake_task(&self) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        // can't be full: spawn keep the unfinished tasks within TASK_QUEUE_SIZE
+        let _ = self.task_queue.push(self.task_id);
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
@@ -0,0 +1,388 @@
+//! this module impl kros preemptive kernel threads.
+//! `
+//!     - spawn: map a guarded stack, build the initial TrapFrame on it -> ready queue
+//!     - context switch: timer / yield trap save the TrapFrame, trap_dispatch resume another one
+//!     - scheduler: strict priority levels, round-robin TIME_SLICE ticks inside a level,
+//!       idle thread `hlt` when nothing ready
+//!     - sleep: blocked until a wake tick, woken by the timer interrupt
+//!     - WaitQueue: block until woken, wake callable from interrupt handlers
+//!     - yield_now / exit / JoinHandle::join, stats: context switches, runtime per thread
+//!     - set_address_space: a thread running user code own its address space, loaded when switched in
+//! `
+//! thread 0 is the boot flow (kernel_main) on the bootloader stack, it can't exit.
+
+pub mod stack;
+pub mod wait;
+mod scheduler;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+
+use crate::interrupts::trap::{TrapFrame, RFLAGS_INTERRUPT};
+use crate::interrupts::YIELD_VECTOR;
+use crate::smp;
+use crate::sync::IrqSpinlock;
+use crate::time::{self, Duration, Instant};
+use crate::user::AddressSpace;
+use scheduler::{Scheduler, Thread};
+use stack::Stack;
+
+pub use wait::WaitQueue;
+
+/// timer ticks a thread run before the next ready one is switched in.
+pub const TIME_SLICE: u64 = 10;
+
+/// define a unique thread id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    /// id from `as_u64`, e.g. a lock owner record.
+    pub const fn from_u64(id: u64) -> Self {
+        ThreadId(id)
+    }
+}
+
+/// the boot flow: `kernel_main` and everything before `init`.
+pub const BOOT_THREAD: ThreadId = ThreadId(0);
+
+/// number of priority levels.
+pub const PRIORITY_LEVELS: usize = 3;
+
+/// define a scheduling level: a ready thread never run while a higher level is ready.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+impl Priority {
+    /// lowest first.
+    pub const ALL: [Priority; PRIORITY_LEVELS] = [Priority::Low, Priority::Normal, Priority::High];
+}
+
+/// define the scheduling state of a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    /// in a run queue.
+    Ready,
+    Running,
+    /// sleeping, joining or in a wait queue.
+    Blocked,
+    /// ended, not joined yet.
+    Dead,
+}
+
+/// define scheduling statistics of one thread.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadStats {
+    pub id: ThreadId,
+    pub priority: Priority,
+    pub state: ThreadState,
+    /// the idle thread: run only when nothing else is ready.
+    pub idle: bool,
+    /// times the thread was switched in.
+    pub switches: u64,
+    /// time on CPU (clocksource resolution).
+    pub runtime: Duration,
+}
+
+/// define why a thread can't be created.
+#[derive(Debug)]
+pub enum SpawnError {
+    /// `memory::init_kernel_memory` not called: can't map the stack.
+    NoKernelMemory,
+    /// `thread::init` not called.
+    NotInitialized,
+    NoFrame,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for SpawnError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        SpawnError::Map(err)
+    }
+}
+
+static SCHEDULER: IrqSpinlock<Scheduler> = IrqSpinlock::new(Scheduler::new());
+// scheduler current, readable without the scheduler lock (lock owner records)
+static CURRENT: AtomicU64 = AtomicU64::new(BOOT_THREAD.0);
+
+/// start scheduling: the caller become the boot thread, create the idle thread.
+///
+/// need heap and `memory::init_kernel_memory`.
+pub fn init() -> Result<(), SpawnError> {
+    let idle = new_thread(idle_main, 0, Priority::Low)?;
+    let mut scheduler = SCHEDULER.lock();
+    if !scheduler.is_initialized() {
+        scheduler.init(ThreadId::new(), idle);
+    }
+    Ok(())
+}
+
+// map a stack and build the frame `trap_common` resume: iretq -> entry(arg)
+fn new_thread(entry: extern "C" fn(usize) -> !, arg: usize, priority: Priority) -> Result<Thread, SpawnError> {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
+
+    let stack = Stack::new()?;
+    let top = stack.top().as_u64();
+    // entry see rsp = top - 8: 16 byte aligned after a call pushed a (zero) return address
+    let rsp = top - 8;
+    let context = (rsp - size_of::<TrapFrame>() as u64) & !0xf;
+    let frame = TrapFrame {
+        rip: entry as usize as u64,
+        cs: u64::from(CS::get_reg().0),
+        rflags: RFLAGS_INTERRUPT | 0x2, // bit 1 reserved, always set
+        rsp,
+        ss: u64::from(SS::get_reg().0),
+        rdi: arg as u64,
+        ..TrapFrame::default()
+    };
+    unsafe {
+        (rsp as *mut u64).write(0);
+        (context as *mut TrapFrame).write(frame);
+    }
+    Ok(Thread::new(context as usize, Some(stack), priority))
+}
+
+extern "C" fn idle_main(_: usize) -> ! {
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+// first code of every spawned thread: `main` is a `Box<Box<dyn FnOnce()>>` raw pointer
+extern "C" fn thread_start(main: usize) -> ! {
+    let main = unsafe { Box::from_raw(main as *mut Box<dyn FnOnce() + Send>) };
+    main();
+    exit()
+}
+
+/// spawn a `Priority::Normal` kernel thread running `f`, panic if its stack can't be mapped.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    try_spawn(f, Priority::Normal).expect("thread spawn failed")
+}
+
+/// spawn a kernel thread at `priority`, panic if its stack can't be mapped.
+pub fn spawn_with_priority<F, T>(priority: Priority, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    try_spawn(f, priority).expect("thread spawn failed")
+}
+
+/// spawn a kernel thread running `f`, a higher level than the caller run from the next tick.
+pub fn try_spawn<F, T>(f: F, priority: Priority) -> Result<JoinHandle<T>, SpawnError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    if !is_initialized() {
+        return Err(SpawnError::NotInitialized);
+    }
+    // stacks of detached threads are freed here: a dead thread can't free its own stack
+    let detached = SCHEDULER.lock().take_detached();
+    drop(detached);
+
+    let result = Arc::new(Mutex::new(None));
+    let packet = result.clone();
+    let main: Box<dyn FnOnce() + Send> = Box::new(move || {
+        let value = f();
+        *packet.lock() = Some(value);
+    });
+    let main = Box::into_raw(Box::new(main));
+    let thread = match new_thread(thread_start, main as usize, priority) {
+        Ok(thread) => thread,
+        Err(err) => {
+            drop(unsafe { Box::from_raw(main) });
+            return Err(err);
+        }
+    };
+
+    let id = ThreadId::new();
+    SCHEDULER.lock().add(id, thread);
+    Ok(JoinHandle { id, result })
+}
+
+/// whether `init` was called: threads can block and switch.
+pub fn is_initialized() -> bool {
+    SCHEDULER.lock().is_initialized()
+}
+
+/// id of the running thread, the idle loop id on an application processor (`smp`).
+pub fn current_id() -> ThreadId {
+    match smp::current_ap() {
+        Some(cpu) => cpu.idle_thread(),
+        None => ThreadId(CURRENT.load(Ordering::Relaxed)),
+    }
+}
+
+/// id never used by a scheduler thread: the idle loop of an application processor.
+pub(crate) fn reserve_id() -> ThreadId {
+    ThreadId::new()
+}
+
+/// run the current thread in `space` (None: kernel page table only), loaded again after each switch.
+///
+/// the thread own the space until replaced or the thread is removed. need `init`.
+pub fn set_address_space(space: Option<Arc<AddressSpace>>) {
+    let old = SCHEDULER.lock().set_address_space(space);
+    // last owner: free the user page tables outside the scheduler lock
+    drop(old);
+}
+
+/// user address space of the running thread.
+pub fn address_space() -> Option<Arc<AddressSpace>> {
+    SCHEDULER.lock().address_space()
+}
+
+/// change the priority of the running thread.
+pub fn set_priority(priority: Priority) {
+    let mut scheduler = SCHEDULER.lock();
+    let current = scheduler.current();
+    scheduler.set_priority(current, priority);
+}
+
+/// give the CPU to the next ready thread, the caller go to the back of its run queue.
+///
+/// called with interrupts disabled: resumed with interrupts still disabled.
+pub fn yield_now() {
+    unsafe { core::arch::asm!("int {vector}", vector = const YIELD_VECTOR) };
+}
+
+/// end the current thread, wake its joiner. the boot thread can't exit.
+///
+/// values on the thread stack are not dropped.
+pub fn exit() -> ! {
+    interrupts::disable();
+    SCHEDULER.lock().exit_current();
+    // `int` ignore IF: switch away and never come back
+    yield_now();
+    unreachable!("dead thread resumed");
+}
+
+/// block the running thread for at least `duration` (tick resolution).
+///
+/// before `init` (no scheduler): `hlt` like `time::sleep`.
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+/// block the running thread until the tick counter reach `deadline`.
+pub fn sleep_until(deadline: Instant) {
+    if !is_initialized() {
+        return time::sleep_until(deadline);
+    }
+    if Instant::now() >= deadline {
+        return;
+    }
+    // block and switch without a gap: the wake tick can't come before the thread is switched out
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    SCHEDULER.lock().sleep_current(deadline.ticks());
+    yield_now();
+    if enabled {
+        interrupts::enable();
+    }
+}
+
+/// block the running thread until `wake` it, called with interrupts disabled and the
+/// scheduler unlocked, after the thread is registered with its waker.
+pub(crate) fn block_current() {
+    SCHEDULER.lock().block_current();
+    yield_now();
+}
+
+/// ready a thread blocked by `block_current`, false if it was not blocked. interrupt safe.
+pub(crate) fn wake(id: ThreadId) -> bool {
+    SCHEDULER.lock().wake(id)
+}
+
+/// number of switches to a different thread since `init`.
+pub fn context_switches() -> u64 {
+    SCHEDULER.lock().context_switches()
+}
+
+/// statistics of thread `id`, None if removed.
+pub fn stats_of(id: ThreadId) -> Option<ThreadStats> {
+    SCHEDULER.lock().stats_of(id)
+}
+
+/// statistics of every thread, idle included.
+pub fn stats() -> Vec<ThreadStats> {
+    SCHEDULER.lock().stats()
+}
+
+/// called by the timer trap (interrupts disabled): return the frame to resume.
+pub(crate) fn on_tick(frame: &mut TrapFrame) -> *mut TrapFrame {
+    let mut scheduler = SCHEDULER.lock();
+    let next = scheduler.tick(time::ticks(), frame as *mut TrapFrame as usize);
+    CURRENT.store(scheduler.current().0, Ordering::Relaxed);
+    next as *mut TrapFrame
+}
+
+/// called by the yield trap (interrupts disabled): return the frame to resume.
+pub(crate) fn switch(frame: &mut TrapFrame) -> *mut TrapFrame {
+    let mut scheduler = SCHEDULER.lock();
+    let next = scheduler.switch(frame as *mut TrapFrame as usize);
+    CURRENT.store(scheduler.current().0, Ordering::Relaxed);
+    next as *mut TrapFrame
+}
+
+/// define an owned permission to join a thread, drop it detach the thread.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// whether the thread has ended.
+    pub fn is_finished(&self) -> bool {
+        SCHEDULER.lock().is_dead(self.id)
+    }
+
+    /// block until the thread end, free its stack and return its result (None: ended by `exit`).
+    pub fn join(self) -> Option<T> {
+        while !SCHEDULER.lock().join_or_block(self.id) {
+            yield_now();
+        }
+        // free the stack after unlock
+        let thread = SCHEDULER.lock().remove(self.id);
+        drop(thread);
+        self.result.lock().take()
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        // joined: already removed, detach do nothing
+        let thread = SCHEDULER.lock().detach(self.id);
+        drop(thread);
+    }
+}
@@ -0,0 +1,338 @@
+//! this module impl the priority thread scheduler.
+//! `
+//!     - threads: ThreadId -> Thread, ready: one run queue per priority, round-robin inside a level
+//!     - switch: save the trap frame of the current thread, resume the first thread of the highest
+//!       non-empty level (idle if none)
+//!     - tick: wake expired sleepers, switch when the time slice is used up or a higher level is ready
+//!     - sleepers: (wake tick, id) sorted by wake tick, latest first -> pop the expired from the end
+//!     - stats: context switches, runtime per thread
+//!     - switch in: TSS ring 0 stack = the thread stack top, CR3 = the thread page table (user threads)
+//! `
+//! always locked with interrupts disabled: the timer interrupt switch under the same lock,
+//! and the interrupt path never allocate (queue capacity reserved by `add`).
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+use super::stack::Stack;
+use super::{Priority, ThreadId, ThreadState, ThreadStats, BOOT_THREAD, PRIORITY_LEVELS, TIME_SLICE};
+use crate::user::AddressSpace;
+use crate::{gdt, memory, time};
+
+pub(super) struct Thread {
+    pub state: ThreadState,
+    pub priority: Priority,
+    // saved `*mut TrapFrame`, valid while not running
+    pub context: usize,
+    // None: boot thread, run on the bootloader stack. unmapped when the thread is dropped
+    stack: Option<Stack>,
+    // None: kernel page table; Some: user address space loaded while running, freed with the thread
+    address_space: Option<Arc<AddressSpace>>,
+    pub joiner: Option<ThreadId>,
+    pub detached: bool,
+    // times switched in, nanoseconds on CPU before the last switch in
+    switches: u64,
+    runtime_ns: u64,
+}
+
+impl Thread {
+    pub fn new(context: usize, stack: Option<Stack>, priority: Priority) -> Self {
+        Thread {
+            state: ThreadState::Ready,
+            priority,
+            context,
+            stack,
+            address_space: None,
+            joiner: None,
+            detached: false,
+            switches: 0,
+            runtime_ns: 0,
+        }
+    }
+
+    /// ring 0 stack of interrupts from ring 3 while this thread run.
+    pub fn kernel_stack(&self) -> VirtAddr {
+        self.stack.as_ref().map_or_else(gdt::default_kernel_stack, Stack::top)
+    }
+
+    fn page_table(&self) -> Option<PhysFrame> {
+        self.address_space.as_ref().map(|space| space.page_table())
+    }
+}
+
+pub(super) struct Scheduler {
+    threads: BTreeMap<ThreadId, Thread>,
+    ready: [VecDeque<ThreadId>; PRIORITY_LEVELS],
+    sleepers: Vec<(u64, ThreadId)>,
+    current: ThreadId,
+    // None: not initialized, never switch
+    idle: Option<ThreadId>,
+    slice: u64,
+    switches: u64,
+    // monotonic_ns when the current thread was switched in
+    switched_in_at: u64,
+}
+
+impl Scheduler {
+    pub const fn new() -> Self {
+        Scheduler {
+            threads: BTreeMap::new(),
+            ready: [const { VecDeque::new() }; PRIORITY_LEVELS],
+            sleepers: Vec::new(),
+            current: BOOT_THREAD,
+            idle: None,
+            slice: TIME_SLICE,
+            switches: 0,
+            switched_in_at: 0,
+        }
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.idle.is_some()
+    }
+
+    /// register the boot flow as the running thread and the idle thread (never queued).
+    pub fn init(&mut self, idle_id: ThreadId, idle: Thread) {
+        let mut boot = Thread::new(0, None, Priority::Normal);
+        boot.state = ThreadState::Running;
+        boot.switches = 1;
+        self.threads.insert(BOOT_THREAD, boot);
+        self.threads.insert(idle_id, idle);
+        self.idle = Some(idle_id);
+        self.switched_in_at = time::monotonic_ns();
+    }
+
+    pub fn current(&self) -> ThreadId {
+        self.current
+    }
+
+    /// whether `id` ended (or already removed).
+    pub fn is_dead(&self, id: ThreadId) -> bool {
+        self.threads.get(&id).is_none_or(|thread| thread.state == ThreadState::Dead)
+    }
+
+    /// add a new ready thread at the back of its run queue.
+    pub fn add(&mut self, id: ThreadId, thread: Thread) {
+        let priority = thread.priority;
+        self.threads.insert(id, thread);
+        // every thread can be queued / sleep at once: push in interrupt never grow a queue
+        let count = self.threads.len();
+        for queue in self.ready.iter_mut() {
+            queue.reserve(count.saturating_sub(queue.len()));
+        }
+        self.sleepers.reserve(count.saturating_sub(self.sleepers.len()));
+        self.ready[priority as usize].push_back(id);
+    }
+
+    /// change the priority of `id`, a queued thread move to the back of the new level.
+    pub fn set_priority(&mut self, id: ThreadId, priority: Priority) {
+        let Some(thread) = self.threads.get_mut(&id) else {
+            return;
+        };
+        let old = core::mem::replace(&mut thread.priority, priority);
+        if thread.state == ThreadState::Ready && old != priority {
+            self.ready[old as usize].retain(|&queued| queued != id);
+            self.ready[priority as usize].push_back(id);
+        }
+    }
+
+    /// blocked thread -> back of its run queue, false if `id` was not blocked.
+    pub fn wake(&mut self, id: ThreadId) -> bool {
+        match self.threads.get_mut(&id) {
+            Some(thread) if thread.state == ThreadState::Blocked => {
+                thread.state = ThreadState::Ready;
+                self.ready[thread.priority as usize].push_back(id);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// block the current thread, the caller must switch away (`yield_now`).
+    pub fn block_current(&mut self) {
+        assert!(Some(self.current) != self.idle, "idle thread can't block");
+        self.threads.get_mut(&self.current).expect("current thread missing").state = ThreadState::Blocked;
+    }
+
+    /// block the current thread until tick `wake_at`.
+    pub fn sleep_current(&mut self, wake_at: u64) {
+        self.block_current();
+        // latest first: same tick -> after the earlier sleepers, woken later
+        let index = self.sleepers.partition_point(|&(tick, _)| tick > wake_at);
+        self.sleepers.insert(index, (wake_at, self.current));
+    }
+
+    /// mark the current thread dead and wake its joiner, the caller must switch away.
+    pub fn exit_current(&mut self) {
+        assert!(self.current != BOOT_THREAD && Some(self.current) != self.idle, "boot / idle thread can't exit");
+        let thread = self.threads.get_mut(&self.current).expect("current thread missing");
+        thread.state = ThreadState::Dead;
+        if let Some(joiner) = thread.joiner.take() {
+            self.wake(joiner);
+        }
+    }
+
+    /// true if `target` is dead, else block the current thread until it exit.
+    pub fn join_or_block(&mut self, target: ThreadId) -> bool {
+        assert!(target != self.current, "thread can't join itself");
+        let current = self.current;
+        let thread = self.threads.get_mut(&target).expect("joined thread missing");
+        if thread.state == ThreadState::Dead {
+            return true;
+        }
+        thread.joiner = Some(current);
+        self.block_current();
+        false
+    }
+
+    /// remove a dead thread, drop it (unmap the stack) after unlock.
+    pub fn remove(&mut self, id: ThreadId) -> Option<Thread> {
+        match self.threads.get(&id) {
+            Some(thread) if thread.state == ThreadState::Dead => self.threads.remove(&id),
+            _ => None,
+        }
+    }
+
+    /// nobody will join `id`: removed at once if already dead, else by `take_detached`.
+    pub fn detach(&mut self, id: ThreadId) -> Option<Thread> {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            thread.detached = true;
+        }
+        self.remove(id)
+    }
+
+    /// remove every dead detached thread.
+    pub fn take_detached(&mut self) -> Vec<Thread> {
+        let dead: Vec<ThreadId> = self.threads.iter()
+            .filter(|(_, thread)| thread.detached && thread.state == ThreadState::Dead)
+            .map(|(&id, _)| id)
+            .collect();
+        dead.into_iter().filter_map(|id| self.threads.remove(&id)).collect()
+    }
+
+    // highest level with a ready thread
+    fn highest_ready(&self) -> Option<Priority> {
+        Priority::ALL.into_iter().rev().find(|&priority| !self.ready[priority as usize].is_empty())
+    }
+
+    /// timer tick: wake sleepers whose tick came, then switch when the time slice is used up,
+    /// a higher level is ready, or idle and a thread is ready.
+    pub fn tick(&mut self, now: u64, frame: usize) -> usize {
+        while let Some(&(wake_at, id)) = self.sleepers.last() {
+            if wake_at > now {
+                break;
+            }
+            self.sleepers.pop();
+            self.wake(id);
+        }
+
+        self.slice = self.slice.saturating_sub(1);
+        let preempt = match self.highest_ready() {
+            None => false,
+            Some(_) if Some(self.current) == self.idle => true,
+            ready => ready > self.threads.get(&self.current).map(|thread| thread.priority),
+        };
+        if self.slice == 0 || preempt {
+            self.switch(frame)
+        } else {
+            frame
+        }
+    }
+
+    /// save `frame` as the current thread context, return the context of the next thread.
+    pub fn switch(&mut self, frame: usize) -> usize {
+        let Some(idle) = self.idle else {
+            return frame;
+        };
+        let now = time::monotonic_ns();
+        let current = self.threads.get_mut(&self.current).expect("current thread missing");
+        current.context = frame;
+        current.runtime_ns += now.saturating_sub(self.switched_in_at);
+        // blocked / dead: out of the run queue; ready: woken before switch, already queued
+        if current.state == ThreadState::Running {
+            current.state = ThreadState::Ready;
+            if self.current != idle {
+                self.ready[current.priority as usize].push_back(self.current);
+            }
+        }
+
+        let next = self.pop_ready().unwrap_or(idle);
+        if next != self.current {
+            self.switches += 1;
+        }
+        let thread = self.threads.get_mut(&next).expect("next thread missing");
+        thread.state = ThreadState::Running;
+        thread.switches += 1;
+        self.current = next;
+        self.slice = TIME_SLICE;
+        self.switched_in_at = now;
+        gdt::set_kernel_stack(thread.kernel_stack());
+        activate_page_table(thread.page_table());
+        thread.context
+    }
+
+    /// change the address space of the current thread and load it, return the old one (drop after unlock).
+    pub fn set_address_space(&mut self, space: Option<Arc<AddressSpace>>) -> Option<Arc<AddressSpace>> {
+        let current = self.threads.get_mut(&self.current).expect("current thread missing");
+        let old = core::mem::replace(&mut current.address_space, space);
+        activate_page_table(current.page_table());
+        old
+    }
+
+    pub fn address_space(&self) -> Option<Arc<AddressSpace>> {
+        self.threads.get(&self.current)?.address_space.clone()
+    }
+
+    // first ready thread of the highest level, skip stale entries
+    fn pop_ready(&mut self) -> Option<ThreadId> {
+        for priority in Priority::ALL.into_iter().rev() {
+            while let Some(id) = self.ready[priority as usize].pop_front() {
+                if self.threads.get(&id).is_some_and(|thread| thread.state == ThreadState::Ready) {
+                    return Some(id);
+                }
+            }
+        }
+        None
+    }
+
+    /// number of switches to a different thread.
+    pub fn context_switches(&self) -> u64 {
+        self.switches
+    }
+
+    pub fn stats_of(&self, id: ThreadId) -> Option<ThreadStats> {
+        let thread = self.threads.get(&id)?;
+        let mut runtime_ns = thread.runtime_ns;
+        if id == self.current {
+            runtime_ns += time::monotonic_ns().saturating_sub(self.switched_in_at);
+        }
+        Some(ThreadStats {
+            id,
+            priority: thread.priority,
+            state: thread.state,
+            idle: Some(id) == self.idle,
+            switches: thread.switches,
+            runtime: time::Duration::from_nanos(runtime_ns),
+        })
+    }
+
+    pub fn stats(&self) -> Vec<ThreadStats> {
+        self.threads.keys().filter_map(|&id| self.stats_of(id)).collect()
+    }
+}
+
+// load `page_table` (None: kernel page table) if not already active
+fn activate_page_table(page_table: Option<PhysFrame>) {
+    let Some(frame) = page_table.or_else(memory::kernel_page_table) else {
+        return;
+    };
+    let (active, flags) = Cr3::read();
+    if active != frame {
+        unsafe { Cr3::write(frame, flags) };
+    }
+}
@@ -0,0 +1,118 @@
+//! this module impl kernel thread stacks.
+//! `
+//!     - stack region: slot i at STACK_START + i * SLOT_SIZE, one slot per live thread
+//!     - slot: guard page(never mapped) | STACK_PAGES mapped pages, stack grow down to the guard
+//!     - drop: unmap the pages, frames back to the frame allocator, slot reused by the next spawn
+//! `
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sync::Spinlock;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use super::SpawnError;
+use crate::memory::{self, KernelMemory};
+
+/// kernel thread stack region start.
+pub const STACK_START: u64 = 0x_6666_0000_0000;
+/// mapped pages per stack (64 KiB).
+pub const STACK_PAGES: u64 = 16;
+
+const PAGE_SIZE: u64 = 4096;
+const SLOT_SIZE: u64 = (STACK_PAGES + 1) * PAGE_SIZE;
+// enough slots for any heap: 256 GiB of address space
+const MAX_SLOTS: u64 = 1 << 22;
+
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0);
+// slots of dropped stacks, pushed / popped under the kernel memory lock
+static FREE_SLOTS: Spinlock<Vec<u64>> = Spinlock::new(Vec::new());
+
+/// define one mapped kernel stack with a guard page below it.
+#[derive(Debug)]
+pub struct Stack {
+    slot: u64,
+}
+
+impl Stack {
+    /// map a stack in a free slot.
+    pub fn new() -> Result<Stack, SpawnError> {
+        let (stack, mapped) = memory::with_kernel_memory(|memory| {
+            let slot = FREE_SLOTS.lock().pop().unwrap_or_else(|| NEXT_SLOT.fetch_add(1, Ordering::Relaxed));
+            assert!(slot < MAX_SLOTS, "kernel stack region exhausted");
+            let stack = Stack { slot };
+            let mapped = stack.map(memory);
+            (stack, mapped)
+        })
+        .ok_or(SpawnError::NoKernelMemory)?;
+        // map failed: the partial stack is dropped here, outside the lock
+        mapped.map(|_| stack)
+    }
+
+    fn map(&self, memory: &mut KernelMemory) -> Result<(), SpawnError> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        for page in self.pages() {
+            let frame = memory.frame_allocator.allocate_frame().ok_or(SpawnError::NoFrame)?;
+            unsafe {
+                memory.mapper.map_to(page, frame, flags, &mut memory.frame_allocator)?.flush();
+            }
+        }
+        Ok(())
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let bottom = Page::containing_address(self.bottom());
+        Page::range(bottom, bottom + STACK_PAGES)
+    }
+
+    /// lowest address of the guard page.
+    pub fn guard(&self) -> VirtAddr {
+        VirtAddr::new(STACK_START + self.slot * SLOT_SIZE)
+    }
+
+    /// lowest usable address.
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard() + PAGE_SIZE
+    }
+
+    /// end of the stack: initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        self.guard() + SLOT_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        memory::with_kernel_memory(|memory| {
+            for page in self.pages() {
+                // map may have failed halfway: skip pages not mapped
+                if let Ok((frame, flush)) = memory.mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { memory.frame_allocator.deallocate_frame(frame) };
+                }
+            }
+            FREE_SLOTS.lock().push(self.slot);
+        });
+    }
+}
+
+/// whether `addr` hit the guard page of a thread stack -> that stack overflowed.
+pub fn in_guard_page(addr: VirtAddr) -> bool {
+    let addr = addr.as_u64();
+    (STACK_START..STACK_START + MAX_SLOTS * SLOT_SIZE).contains(&addr) && (addr - STACK_START) % SLOT_SIZE < PAGE_SIZE
+}
+
+
+#[test_case]
+fn test_stack_layout() {
+    let stack = core::mem::ManuallyDrop::new(Stack { slot: 2 });
+    assert_eq!(stack.guard().as_u64(), STACK_START + 2 * SLOT_SIZE);
+    assert_eq!(stack.top() - stack.bottom(), STACK_PAGES * PAGE_SIZE);
+    assert!(in_guard_page(stack.guard() + 8u64));
+    assert!(!in_guard_page(stack.bottom()));
+    assert!(!in_guard_page(stack.top() - 8u64));
+    assert!(in_guard_page(stack.top())); // next slot guard
+}
@@ -0,0 +1,202 @@
+//! this module impl wait queues: threads blocked until an event.
+//! `
+//!     - wait_until(condition): check with interrupts disabled, else enqueue + block, check again when woken
+//!     - wait_after(release): enqueue, release (e.g. a mutex), block -> condition variable
+//!     - wake_one / wake_all: pop waiters -> ready queue, callable from interrupt handlers
+//! `
+//! the condition is checked and the thread blocked without a gap: a wake from an interrupt
+//! handler between them would be lost. waiters are linked through nodes on their own stack:
+//! enqueue never allocate with interrupts disabled.
+
+use core::ptr;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::ThreadId;
+
+// one blocked thread, on its stack while it wait
+struct Waiter {
+    id: ThreadId,
+    next: *mut Waiter,
+    queued: bool,
+}
+
+impl Waiter {
+    fn new(id: ThreadId) -> Self {
+        Waiter { id, next: ptr::null_mut(), queued: false }
+    }
+}
+
+// intrusive FIFO of `Waiter`s
+struct WaitList {
+    head: *mut Waiter,
+    tail: *mut Waiter,
+    len: usize,
+}
+
+// the nodes are only touched under the queue lock
+unsafe impl Send for WaitList {}
+
+impl WaitList {
+    const fn new() -> Self {
+        WaitList { head: ptr::null_mut(), tail: ptr::null_mut(), len: 0 }
+    }
+
+    // `waiter` must stay in place until popped or removed
+    fn push_back(&mut self, waiter: &mut Waiter) {
+        waiter.next = ptr::null_mut();
+        waiter.queued = true;
+        let waiter: *mut Waiter = waiter;
+        match unsafe { self.tail.as_mut() } {
+            Some(tail) => tail.next = waiter,
+            None => self.head = waiter,
+        }
+        self.tail = waiter;
+        self.len += 1;
+    }
+
+    fn pop_front(&mut self) -> Option<ThreadId> {
+        let waiter = unsafe { self.head.as_mut()? };
+        self.head = waiter.next;
+        if self.head.is_null() {
+            self.tail = ptr::null_mut();
+        }
+        waiter.queued = false;
+        self.len -= 1;
+        Some(waiter.id)
+    }
+
+    // unlink `waiter` if still queued: woken by something else than this queue
+    fn remove(&mut self, waiter: &mut Waiter) {
+        if !waiter.queued {
+            return;
+        }
+        let target: *mut Waiter = waiter;
+        let mut previous: *mut Waiter = ptr::null_mut();
+        let mut current = self.head;
+        while !current.is_null() && current != target {
+            previous = current;
+            current = unsafe { (*current).next };
+        }
+        if current.is_null() {
+            return;
+        }
+        match unsafe { previous.as_mut() } {
+            Some(previous) => previous.next = waiter.next,
+            None => self.head = waiter.next,
+        }
+        if self.tail == target {
+            self.tail = previous;
+        }
+        waiter.queued = false;
+        self.len -= 1;
+    }
+}
+
+/// define a FIFO of threads waiting for one event.
+pub struct WaitQueue {
+    // only locked with interrupts disabled: interrupt handlers wake
+    waiters: Mutex<WaitList>,
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiters: Mutex::new(WaitList::new()) }
+    }
+
+    /// block the running thread until woken once.
+    pub fn wait(&self) {
+        let mut woken = false;
+        self.wait_until(|| core::mem::replace(&mut woken, true));
+    }
+
+    /// block the running thread until `condition` is true, checked before each block and after each wake.
+    ///
+    /// before `thread::init`: `hlt` between checks.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        let enabled = interrupts::are_enabled();
+        loop {
+            interrupts::disable();
+            if condition() {
+                break;
+            }
+            if super::is_initialized() {
+                let mut waiter = Waiter::new(super::current_id());
+                self.waiters.lock().push_back(&mut waiter);
+                super::block_current();
+                self.waiters.lock().remove(&mut waiter);
+            } else {
+                assert!(enabled, "wait with interrupts disabled never wake up");
+                interrupts::enable_and_hlt();
+            }
+        }
+        if enabled {
+            interrupts::enable();
+        }
+    }
+
+    /// enqueue the running thread, call `release`, then block until woken once.
+    ///
+    /// a wake after `release` is not lost (condition variable). before `thread::init`: `hlt` once.
+    pub fn wait_after(&self, release: impl FnOnce()) {
+        let enabled = interrupts::are_enabled();
+        interrupts::disable();
+        if super::is_initialized() {
+            let mut waiter = Waiter::new(super::current_id());
+            self.waiters.lock().push_back(&mut waiter);
+            release();
+            super::block_current();
+            self.waiters.lock().remove(&mut waiter);
+        } else {
+            release();
+            assert!(enabled, "wait with interrupts disabled never wake up");
+            interrupts::enable_and_hlt();
+        }
+        if enabled {
+            interrupts::enable();
+        }
+    }
+
+    /// wake the longest waiting thread, false if none. interrupt safe.
+    pub fn wake_one(&self) -> bool {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            while let Some(id) = waiters.pop_front() {
+                if super::wake(id) {
+                    return true;
+                }
+            }
+            false
+        })
+    }
+
+    /// wake every waiting thread, return the number woken. interrupt safe.
+    pub fn wake_all(&self) -> usize {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            let mut woken = 0;
+            while let Some(id) = waiters.pop_front() {
+                if super::wake(id) {
+                    woken += 1;
+                }
+            }
+            woken
+        })
+    }
+
+    /// number of threads waiting.
+    pub fn len(&self) -> usize {
+        interrupts::without_interrupts(|| self.waiters.lock().len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
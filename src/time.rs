@@ -0,0 +1,253 @@
+//! this module impl kros time keeping.
+//! `
+//!     - pit: programmable interval timer(IRQ0) at `DEFAULT_FREQUENCY` Hz
+//!     - TICKS: monotonic counter, increment by the timer interrupt
+//!     - Instant: a point in time in ticks, Duration: core::time::Duration
+//!     - sleep: hlt until the deadline tick
+//!     - rtc: CMOS real-time clock, read once at boot -> wall-clock `now`
+//!     - tsc: invariant TSC calibrated at boot -> `monotonic_ns`, else HPET, else tick counter
+//!     - hpet: main counter clocksource, timer 0 can replace the PIT as tick source
+//! `
+
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+
+pub use core::time::Duration;
+pub use rtc::DateTime;
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+
+use hpet::HpetError;
+
+/// default timer interrupt rate(Hz).
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// PIT input clocks per tick
+static PIT_DIVISOR: AtomicU32 = AtomicU32::new(65536);
+// tick period(femtoseconds) of the tick source: PIT, or HPET timer 0
+static TICK_PERIOD_FS: AtomicU64 = AtomicU64::new(pit_period_fs(65536));
+// wall-clock epoch: RTC unix timestamp read at tick BOOT_TICKS
+static BOOT_EPOCH: AtomicU64 = AtomicU64::new(0);
+static BOOT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// program the PIT to the default rate, read the boot time from RTC and calibrate the TSC,
+/// called by `crate::init`.
+pub fn init() {
+    set_frequency(DEFAULT_FREQUENCY);
+    BOOT_TICKS.store(ticks(), Ordering::Relaxed);
+    BOOT_EPOCH.store(rtc::read().unix_timestamp(), Ordering::Relaxed);
+
+    // not invariant: rate change with P-states / stop in C-states -> keep the tick counter
+    if tsc::is_invariant() {
+        if let Some(hz) = tsc::calibrate() {
+            tsc::enable(hz, ticks_to_nanos(ticks()));
+        }
+    }
+}
+
+/// map the HPET and use its main counter as clocksource, recalibrate the TSC against it.
+///
+/// need page table: called after memory init, the PIT stay the tick source.
+pub fn init_hpet(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), HpetError> {
+    hpet::init(mapper, frame_allocator)?;
+    hpet::enable_clocksource(monotonic_ns())?;
+    if tsc::frequency().is_some() {
+        if let Some(hz) = tsc::calibrate() {
+            tsc::enable(hz, monotonic_ns());
+        }
+    }
+    Ok(())
+}
+
+/// define where `monotonic_ns` read the time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clocksource {
+    Tsc,
+    Hpet,
+    Pit,
+}
+
+pub fn clocksource() -> Clocksource {
+    if tsc::frequency().is_some() {
+        Clocksource::Tsc
+    } else if hpet::nanos().is_some() {
+        Clocksource::Hpet
+    } else {
+        Clocksource::Pit
+    }
+}
+
+/// nanoseconds since boot: TSC resolution, HPET when the TSC is unreliable, else tick resolution.
+pub fn monotonic_ns() -> u64 {
+    tsc::nanos()
+        .or_else(hpet::nanos)
+        .unwrap_or_else(|| ticks_to_nanos(ticks()))
+}
+
+/// RTC date and time at `init`.
+pub fn boot_time() -> DateTime {
+    DateTime::from_unix_timestamp(BOOT_EPOCH.load(Ordering::Relaxed))
+}
+
+/// current date and time: boot epoch + ticks since, no CMOS access.
+pub fn now() -> DateTime {
+    let since_boot = Instant::now().duration_since(Instant { ticks: BOOT_TICKS.load(Ordering::Relaxed) });
+    DateTime::from_unix_timestamp(BOOT_EPOCH.load(Ordering::Relaxed) + since_boot.as_secs())
+}
+
+const fn pit_period_fs(divisor: u32) -> u64 {
+    (divisor as u128 * FEMTOS_PER_SEC as u128 / pit::BASE_FREQUENCY as u128) as u64
+}
+
+/// reprogram the PIT to the nearest rate of `hz`.
+///
+/// Instants taken before the change are measured with the new period.
+pub fn set_frequency(hz: u32) {
+    let divisor = pit::divisor_for(hz);
+    without_interrupts(|| {
+        PIT_DIVISOR.store(divisor, Ordering::SeqCst);
+        unsafe { pit::set_divisor(divisor) };
+        if !hpet::is_legacy() {
+            TICK_PERIOD_FS.store(pit_period_fs(divisor), Ordering::SeqCst);
+        }
+    });
+}
+
+/// use HPET timer 0 as tick source at `hz` instead of the PIT.
+pub fn use_hpet_ticks(hz: u32) -> Result<(), HpetError> {
+    without_interrupts(|| {
+        let period = hpet::start_periodic(hz)?;
+        TICK_PERIOD_FS.store(period, Ordering::SeqCst);
+        Ok(())
+    })
+}
+
+/// give the tick source back to the PIT.
+pub fn use_pit_ticks() -> Result<(), HpetError> {
+    without_interrupts(|| {
+        hpet::stop_periodic()?;
+        TICK_PERIOD_FS.store(pit_period_fs(PIT_DIVISOR.load(Ordering::SeqCst)), Ordering::SeqCst);
+        Ok(())
+    })
+}
+
+/// actual timer interrupt rate(Hz), rounded down.
+pub fn frequency() -> u32 {
+    (FEMTOS_PER_SEC / TICK_PERIOD_FS.load(Ordering::Relaxed)) as u32
+}
+
+/// ticks since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// called by the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+// tick count <-> nanoseconds with the current tick period
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    let nanos = u128::from(ticks) * u128::from(TICK_PERIOD_FS.load(Ordering::Relaxed)) / u128::from(FEMTOS_PER_NANO);
+    nanos.min(u128::from(u64::MAX)) as u64
+}
+
+// round up: sleep at least `duration`
+pub(crate) fn duration_to_ticks(duration: Duration) -> u64 {
+    let femtos = duration.as_nanos() * u128::from(FEMTOS_PER_NANO);
+    let ticks = femtos.div_ceil(u128::from(TICK_PERIOD_FS.load(Ordering::Relaxed)));
+    ticks.min(u128::from(u64::MAX)) as u64
+}
+
+/// define a point of the monotonic tick counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant { ticks: ticks() }
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    /// time from `earlier` to self, zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(ticks_to_nanos(self.ticks.saturating_sub(earlier.ticks)))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.ticks.checked_add(duration_to_ticks(duration)).map(|ticks| Instant { ticks })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// halt until `duration` elapsed(tick resolution), need interrupts enabled.
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration);
+}
+
+/// halt until the tick counter reach `deadline`, need interrupts enabled.
+pub fn sleep_until(deadline: Instant) {
+    use x86_64::instructions::interrupts;
+
+    assert!(interrupts::are_enabled(), "sleep with interrupts disabled never wake up");
+    loop {
+        // check and hlt without a gap: a timer interrupt between them would oversleep one tick
+        interrupts::disable();
+        if Instant::now() >= deadline {
+            interrupts::enable();
+            return;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+
+#[test_case]
+fn test_tick_conversion() {
+    // 1000 Hz -> divisor 1193, tick ~ 0.99985ms
+    let divisor = pit::divisor_for(1000);
+    assert_eq!(divisor, 1193);
+    assert_eq!(pit::divisor_for(1), 65536);
+    assert_eq!(PIT_DIVISOR.load(Ordering::Relaxed), divisor);
+    assert_eq!(frequency(), 1000);
+    assert_eq!(duration_to_ticks(Duration::ZERO), 0);
+    assert_eq!(duration_to_ticks(Duration::from_millis(10)), 11);
+    assert_eq!(ticks_to_nanos(1000), 999_847_466);
+}
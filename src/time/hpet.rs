@@ -0,0 +1,288 @@
+//! this module impl the high precision event timer(HPET) driver.
+//! `
+//!     - found by the ACPI "HPET" table, registers mapped uncached by `memory::map_mmio`
+//!     - main counter: 64 bit up counter, period in femtoseconds -> clocksource
+//!     - legacy replacement route: timer 0 -> IRQ0 (replace the PIT), timer 1 -> IRQ8 (replace the RTC)
+//!     - timer 0 periodic: kernel tick source, timer 1 one shot: single high precision deadline
+//! `
+//! no IO APIC: timers can only reach the 8259 through legacy replacement route.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::Duration;
+use crate::acpi::{self, AcpiError, SdtHeader};
+use crate::{interrupts, memory};
+
+const REG_CAPABILITIES: u64 = 0x000;
+const REG_CONFIG: u64 = 0x010;
+const REG_INTERRUPT_STATUS: u64 = 0x020;
+const REG_MAIN_COUNTER: u64 = 0x0F0;
+const REGISTERS_SIZE: u64 = 0x400;
+
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+
+// spec: counter period at most 100ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+// legacy replacement route: timer 1 -> IRQ8
+const ONE_SHOT_IRQ: u8 = 8;
+
+/// define why the HPET can't be used.
+#[derive(Debug)]
+pub enum HpetError {
+    Acpi(AcpiError),
+    /// registers not in system memory address space.
+    NotMemoryMapped,
+    Map(MapToError<Size4KiB>),
+    /// counter period 0 or longer than 100ns.
+    BadPeriod(u64),
+    /// 32 bit main counter, wrap in seconds.
+    Counter32Bit,
+    NotInitialized,
+    /// no legacy replacement route: can't reach the 8259.
+    NoLegacyRoute,
+    /// timer 0 can't run periodic.
+    NoPeriodicTimer,
+    /// rate 0 or faster than the counter.
+    BadFrequency(u32),
+}
+
+impl From<AcpiError> for HpetError {
+    fn from(err: AcpiError) -> Self {
+        HpetError::Acpi(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for HpetError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        HpetError::Map(err)
+    }
+}
+
+/// ACPI HPET description table.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct HpetTable {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    // generic address structure
+    address_space_id: u8,
+    register_bit_width: u8,
+    register_bit_offset: u8,
+    access_size: u8,
+    address: u64,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+// virtual address of the registers, 0: not initialized
+static BASE: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+static LEGACY: AtomicBool = AtomicBool::new(false);
+// clocksource: counter value at enable and the monotonic nanoseconds it represent
+static BASE_COUNTER: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+static CLOCKSOURCE: AtomicBool = AtomicBool::new(false);
+static ONE_SHOTS: AtomicU64 = AtomicU64::new(0);
+
+fn timer_config(timer: u64) -> u64 {
+    0x100 + 0x20 * timer
+}
+
+fn timer_comparator(timer: u64) -> u64 {
+    0x108 + 0x20 * timer
+}
+
+fn base() -> Result<VirtAddr, HpetError> {
+    match BASE.load(Ordering::Acquire) {
+        0 => Err(HpetError::NotInitialized),
+        base => Ok(VirtAddr::new(base)),
+    }
+}
+
+unsafe fn read(base: VirtAddr, register: u64) -> u64 {
+    (base + register).as_ptr::<u64>().read_volatile()
+}
+
+unsafe fn write(base: VirtAddr, register: u64, value: u64) {
+    (base + register).as_mut_ptr::<u64>().write_volatile(value)
+}
+
+/// find the HPET through ACPI, map its registers and start the main counter.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), HpetError> {
+    if BASE.load(Ordering::Acquire) != 0 {
+        return Ok(());
+    }
+    let (table_addr, _) = acpi::find_table(b"HPET")?;
+    let table: HpetTable = unsafe { acpi::read_phys(table_addr)? };
+    if table.address_space_id != 0 {
+        return Err(HpetError::NotMemoryMapped);
+    }
+    let base = memory::map_mmio(PhysAddr::new(table.address), REGISTERS_SIZE, mapper, frame_allocator)?;
+
+    let capabilities = unsafe { read(base, REG_CAPABILITIES) };
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        return Err(HpetError::BadPeriod(period));
+    }
+    if capabilities & CAP_COUNTER_64BIT == 0 {
+        return Err(HpetError::Counter32Bit);
+    }
+    unsafe {
+        let config = read(base, REG_CONFIG);
+        write(base, REG_CONFIG, (config & !CONFIG_LEGACY_ROUTE) | CONFIG_ENABLE);
+    }
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    BASE.store(base.as_u64(), Ordering::Release);
+    Ok(())
+}
+
+pub fn is_available() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+/// main counter period in femtoseconds.
+pub fn period_fs() -> Option<u64> {
+    base().ok().map(|_| PERIOD_FS.load(Ordering::Relaxed))
+}
+
+/// main counter rate(Hz).
+pub fn frequency() -> Option<u64> {
+    period_fs().map(|period| FEMTOS_PER_SEC / period)
+}
+
+/// read the main counter.
+pub fn counter() -> Option<u64> {
+    base().ok().map(|base| unsafe { read(base, REG_MAIN_COUNTER) })
+}
+
+/// use the main counter as clocksource, continuing from `base_nanos`.
+pub fn enable_clocksource(base_nanos: u64) -> Result<(), HpetError> {
+    let counter = counter().ok_or(HpetError::NotInitialized)?;
+    without_interrupts(|| {
+        BASE_NANOS.store(base_nanos, Ordering::SeqCst);
+        BASE_COUNTER.store(counter, Ordering::SeqCst);
+        CLOCKSOURCE.store(true, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+/// monotonic nanoseconds from the main counter, None if the HPET is not the clocksource.
+pub fn nanos() -> Option<u64> {
+    if !CLOCKSOURCE.load(Ordering::Relaxed) {
+        return None;
+    }
+    let counts = counter()?.wrapping_sub(BASE_COUNTER.load(Ordering::Relaxed));
+    let nanos = u128::from(counts) * u128::from(PERIOD_FS.load(Ordering::Relaxed)) / u128::from(FEMTOS_PER_NANO);
+    Some(BASE_NANOS.load(Ordering::Relaxed) + nanos as u64)
+}
+
+/// timer 0 and 1 route to IRQ0 / IRQ8 (legacy replacement).
+pub fn is_legacy() -> bool {
+    LEGACY.load(Ordering::Relaxed)
+}
+
+/// drive IRQ0 from timer 0 at `hz` instead of the PIT, return the tick period in femtoseconds.
+///
+/// also route timer 1 to IRQ8: the RTC periodic interrupt stop working.
+pub fn start_periodic(hz: u32) -> Result<u64, HpetError> {
+    let base = base()?;
+    let period = PERIOD_FS.load(Ordering::Relaxed);
+    let counts = match hz {
+        0 => 0,
+        hz => FEMTOS_PER_SEC / u64::from(hz) / period,
+    };
+    if counts == 0 {
+        return Err(HpetError::BadFrequency(hz));
+    }
+    unsafe {
+        if read(base, REG_CAPABILITIES) & CAP_LEGACY_ROUTE == 0 {
+            return Err(HpetError::NoLegacyRoute);
+        }
+        if read(base, timer_config(0)) & TIMER_PERIODIC_CAP == 0 {
+            return Err(HpetError::NoPeriodicTimer);
+        }
+    }
+
+    without_interrupts(|| unsafe {
+        let config = read(base, timer_config(0)) & !TIMER_32BIT_MODE;
+        write(base, timer_config(0), config | TIMER_INT_ENABLE | TIMER_PERIODIC | TIMER_VALUE_SET);
+        // VALUE_SET: first write the comparator, second the period accumulator
+        write(base, timer_comparator(0), read(base, REG_MAIN_COUNTER) + counts);
+        write(base, timer_comparator(0), counts);
+        let config = read(base, REG_CONFIG);
+        write(base, REG_CONFIG, config | CONFIG_LEGACY_ROUTE | CONFIG_ENABLE);
+        LEGACY.store(true, Ordering::SeqCst);
+    });
+    Ok(counts * period)
+}
+
+/// stop timer interrupts and give IRQ0 / IRQ8 back to the PIT / RTC.
+pub fn stop_periodic() -> Result<(), HpetError> {
+    let base = base()?;
+    without_interrupts(|| unsafe {
+        for timer in 0..2 {
+            let config = read(base, timer_config(timer));
+            write(base, timer_config(timer), config & !(TIMER_INT_ENABLE | TIMER_PERIODIC));
+        }
+        let config = read(base, REG_CONFIG);
+        write(base, REG_CONFIG, config & !CONFIG_LEGACY_ROUTE);
+        LEGACY.store(false, Ordering::SeqCst);
+    });
+    Ok(())
+}
+
+/// raise IRQ8 once after `delay` with timer 1, need legacy replacement route(`start_periodic`).
+pub fn one_shot(delay: Duration) -> Result<(), HpetError> {
+    let base = base()?;
+    if !is_legacy() {
+        return Err(HpetError::NoLegacyRoute);
+    }
+    let period = PERIOD_FS.load(Ordering::Relaxed);
+    let counts = (delay.as_nanos() * u128::from(FEMTOS_PER_NANO)).div_ceil(u128::from(period)).max(1) as u64;
+
+    without_interrupts(|| unsafe {
+        let config = read(base, timer_config(1)) & !(TIMER_PERIODIC | TIMER_32BIT_MODE);
+        write(base, timer_config(1), config | TIMER_INT_ENABLE);
+        write(base, timer_comparator(1), read(base, REG_MAIN_COUNTER) + counts);
+    });
+    interrupts::unmask_irq(ONE_SHOT_IRQ);
+    Ok(())
+}
+
+/// number of timer 1 one shot interrupts handled.
+pub fn one_shots() -> u64 {
+    ONE_SHOTS.load(Ordering::Relaxed)
+}
+
+/// called by the IRQ8 handler in legacy replacement mode.
+pub(crate) fn handle_one_shot() {
+    if let Ok(base) = base() {
+        unsafe {
+            // timer 1 status bit, write 1 to clear(level triggered)
+            write(base, REG_INTERRUPT_STATUS, 1 << 1);
+            let config = read(base, timer_config(1));
+            write(base, timer_config(1), config & !TIMER_INT_ENABLE);
+        }
+    }
+    ONE_SHOTS.fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,68 @@
+//! this module impl the 8253/8254 programmable interval timer(PIT).
+//! `
+//!     - input clock 1.193182 MHz / divisor -> IRQ0 rate
+//!     - channel 0: mode 2(rate generator), lobyte/hibyte access
+//!     - channel 2: mode 0(one shot) gated by port 0x61, polled -> TSC calibration
+//! `
+
+use x86_64::instructions::port::Port;
+
+/// PIT input clock(Hz).
+pub const BASE_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0_DATA: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+// channel 0(bit 7-6 = 00) | access lobyte/hibyte(11) | mode 2 rate generator(010) | binary(0)
+const CHANNEL_0_RATE_GENERATOR: u8 = 0x34;
+
+/// divisor for the nearest rate to `hz`, in the 16 bit range (0 means 65536).
+pub fn divisor_for(hz: u32) -> u32 {
+    let divisor = (BASE_FREQUENCY + u64::from(hz) / 2) / u64::from(hz.max(1));
+    divisor.clamp(1, 65536) as u32
+}
+
+/// program channel 0 to raise IRQ0 every `divisor` input clocks.
+///
+/// # Safety
+/// the caller must make sure no other code program the PIT at the same time.
+pub unsafe fn set_divisor(divisor: u32) {
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut data: Port<u8> = Port::new(CHANNEL_0_DATA);
+
+    let divisor = if divisor >= 65536 { 0 } else { divisor as u16 }; // 0 -> 65536
+    command.write(CHANNEL_0_RATE_GENERATOR);
+    data.write((divisor & 0xff) as u8);
+    data.write((divisor >> 8) as u8);
+}
+
+const CHANNEL_2_DATA: u16 = 0x42;
+// bit 0: channel 2 gate, bit 1: speaker enable, bit 5: channel 2 output
+const SPEAKER_CONTROL: u16 = 0x61;
+const CHANNEL_2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+// channel 2(10) | access lobyte/hibyte(11) | mode 0 interrupt on terminal count(000) | binary(0)
+const CHANNEL_2_ONE_SHOT: u8 = 0xB0;
+
+/// start channel 2 counting down `count` input clocks with the speaker off,
+/// its output go high at terminal count (see `channel_2_done`), no interrupt.
+///
+/// # Safety
+/// the caller must make sure no other code use channel 2 / the speaker at the same time.
+pub unsafe fn start_channel_2(count: u16) {
+    let mut control: Port<u8> = Port::new(SPEAKER_CONTROL);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut data: Port<u8> = Port::new(CHANNEL_2_DATA);
+
+    let value = control.read();
+    control.write((value & !SPEAKER_ENABLE) | CHANNEL_2_GATE);
+    command.write(CHANNEL_2_ONE_SHOT);
+    data.write((count & 0xff) as u8);
+    data.write((count >> 8) as u8); // count start here
+}
+
+/// channel 2 reached terminal count.
+pub fn channel_2_done() -> bool {
+    let mut control: Port<u8> = Port::new(SPEAKER_CONTROL);
+    unsafe { control.read() & CHANNEL_2_OUTPUT != 0 }
+}
@@ -0,0 +1,274 @@
+//! this module impl the CMOS real-time clock(RTC) driver.
+//! `
+//!     - port 0x70: register index (bit 7: NMI disable, set only during an access), port 0x71: data
+//!     - register A bit 7: update in progress -> wait, read twice until equal
+//!     - register B: bit 2 binary(else BCD), bit 1 24-hour(else 12-hour, PM = hour bit 7)
+//!     - periodic interrupt: IRQ8 at 32768 >> (rate - 1) Hz, ack by reading register C,
+//!       wake the threads blocked in `wait_periodic`
+//! `
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sync::Spinlock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::interrupts;
+use crate::thread::WaitQueue;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_CENTURY: u8 = 0x32; // ACPI FADT default, may not exist
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+const REG_STATUS_C: u8 = 0x0C;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+const RTC_IRQ: u8 = 8;
+
+// index / data port pair: one access sequence at a time, also from the IRQ8 handler
+static CMOS: Spinlock<()> = Spinlock::new(());
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_WAITERS: WaitQueue = WaitQueue::new();
+
+/// define a calendar date and time (UTC as the RTC holds it).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// seconds since 1970-01-01 00:00:00.
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), i64::from(self.month), i64::from(self.day));
+        let seconds = i64::from(self.hour) * 3600 + i64::from(self.minute) * 60 + i64::from(self.second);
+        (days * 86400 + seconds).max(0) as u64
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let days = (timestamp / 86400) as i64;
+        let seconds = timestamp % 86400;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// days since 1970-01-01 of a proleptic gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// (year, month, day) of days since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// NMI masked only during the access: the index is written back with bit 7 clear
+unsafe fn read_register(register: u8) -> u8 {
+    Port::<u8>::new(CMOS_ADDRESS).write(NMI_DISABLE | register);
+    let value = Port::<u8>::new(CMOS_DATA).read();
+    Port::<u8>::new(CMOS_ADDRESS).write(register);
+    value
+}
+
+unsafe fn write_register(register: u8, value: u8) {
+    Port::<u8>::new(CMOS_ADDRESS).write(NMI_DISABLE | register);
+    Port::<u8>::new(CMOS_DATA).write(value);
+    Port::<u8>::new(CMOS_ADDRESS).write(register);
+}
+
+// raw register values: seconds, minutes, hours, day, month, year, century
+unsafe fn read_raw() -> [u8; 7] {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [REG_SECONDS, REG_MINUTES, REG_HOURS, REG_DAY, REG_MONTH, REG_YEAR, REG_CENTURY].map(|reg| read_register(reg))
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+// decode raw register values with the register B format bits.
+fn decode(raw: [u8; 7], status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year, century] = raw;
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let value = |v: u8| if binary { v } else { from_bcd(v) };
+
+    // 12-hour: PM flag in bit 7, 12 AM -> 0, 12 PM -> 12
+    let pm = hour & HOUR_PM != 0;
+    let mut hour = value(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    // no century register: 20xx
+    let century = match value(century) {
+        century @ 19..=99 => u16::from(century),
+        _ => 20,
+    };
+    DateTime {
+        year: century * 100 + u16::from(value(year)),
+        month: value(month),
+        day: value(day),
+        hour,
+        minute: value(minute),
+        second: value(second),
+    }
+}
+
+/// read the current date and time from the RTC.
+pub fn read() -> DateTime {
+    without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        unsafe {
+            // an update between the reads mix two times: read until stable
+            let mut raw = read_raw();
+            loop {
+                let again = read_raw();
+                if again == raw {
+                    break;
+                }
+                raw = again;
+            }
+            decode(raw, read_register(REG_STATUS_B))
+        }
+    })
+}
+
+/// define the periodic interrupt rate is out of 3..=15.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadRate(pub u8);
+
+/// enable the periodic interrupt on IRQ8 at 32768 >> (rate - 1) Hz (rate 6: 1024 Hz).
+///
+/// IRQ8 belong to HPET timer 1 while `hpet::is_legacy`: no interrupt then.
+pub fn enable_periodic(rate: u8) -> Result<(), BadRate> {
+    if !(3..=15).contains(&rate) {
+        return Err(BadRate(rate));
+    }
+    without_interrupts(|| {
+        {
+            let _cmos = CMOS.lock();
+            unsafe {
+                let status_a = read_register(REG_STATUS_A);
+                write_register(REG_STATUS_A, (status_a & 0xf0) | rate);
+                let status_b = read_register(REG_STATUS_B);
+                write_register(REG_STATUS_B, status_b | STATUS_B_PERIODIC);
+                read_register(REG_STATUS_C); // drop a pending interrupt
+            }
+        }
+        interrupts::unmask_irq(RTC_IRQ);
+    });
+    Ok(())
+}
+
+/// disable the periodic interrupt and mask IRQ8.
+pub fn disable_periodic() {
+    without_interrupts(|| {
+        {
+            let _cmos = CMOS.lock();
+            unsafe {
+                let status_b = read_register(REG_STATUS_B);
+                write_register(REG_STATUS_B, status_b & !STATUS_B_PERIODIC);
+            }
+        }
+        interrupts::mask_irq(RTC_IRQ);
+    });
+}
+
+/// number of periodic interrupts handled.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// block the running thread until the next periodic interrupt, need `enable_periodic`.
+pub fn wait_periodic() {
+    let start = periodic_ticks();
+    PERIODIC_WAITERS.wait_until(|| periodic_ticks() != start);
+}
+
+/// called by the IRQ8 handler: RTC don't raise the next interrupt until register C is read.
+pub(crate) fn handle_interrupt() {
+    {
+        let _cmos = CMOS.lock();
+        unsafe { read_register(REG_STATUS_C) };
+    }
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    PERIODIC_WAITERS.wake_all();
+}
+
+
+#[test_case]
+fn test_decode_formats() {
+    // 2024-02-29 23:59:58, BCD 12-hour: 11 PM
+    let raw = [0x58, 0x59, HOUR_PM | 0x11, 0x29, 0x02, 0x24, 0x20];
+    let expected = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 58 };
+    assert_eq!(decode(raw, 0), expected);
+    // binary 24-hour, no century register
+    let raw = [58, 59, 23, 29, 2, 24, 0xff];
+    assert_eq!(decode(raw, STATUS_B_BINARY | STATUS_B_24_HOUR), expected);
+    // 12 AM -> 0
+    assert_eq!(decode([0, 0, 0x12, 1, 1, 0, 0x20], 0).hour, 0);
+}
+
+#[test_case]
+fn test_unix_timestamp() {
+    let date = DateTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 58 };
+    assert_eq!(date.unix_timestamp(), 1_709_251_198);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_251_198), date);
+    assert_eq!(DateTime::from_unix_timestamp(0).year, 1970);
+}
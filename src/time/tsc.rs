@@ -0,0 +1,120 @@
+//! this module impl the time stamp counter(TSC) clocksource.
+//! `
+//!     - invariant TSC: CPUID 0x80000007 EDX bit 8, constant rate in all P/C states
+//!     - calibrate: count TSC over 10ms of HPET main counter(when mapped) or a PIT channel 2 one shot,
+//!       best of `CALIBRATE_RUNS`
+//!     - nanos: (rdtsc - base) * 1e9 / hz, None when the TSC is not used
+//! `
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{hpet, pit};
+
+const CALIBRATE_MS: u64 = 10;
+const CALIBRATE_RUNS: usize = 3;
+// port / MMIO reads ~1us: 10ms need ~10k polls
+const CALIBRATE_SPIN_LIMIT: u64 = 1_000_000;
+
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+const CPUID_ADVANCED_POWER: u32 = 0x8000_0007;
+const INVARIANT_TSC: u32 = 1 << 8;
+
+// 0: TSC not used as clocksource
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+// rdtsc at calibration and the monotonic nanoseconds it represent
+static BASE_TSC: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// read the time stamp counter.
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// TSC tick at constant rate and don't stop in deep C states.
+pub fn is_invariant() -> bool {
+    let max_extended = __cpuid(CPUID_EXTENDED_MAX).eax;
+    max_extended >= CPUID_ADVANCED_POWER && __cpuid(CPUID_ADVANCED_POWER).edx & INVARIANT_TSC != 0
+}
+
+/// measure the TSC rate(Hz) against the HPET, or PIT channel 2 before the HPET is mapped.
+///
+/// None if the reference never finish.
+pub fn calibrate() -> Option<u64> {
+    // SMI / VM exit in a PIT run make it longer: take the shortest
+    let (reference_hz, counts, cycles) = match hpet::frequency() {
+        Some(hz) => (hz, hz * CALIBRATE_MS / 1000, without_interrupts(|| measure_hpet(hz * CALIBRATE_MS / 1000))?),
+        None => {
+            let count = pit::BASE_FREQUENCY * CALIBRATE_MS / 1000;
+            let cycles =
+                without_interrupts(|| (0..CALIBRATE_RUNS).filter_map(|_| measure_channel_2(count as u16)).min())?;
+            (pit::BASE_FREQUENCY, count, cycles)
+        }
+    };
+    Some((u128::from(cycles) * u128::from(reference_hz) / u128::from(counts)) as u64)
+}
+
+// TSC cycles while the HPET main counter advance `counts`: both read back to back, no edge to wait.
+fn measure_hpet(counts: u64) -> Option<u64> {
+    let start = hpet::counter()?;
+    let start_tsc = rdtsc();
+    for _ in 0..CALIBRATE_SPIN_LIMIT {
+        let now = hpet::counter()?;
+        let end_tsc = rdtsc();
+        if now.wrapping_sub(start) >= counts {
+            // scale to exactly `counts` reference ticks
+            let cycles = u128::from(end_tsc - start_tsc) * u128::from(counts) / u128::from(now - start);
+            return Some(cycles as u64);
+        }
+    }
+    None
+}
+
+// TSC cycles of one PIT channel 2 one shot of `count` input clocks.
+fn measure_channel_2(count: u16) -> Option<u64> {
+    unsafe { pit::start_channel_2(count) };
+    let start = rdtsc();
+    for _ in 0..CALIBRATE_SPIN_LIMIT {
+        if pit::channel_2_done() {
+            return Some(rdtsc() - start);
+        }
+    }
+    None
+}
+
+/// use the TSC as clocksource at `hz`, continuing from `base_nanos`.
+pub fn enable(hz: u64, base_nanos: u64) {
+    without_interrupts(|| {
+        BASE_NANOS.store(base_nanos, Ordering::SeqCst);
+        BASE_TSC.store(rdtsc(), Ordering::SeqCst);
+        FREQUENCY.store(hz, Ordering::SeqCst);
+    });
+}
+
+/// calibrated TSC rate(Hz) if the TSC is the clocksource.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// monotonic nanoseconds from the TSC, None if the TSC is not the clocksource.
+pub fn nanos() -> Option<u64> {
+    let hz = frequency()?;
+    let cycles = rdtsc().saturating_sub(BASE_TSC.load(Ordering::Relaxed));
+    let nanos = u128::from(cycles) * 1_000_000_000 / u128::from(hz);
+    Some(BASE_NANOS.load(Ordering::Relaxed) + nanos as u64)
+}
+
+
+#[test_case]
+fn test_calibrate_against_pit() {
+    let hz = calibrate().expect("PIT channel 2 never finish");
+    // any x86_64 TSC(or QEMU's) run faster than 10 MHz
+    assert!(hz > 10_000_000, "TSC {} Hz", hz);
+    let first = rdtsc();
+    assert!(rdtsc() >= first);
+}
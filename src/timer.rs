@@ -0,0 +1,363 @@
+//! this module impl kros software timers on a hierarchical timer wheel.
+//! `
+//!     - wheel: 4 levels x 64 slots, level n slot = 64^n ticks, ~4.6 hours at 1000 Hz
+//!     - far timer: cascade to a lower level when its slot come round, re-insert by deadline
+//!     - timer interrupt: `on_tick` only check the next deadline and queue a deferred `process`
+//!     - process(deferred context): advance the wheel to now, run expired callbacks
+//!     - one shot / periodic timers, cancel by `TimerHandle`
+//! `
+//! timer nodes live in a fixed pool: schedule / cancel don't alloc, safe in interrupt context.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::deferred::{self, WorkItem};
+use crate::sync::IrqSpinlock;
+use crate::time::{self, Duration, Instant};
+
+/// max armed timers.
+pub const MAX_TIMERS: usize = 256;
+
+const LEVELS: usize = 4;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+// farthest deadline one insert can hold: 64^4 ticks
+const MAX_DELTA: u64 = 1 << (SLOT_BITS * LEVELS as u32);
+
+// index link in the node pool
+const NIL: u16 = u16::MAX;
+
+/// define a scheduled timer, used to cancel it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    index: u16,
+    generation: u32,
+}
+
+/// define why a timer can't be scheduled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// all `MAX_TIMERS` nodes armed.
+    Full,
+    /// periodic timer with zero period.
+    ZeroPeriod,
+}
+
+// which list a node is linked in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Free,
+    Slot(usize, usize),
+    Expired,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    item: WorkItem,
+    expires: u64,
+    // 0: one shot
+    period: u64,
+    generation: u32,
+    location: Location,
+    prev: u16,
+    next: u16,
+}
+
+fn noop(_: usize) {}
+
+impl Node {
+    const EMPTY: Node = Node {
+        item: WorkItem::new(noop, 0),
+        expires: 0,
+        period: 0,
+        generation: 0,
+        location: Location::Free,
+        prev: NIL,
+        next: NIL,
+    };
+}
+
+struct Wheel {
+    nodes: [Node; MAX_TIMERS],
+    slots: [[u16; SLOTS]; LEVELS],
+    expired: u16,
+    free: u16,
+    // next tick to process: all ticks before are done
+    clock: u64,
+    armed: usize,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        let mut nodes = [Node::EMPTY; MAX_TIMERS];
+        // free list through `next`
+        let mut i = 0;
+        while i < MAX_TIMERS {
+            nodes[i].next = if i + 1 < MAX_TIMERS { (i + 1) as u16 } else { NIL };
+            i += 1;
+        }
+        Wheel { nodes, slots: [[NIL; SLOTS]; LEVELS], expired: NIL, free: 0, clock: 0, armed: 0 }
+    }
+
+    fn head(&mut self, location: Location) -> &mut u16 {
+        match location {
+            Location::Free => &mut self.free,
+            Location::Slot(level, slot) => &mut self.slots[level][slot],
+            Location::Expired => &mut self.expired,
+        }
+    }
+
+    fn link(&mut self, index: u16, location: Location) {
+        let head = *self.head(location);
+        if head != NIL {
+            self.nodes[usize::from(head)].prev = index;
+        }
+        let node = &mut self.nodes[usize::from(index)];
+        node.location = location;
+        node.prev = NIL;
+        node.next = head;
+        *self.head(location) = index;
+    }
+
+    fn unlink(&mut self, index: u16) {
+        let Node { prev, next, location, .. } = self.nodes[usize::from(index)];
+        match prev {
+            NIL => *self.head(location) = next,
+            prev => self.nodes[usize::from(prev)].next = next,
+        }
+        if next != NIL {
+            self.nodes[usize::from(next)].prev = prev;
+        }
+    }
+
+    // slot for a deadline, relative to the wheel clock.
+    fn location_for(&self, expires: u64) -> Location {
+        // too far: park in the last level, re-insert on cascade
+        let delta = (expires - self.clock).min(MAX_DELTA - 1);
+        let level = (0..LEVELS).find(|&level| delta < 1 << (SLOT_BITS * (level as u32 + 1))).unwrap_or(LEVELS - 1);
+        let key = if delta == MAX_DELTA - 1 { self.clock + delta } else { expires };
+        Location::Slot(level, ((key >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize)
+    }
+
+    fn insert(&mut self, item: WorkItem, expires: u64, period: u64, now: u64) -> Result<TimerHandle, TimerError> {
+        if self.free == NIL {
+            return Err(TimerError::Full);
+        }
+        // idle wheel: nothing to cascade, skip the ticks in between
+        if self.armed == 0 {
+            self.clock = self.clock.max(now);
+        }
+        let index = self.free;
+        self.unlink(index);
+        let node = &mut self.nodes[usize::from(index)];
+        node.item = item;
+        node.expires = expires;
+        node.period = period;
+        node.generation = node.generation.wrapping_add(1);
+        let handle = TimerHandle { index, generation: node.generation };
+
+        // deadline in an already processed tick: expire on next `process`
+        let location = match expires < self.clock {
+            true => Location::Expired,
+            false => self.location_for(expires),
+        };
+        self.link(index, location);
+        self.armed += 1;
+        Ok(handle)
+    }
+
+    fn cancel(&mut self, handle: TimerHandle) -> bool {
+        let armed = self
+            .nodes
+            .get(usize::from(handle.index))
+            .is_some_and(|node| node.generation == handle.generation && node.location != Location::Free);
+        if armed {
+            self.release(handle.index);
+        }
+        armed
+    }
+
+    fn release(&mut self, index: u16) {
+        self.unlink(index);
+        self.link(index, Location::Free);
+        self.armed -= 1;
+    }
+
+    // move every node of a slot to its slot for the current clock.
+    fn cascade(&mut self, level: usize, slot: usize) {
+        let mut index = core::mem::replace(&mut self.slots[level][slot], NIL);
+        while index != NIL {
+            let next = self.nodes[usize::from(index)].next;
+            let location = self.location_for(self.nodes[usize::from(index)].expires);
+            self.link(index, location);
+            index = next;
+        }
+    }
+
+    /// process all ticks up to `now`: expired nodes move to the expired list.
+    fn advance(&mut self, now: u64) {
+        while self.clock <= now {
+            if self.armed == 0 {
+                self.clock = now + 1;
+                break;
+            }
+            let tick = self.clock;
+            // level n slot come round every 64^n ticks, cascade from the top
+            for level in (1..LEVELS).rev() {
+                if tick & ((1 << (SLOT_BITS * level as u32)) - 1) == 0 {
+                    self.cascade(level, ((tick >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize);
+                }
+            }
+            let slot = (tick & SLOT_MASK) as usize;
+            while self.slots[0][slot] != NIL {
+                let index = self.slots[0][slot];
+                self.unlink(index);
+                self.link(index, Location::Expired);
+            }
+            self.clock += 1;
+        }
+    }
+
+    /// take one expired timer: re-arm periodic, free one shot.
+    fn pop_expired(&mut self) -> Option<WorkItem> {
+        let index = self.expired;
+        if index == NIL {
+            return None;
+        }
+        let Node { item, expires, period, .. } = self.nodes[usize::from(index)];
+        if period == 0 {
+            self.release(index);
+        } else {
+            // missed periods(long deferred latency) are skipped, not run in burst
+            let mut expires = expires + period;
+            if expires < self.clock {
+                expires += (self.clock - expires).div_ceil(period) * period;
+            }
+            self.unlink(index);
+            self.nodes[usize::from(index)].expires = expires;
+            let location = self.location_for(expires);
+            self.link(index, location);
+        }
+        Some(item)
+    }
+
+    // earliest deadline of armed timers, u64::MAX if none.
+    fn next_expiry(&self) -> u64 {
+        if self.expired != NIL {
+            return 0;
+        }
+        self.nodes
+            .iter()
+            .filter(|node| matches!(node.location, Location::Slot(..)))
+            .map(|node| node.expires)
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+static WHEEL: IrqSpinlock<Wheel> = IrqSpinlock::new(Wheel::new());
+// tick of the earliest deadline: timer interrupt check it without lock
+static NEXT_EXPIRY: AtomicU64 = AtomicU64::new(u64::MAX);
+// one `process` in deferred queue at a time
+static PROCESS_QUEUED: AtomicBool = AtomicBool::new(false);
+
+/// run `item` in deferred context once at `deadline`.
+pub fn schedule(deadline: Instant, item: WorkItem) -> Result<TimerHandle, TimerError> {
+    arm(deadline, 0, item)
+}
+
+/// run `item` in deferred context every `period`, first at `first`.
+pub fn schedule_periodic(first: Instant, period: Duration, item: WorkItem) -> Result<TimerHandle, TimerError> {
+    match time::duration_to_ticks(period) {
+        0 => Err(TimerError::ZeroPeriod),
+        period => arm(first, period, item),
+    }
+}
+
+fn arm(deadline: Instant, period: u64, item: WorkItem) -> Result<TimerHandle, TimerError> {
+    let mut wheel = WHEEL.lock();
+    let handle = wheel.insert(item, deadline.ticks(), period, time::ticks())?;
+    NEXT_EXPIRY.fetch_min(deadline.ticks(), Ordering::SeqCst);
+    Ok(handle)
+}
+
+/// cancel a timer, return false if it already fired(one shot) or was cancelled.
+pub fn cancel(handle: TimerHandle) -> bool {
+    WHEEL.lock().cancel(handle)
+}
+
+/// number of armed timers.
+pub fn armed() -> usize {
+    WHEEL.lock().armed
+}
+
+/// called by the timer interrupt handler: queue `process` when a deadline passed.
+pub(crate) fn on_tick(now: u64) {
+    if now < NEXT_EXPIRY.load(Ordering::Relaxed) || PROCESS_QUEUED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if deferred::schedule(WorkItem::new(process, 0)).is_err() {
+        PROCESS_QUEUED.store(false, Ordering::SeqCst); // retry next tick
+    }
+}
+
+/// deferred work: advance the wheel and run expired callbacks with interrupts enabled.
+fn process(_: usize) {
+    PROCESS_QUEUED.store(false, Ordering::SeqCst);
+    WHEEL.lock().advance(time::ticks());
+
+    // one by one without the lock: callback may schedule / cancel timers
+    loop {
+        let expired = WHEEL.lock().pop_expired();
+        match expired {
+            Some(item) => item.run(),
+            None => break,
+        }
+    }
+    let wheel = WHEEL.lock();
+    NEXT_EXPIRY.store(wheel.next_expiry(), Ordering::SeqCst);
+}
+
+
+#[test_case]
+fn test_wheel_levels_and_cascade() {
+    // no heap in lib test, too big for the stack
+    static TEST_WHEEL: IrqSpinlock<Wheel> = IrqSpinlock::new(Wheel::new());
+
+    let mut wheel = TEST_WHEEL.lock();
+    let item = WorkItem::new(noop, 0);
+    let near = wheel.insert(item, 10, 0, 0).unwrap();
+    let far = wheel.insert(item, 5000, 0, 0).unwrap(); // level 2
+    assert_eq!(wheel.nodes[usize::from(near.index)].location, Location::Slot(0, 10));
+    assert_eq!(wheel.nodes[usize::from(far.index)].location, Location::Slot(2, 1));
+
+    wheel.advance(9);
+    assert!(wheel.pop_expired().is_none());
+    wheel.advance(10);
+    assert!(wheel.pop_expired().is_some());
+    wheel.advance(4999);
+    assert!(wheel.pop_expired().is_none());
+    wheel.advance(5000);
+    assert!(wheel.pop_expired().is_some());
+    assert_eq!(wheel.armed, 0);
+}
+
+#[test_case]
+fn test_wheel_cancel_and_periodic() {
+    static TEST_WHEEL: IrqSpinlock<Wheel> = IrqSpinlock::new(Wheel::new());
+
+    let mut wheel = TEST_WHEEL.lock();
+    let item = WorkItem::new(noop, 0);
+    let once = wheel.insert(item, 3, 0, 0).unwrap();
+    let periodic = wheel.insert(item, 2, 2, 0).unwrap();
+    assert!(wheel.cancel(once));
+    assert!(!wheel.cancel(once));
+
+    wheel.advance(6);
+    // periodic: fired once, re-armed after the missed periods
+    assert!(wheel.pop_expired().is_some());
+    assert!(wheel.pop_expired().is_none());
+    assert_eq!(wheel.nodes[usize::from(periodic.index)].expires, 8);
+    assert!(wheel.cancel(periodic));
+    assert_eq!(wheel.next_expiry(), u64::MAX);
+}
@@ -0,0 +1,234 @@
+//! this module impl user mode (ring 3).
+//! `
+//!     - user region: [USER_START, USER_END), private L4 entries of each AddressSpace, USER_ACCESSIBLE pages
+//!     - AddressSpace: L4 table sharing the kernel entries (not USER_ACCESSIBLE) + private user page tables
+//!     - enter_user_mode: iretq to ring 3 with the user segments, never return
+//!     - spawn: thread owning an AddressSpace, enter ring 3 at once
+//!     - resume: restore a whole TrapFrame in ring 3 (forked process)
+//!     - copy on write: write fault on a page shared by `AddressSpace::fork` -> private copy, retried
+//!     - elf: load an ELF64 executable + SysV initial stack into a fresh AddressSpace
+//!     - interrupt from ring 3: CPU switch to TSS privilege_stack_table[0] = the running thread stack
+//!     - user fault: exception from ring 3 -> report, SIGSEGV to the process (kernel spawned thread: end it)
+//! `
+
+pub mod address_space;
+pub mod elf;
+
+pub use address_space::AddressSpace;
+pub use elf::{ElfError, Program};
+
+use alloc::sync::Arc;
+use core::arch::asm;
+use core::fmt;
+
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::{mapper::MapToError, Size4KiB};
+use x86_64::VirtAddr;
+
+use crate::interrupts::trap::{self, TrapFrame, RFLAGS_INTERRUPT};
+use crate::sync::IrqSpinlock;
+use crate::{gdt, println, process, thread};
+
+/// user region start: L4 entry 224, never used by the kernel.
+pub const USER_START: u64 = 0x_7000_0000_0000;
+/// user region end (exclusive): L4 entries 224..254.
+pub const USER_END: u64 = 0x_7f00_0000_0000;
+
+/// define user address space errors.
+#[derive(Debug)]
+pub enum UserError {
+    /// `memory::init_kernel_memory` not called yet.
+    NoKernelMemory,
+    /// out of physical frames.
+    NoFrame,
+    /// range not inside the user region.
+    NotUserRange,
+    /// address not mapped in the address space.
+    NotMapped(VirtAddr),
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for UserError {
+    fn from(error: MapToError<Size4KiB>) -> Self {
+        UserError::Map(error)
+    }
+}
+
+/// whether [start, start + len) is inside the user region.
+pub fn is_user_range(start: VirtAddr, len: u64) -> bool {
+    let start = start.as_u64();
+    start >= USER_START && start.checked_add(len).is_some_and(|end| end <= USER_END)
+}
+
+/// jump to `entry` in ring 3 with rsp = `stack_top`, rdi = `arg`, interrupts enabled.
+///
+/// # Safety
+///
+/// `entry` and the stack must be mapped USER_ACCESSIBLE in the active address space
+/// (`AddressSpace::activate`). the kernel stack of the caller is abandoned: interrupts
+/// from ring 3 reuse it from the top, values owned by the callers are never dropped.
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr, arg: u64) -> ! {
+    let code = u64::from(gdt::user_code_selector().0);
+    let data = u64::from(gdt::user_data_selector().0);
+    asm!(
+        // iretq frame: ss, rsp, rflags, cs, rip
+        "push {data}",
+        "push {stack}",
+        "push {rflags}",
+        "push {code}",
+        "push {entry}",
+        // no kernel value leak to user registers, rdi keep `arg`
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        data = in(reg) data,
+        stack = in(reg) stack_top.as_u64(),
+        rflags = in(reg) RFLAGS_INTERRUPT | 0x2, // bit 1 reserved, always set
+        code = in(reg) code,
+        entry = in(reg) entry.as_u64(),
+        in("rdi") arg,
+        options(noreturn),
+    )
+}
+
+/// frame entering `entry` in ring 3 with rsp = `stack_top`, rdi = `arg`, interrupts enabled (`resume`).
+pub fn initial_frame(entry: VirtAddr, stack_top: VirtAddr, arg: u64) -> TrapFrame {
+    TrapFrame {
+        rip: entry.as_u64(),
+        cs: u64::from(gdt::user_code_selector().0),
+        rflags: RFLAGS_INTERRUPT | 0x2, // bit 1 reserved, always set
+        rsp: stack_top.as_u64(),
+        ss: u64::from(gdt::user_data_selector().0),
+        rdi: arg,
+        ..TrapFrame::default()
+    }
+}
+
+/// load every register of `frame` and iretq to it, never return.
+///
+/// # Safety
+///
+/// same as `enter_user_mode`: `frame` hold the user selectors, its rip and rsp are mapped
+/// USER_ACCESSIBLE in the active address space. the kernel stack of the caller is abandoned.
+pub unsafe fn resume(frame: &TrapFrame) -> ! {
+    asm!(
+        // rsp inside `frame` until iretq: no interrupt in between
+        "cli",
+        "mov rsp, {frame}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "add rsp, 16", // vector, error code
+        "iretq",
+        frame = in(reg) frame as *const TrapFrame,
+        options(noreturn),
+    )
+}
+
+/// spawn a thread running `entry` in ring 3 inside `space`, rsp = `stack_top`, rdi = `arg`.
+///
+/// the thread own `space`: freed when the thread is removed (joined, or ended while detached).
+/// the thread end by `syscall::SYS_EXIT` or a fault, `join` then return None.
+pub fn spawn(space: Arc<AddressSpace>, entry: VirtAddr, stack_top: VirtAddr, arg: u64) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        space.activate();
+        // owned locals are never dropped after the jump: the thread keep the only reference
+        drop(space);
+        unsafe { enter_user_mode(entry, stack_top, arg) }
+    })
+}
+
+/// resolve a write fault at `addr` on a copy-on-write page of the running thread, false if not one.
+pub(crate) fn copy_on_write(addr: VirtAddr) -> bool {
+    is_user_range(addr, 1) && thread::address_space().is_some_and(|space| space.copy_on_write(addr))
+}
+
+/// whether the interrupted code ran in ring 3.
+pub fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// define which exception ended a user thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    PageFault { addr: VirtAddr, error_code: PageFaultErrorCode },
+    GeneralProtection { error_code: u64 },
+}
+
+/// define the last exception raised by user code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserFault {
+    pub thread: thread::ThreadId,
+    pub ip: VirtAddr,
+    pub kind: FaultKind,
+}
+
+impl fmt::Display for UserFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            FaultKind::PageFault { addr, error_code } => write!(
+                f, "thread {} page fault at {:?} (ip {:?}, {:?})", self.thread.as_u64(), addr, self.ip, error_code
+            ),
+            FaultKind::GeneralProtection { error_code } => write!(
+                f, "thread {} general protection (ip {:?}, error code {:#x})", self.thread.as_u64(), self.ip, error_code
+            ),
+        }
+    }
+}
+
+static LAST_FAULT: IrqSpinlock<Option<UserFault>> = IrqSpinlock::new(None);
+
+/// last exception raised by user code, None if none yet.
+pub fn last_fault() -> Option<UserFault> {
+    *LAST_FAULT.lock()
+}
+
+/// called by exception handlers for a fault from ring 3: report it, SIGSEGV to the running process.
+///
+/// the handler then return: the fault resume in `trap_dispatch`, which deliver the signal
+/// (user handler, or the process end with a core dump). a thread outside any process just end.
+pub(crate) fn handle_fault(stack_frame: &mut InterruptStackFrame, vector: u8, error_code: u64, kind: FaultKind) {
+    let fault = UserFault { thread: thread::current_id(), ip: stack_frame.instruction_pointer, kind };
+    *LAST_FAULT.lock() = Some(fault);
+    println!("USER FAULT: {}", fault);
+    if process::current().is_none() {
+        // the address space is freed with the thread, the kernel state is untouched
+        thread::exit();
+    }
+    process::signal::force(process::signal::Signal::SIGSEGV);
+    trap::redirect_user_fault(stack_frame, vector, error_code);
+}
+
+
+#[test_case]
+fn test_user_range() {
+    assert!(is_user_range(VirtAddr::new(USER_START), 4096));
+    assert!(is_user_range(VirtAddr::new(USER_END - 8), 8));
+    assert!(!is_user_range(VirtAddr::new(USER_END - 8), 9));
+    assert!(!is_user_range(VirtAddr::new(USER_START - 8), 8));
+    assert!(!is_user_range(VirtAddr::new(crate::allocator::HEAP_START as u64), 8));
+}
@@ -0,0 +1,376 @@
+//! this module impl user address spaces.
+//! `
+//!     - new: fresh L4 table, kernel L4 entries copied (shared lower tables, no USER_ACCESSIBLE)
+//!     - map: zeroed frames at user pages, PRESENT | USER_ACCESSIBLE | flags
+//!     - read / write: copy through the physical memory map, the space need not be active
+//!         write copy pages shared with another space first (copy-on-write or read-only)
+//!     - is_mapped: check a user range before the kernel touch it (syscall arguments)
+//!     - activate: copy kernel entries added since new, the running thread own and load it
+//!     - fork: copy the user page tables, writable pages shared read-only + COPY_ON_WRITE by both spaces
+//!     - copy_on_write: write fault on a shared page -> private copy (last sharer: writable again)
+//!     - frame references: mappings of each shared frame, freed with the last one
+//!     - drop: free every user frame (unshared) and user page table, then the L4 table
+//! `
+//! page tables and frame references are only changed under the kernel memory lock.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PageTableIndex, PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{is_user_range, UserError, USER_END, USER_START};
+use crate::memory::{self, BootInfoFrameAllocator};
+use crate::sync::Spinlock;
+use crate::thread;
+
+const PAGE_SIZE: u64 = 4096;
+// L4 entries of the user region
+const USER_L4_START: usize = (USER_START >> 39) as usize;
+const USER_L4_END: usize = (USER_END >> 39) as usize;
+
+/// page table entry bit (free for the OS): page shared by `fork`, read-only until copied.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+// user mappings of each frame shared by `fork`, absent: one mapping.
+// entries are removed only when the frame is freed: copy on write never free heap memory
+// (it run with interrupts disabled on signal delivery)
+static FRAME_REFS: Spinlock<BTreeMap<PhysFrame, usize>> = Spinlock::new(BTreeMap::new());
+
+/// define one user address space: an L4 table with private user mappings.
+#[derive(Debug)]
+pub struct AddressSpace {
+    l4: PhysFrame,
+}
+
+impl AddressSpace {
+    /// allocate an L4 table mapping the kernel and no user page.
+    pub fn new() -> Result<Arc<AddressSpace>, UserError> {
+        let l4 = memory::with_kernel_memory(|memory| memory.frame_allocator.allocate_frame())
+            .ok_or(UserError::NoKernelMemory)?
+            .ok_or(UserError::NoFrame)?;
+        zero_frame(l4);
+        let space = AddressSpace { l4 };
+        memory::with_kernel_memory(|_| space.sync_kernel());
+        Ok(Arc::new(space))
+    }
+
+    /// frame of the L4 table (CR3 value).
+    pub fn page_table(&self) -> PhysFrame {
+        self.l4
+    }
+
+    /// whether this space is loaded in CR3.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.l4
+    }
+
+    /// load this space for the running thread, kept across switches until replaced.
+    pub fn activate(self: &Arc<Self>) {
+        // kernel L4 entries created after `new` (e.g. new MMIO region)
+        memory::with_kernel_memory(|_| self.sync_kernel());
+        thread::set_address_space(Some(self.clone()));
+    }
+
+    /// map zeroed frames at every page of [start, start + len): PRESENT | USER_ACCESSIBLE | `flags`.
+    pub fn map(&self, start: VirtAddr, len: u64, flags: PageTableFlags) -> Result<(), UserError> {
+        if !is_user_range(start, len) {
+            return Err(UserError::NotUserRange);
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + len.max(1) - 1u64);
+        memory::with_kernel_memory(|memory| {
+            let mut mapper = unsafe { self.mapper() };
+            for page in Page::range_inclusive(first, last) {
+                let frame = memory.frame_allocator.allocate_frame().ok_or(UserError::NoFrame)?;
+                zero_frame(frame);
+                match unsafe { mapper.map_to(page, frame, flags, &mut memory.frame_allocator) } {
+                    Ok(flush) => flush.flush(),
+                    Err(error) => {
+                        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+                        return Err(error.into());
+                    }
+                }
+            }
+            Ok(())
+        })
+        .ok_or(UserError::NoKernelMemory)?
+    }
+
+    /// physical address of `addr` in this space.
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        memory::with_kernel_memory(|_| unsafe { self.mapper() }.translate_addr(addr)).flatten()
+    }
+
+    /// whether every page of [addr, addr + len) is a user page mapped with `flags`.
+    pub fn is_mapped(&self, addr: VirtAddr, len: u64, flags: PageTableFlags) -> bool {
+        use x86_64::structures::paging::mapper::TranslateResult;
+
+        if !is_user_range(addr, len) {
+            return false;
+        }
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let first = Page::<Size4KiB>::containing_address(addr);
+        let last = Page::<Size4KiB>::containing_address(addr + len.max(1) - 1u64);
+        memory::with_kernel_memory(|_| {
+            let mapper = unsafe { self.mapper() };
+            Page::range_inclusive(first, last).all(|page| matches!(
+                mapper.translate(page.start_address()),
+                TranslateResult::Mapped { flags: mapped, .. } if mapped.contains(flags)
+            ))
+        })
+        .unwrap_or(false)
+    }
+
+    /// copy of this space: the same user pages, writable ones shared copy-on-write by both spaces.
+    ///
+    /// read-only pages stay shared. the TLB is flushed if this space is active.
+    pub fn fork(&self) -> Result<Arc<AddressSpace>, UserError> {
+        let child = AddressSpace::new()?;
+        memory::with_kernel_memory(|memory| {
+            let (parent, copy) = unsafe { (table(self.l4), table(child.l4)) };
+            let result = fork_table(parent, copy, 4, &mut memory.frame_allocator);
+            // parent pages already made read-only, even on error
+            if self.is_active() {
+                tlb::flush_all();
+            }
+            result
+        })
+        .ok_or(UserError::NoKernelMemory)??;
+        // error: the partial copy is freed by the child drop
+        Ok(child)
+    }
+
+    /// resolve a write fault at `addr`: a private writable copy of a COPY_ON_WRITE page.
+    ///
+    /// false: not a copy-on-write page (a real fault) or no frame left.
+    pub fn copy_on_write(&self, addr: VirtAddr) -> bool {
+        matches!(self.unshare(Page::containing_address(addr), false), Ok(true))
+    }
+
+    /// copy `bytes` to `addr`, every page already mapped (writable or not).
+    ///
+    /// pages shared with another space (copy-on-write or read-only) are copied first: the other
+    /// spaces keep the old bytes.
+    pub fn write(&self, addr: VirtAddr, bytes: &[u8]) -> Result<(), UserError> {
+        if is_user_range(addr, bytes.len() as u64) && !bytes.is_empty() {
+            let first = Page::<Size4KiB>::containing_address(addr);
+            let last = Page::<Size4KiB>::containing_address(addr + (bytes.len() as u64 - 1));
+            for page in Page::range_inclusive(first, last) {
+                self.unshare(page, true)?;
+            }
+        }
+        self.copy(addr, bytes.len(), |virt, range| {
+            let chunk = &bytes[range];
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), virt.as_mut_ptr::<u8>(), chunk.len()) };
+        })
+    }
+
+    /// copy from `addr` to `buf`, every page mapped.
+    pub fn read(&self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), UserError> {
+        self.copy(addr, buf.len(), |virt, range| {
+            let chunk = &mut buf[range];
+            unsafe { core::ptr::copy_nonoverlapping(virt.as_ptr::<u8>(), chunk.as_mut_ptr(), chunk.len()) };
+        })
+    }
+
+    // call `f(kernel view, chunk range)` for each page chunk of [addr, addr + len)
+    fn copy(
+        &self, addr: VirtAddr, len: usize, mut f: impl FnMut(VirtAddr, core::ops::Range<usize>)
+    ) -> Result<(), UserError> {
+        if !is_user_range(addr, len as u64) {
+            return Err(UserError::NotUserRange);
+        }
+        let mut done = 0;
+        while done < len {
+            let virt = addr + done as u64;
+            let chunk = (PAGE_SIZE - virt.as_u64() % PAGE_SIZE).min((len - done) as u64) as usize;
+            let phys = self.translate(virt).ok_or(UserError::NotMapped(virt))?;
+            let kernel = memory::phys_to_virt(phys).ok_or(UserError::NoKernelMemory)?;
+            f(kernel, done..done + chunk);
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    // COPY_ON_WRITE page: own frame if the last sharer, else a copy -> writable. Ok(false): not copy-on-write
+    // `read_only`: a shared read-only page get a private copy too, still read-only (kernel write)
+    fn unshare(&self, page: Page<Size4KiB>, read_only: bool) -> Result<bool, UserError> {
+        if !is_user_range(page.start_address(), PAGE_SIZE) {
+            return Ok(false);
+        }
+        memory::with_kernel_memory(|memory| {
+            let Some(entry) = (unsafe { self.leaf_entry(page) }) else {
+                return Ok(false);
+            };
+            let (Ok(frame), flags) = (entry.frame(), entry.flags()) else {
+                return Ok(false);
+            };
+            let shared = frame_references_locked(frame) > 1;
+            let flags = if flags.contains(COPY_ON_WRITE) {
+                (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE
+            } else if read_only && shared && !flags.contains(PageTableFlags::WRITABLE) {
+                flags
+            } else {
+                return Ok(false);
+            };
+            if shared {
+                let copy = memory.frame_allocator.allocate_frame().ok_or(UserError::NoFrame)?;
+                copy_frame(frame, copy);
+                release_frame(frame);
+                entry.set_frame(copy, flags);
+            } else {
+                entry.set_flags(flags);
+            }
+            if self.is_active() {
+                tlb::flush(page.start_address());
+            }
+            Ok(true)
+        })
+        .ok_or(UserError::NoKernelMemory)?
+    }
+
+    // level 1 entry of `page`, None if a table on the way is missing
+    // caller hold the kernel memory lock
+    unsafe fn leaf_entry(&self, page: Page<Size4KiB>) -> Option<&'static mut PageTableEntry> {
+        let indexes = [page.p4_index(), page.p3_index(), page.p2_index()];
+        let mut frame = self.l4;
+        for index in indexes {
+            frame = table(frame)[index].frame().ok()?;
+        }
+        Some(&mut table(frame)[page.p1_index()])
+    }
+
+    // mapper of this L4 table through the physical memory map
+    // caller hold the kernel memory lock: one mapper of a table at a time
+    unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        let offset = memory::physical_memory_offset().expect("physical memory offset not recorded");
+        OffsetPageTable::new(table(self.l4), offset)
+    }
+
+    // copy the kernel L4 entries outside the user region
+    fn sync_kernel(&self) {
+        let kernel = unsafe { table(memory::kernel_page_table().expect("kernel page table not recorded")) };
+        let user = unsafe { table(self.l4) };
+        for index in 0..512 {
+            if (USER_L4_START..USER_L4_END).contains(&index) {
+                assert!(kernel[index].is_unused(), "kernel mapping inside the user region");
+            } else {
+                user[index] = kernel[index].clone();
+            }
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            // last owner is not a running thread: fall back to the kernel table before freeing
+            let kernel = memory::kernel_page_table().expect("kernel page table not recorded");
+            unsafe { Cr3::write(kernel, Cr3::read().1) };
+        }
+        memory::with_kernel_memory(|memory| unsafe {
+            let l4 = table(self.l4);
+            for entry in l4.iter_mut().take(USER_L4_END).skip(USER_L4_START) {
+                if !entry.is_unused() {
+                    free_table(entry.frame().expect("huge page in user region"), 3, &mut memory.frame_allocator);
+                    entry.set_unused();
+                }
+            }
+            memory.frame_allocator.deallocate_frame(self.l4);
+        });
+    }
+}
+
+// kernel view of a page table frame
+unsafe fn table(frame: PhysFrame) -> &'static mut PageTable {
+    let virt = memory::phys_to_virt(frame.start_address()).expect("physical memory offset not recorded");
+    &mut *virt.as_mut_ptr::<PageTable>()
+}
+
+fn zero_frame(frame: PhysFrame) {
+    let virt = memory::phys_to_virt(frame.start_address()).expect("physical memory offset not recorded");
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+}
+
+fn copy_frame(from: PhysFrame, to: PhysFrame) {
+    let from = memory::phys_to_virt(from.start_address()).expect("physical memory offset not recorded");
+    let to = memory::phys_to_virt(to.start_address()).expect("physical memory offset not recorded");
+    unsafe { core::ptr::copy_nonoverlapping(from.as_ptr::<u8>(), to.as_mut_ptr::<u8>(), PAGE_SIZE as usize) };
+}
+
+/// user mappings of `frame` in every address space (1: not shared).
+pub fn frame_references(frame: PhysFrame) -> usize {
+    memory::with_kernel_memory(|_| frame_references_locked(frame)).unwrap_or(1)
+}
+
+fn frame_references_locked(frame: PhysFrame) -> usize {
+    FRAME_REFS.lock().get(&frame).copied().unwrap_or(1)
+}
+
+fn share_frame(frame: PhysFrame) {
+    *FRAME_REFS.lock().entry(frame).or_insert(1) += 1;
+}
+
+// drop one mapping of `frame`, true if it was the last one: free it
+fn release_frame(frame: PhysFrame) -> bool {
+    let mut refs = FRAME_REFS.lock();
+    let Some(count) = refs.get_mut(&frame) else {
+        return true;
+    };
+    *count -= 1;
+    if *count > 0 {
+        return false;
+    }
+    refs.remove(&frame);
+    true
+}
+
+// copy the user entries of a level `level` table: new lower tables, shared frames.
+// each new table is linked before it is filled: a partial copy stay reachable for drop
+fn fork_table(parent: &mut PageTable, child: &mut PageTable, level: u8, allocator: &mut BootInfoFrameAllocator) -> Result<(), UserError> {
+    let indexes = if level == 4 { USER_L4_START..USER_L4_END } else { 0..512 };
+    for index in indexes.map(|index| PageTableIndex::new(index as u16)) {
+        let entry = &mut parent[index];
+        let Ok(frame) = entry.frame() else {
+            continue;
+        };
+        if level > 1 {
+            let copy = allocator.allocate_frame().ok_or(UserError::NoFrame)?;
+            zero_frame(copy);
+            child[index].set_frame(copy, entry.flags());
+            unsafe { fork_table(table(frame), table(copy), level - 1, allocator)? };
+        } else {
+            let mut flags = entry.flags();
+            if flags.contains(PageTableFlags::WRITABLE) {
+                flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                entry.set_flags(flags);
+            }
+            share_frame(frame);
+            child[index].set_frame(frame, flags);
+        }
+    }
+    Ok(())
+}
+
+// free the mapped frames under a level `level` table (3 -> 1), then the table itself
+unsafe fn free_table(frame: PhysFrame, level: u8, allocator: &mut BootInfoFrameAllocator) {
+    for entry in table(frame).iter() {
+        let Ok(child) = entry.frame() else {
+            continue;
+        };
+        if level > 1 {
+            free_table(child, level - 1, allocator);
+        } else if release_frame(child) {
+            allocator.deallocate_frame(child);
+        }
+    }
+    allocator.deallocate_frame(frame);
+}
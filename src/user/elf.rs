@@ -0,0 +1,352 @@
+//! this module impl the ELF64 program loader.
+//! `
+//!     - parse: ELF header (64 bit, little endian, x86_64, ET_EXEC) + program headers
+//!     - validate: PT_LOAD inside the user region below the stack, no overlap, filesz <= memsz,
+//!       vaddr / offset congruent, entry in an executable segment, no PT_INTERP (static only)
+//!     - load: fresh AddressSpace, PT_LOAD pages mapped with R / W / X of p_flags, file bytes copied,
+//!       rest of memsz (.bss) left zero: frames are zeroed by `AddressSpace::map`
+//!     - stack: USER_STACK_SIZE below USER_STACK_TOP, SysV layout from rsp:
+//!       argc | argv[] 0 | envp[] 0 | auxv (AT_PHDR, AT_PHENT, AT_PHNUM, AT_PAGESZ, AT_ENTRY) AT_NULL | strings
+//!     - Program::spawn / exec: enter the entry point in ring 3
+//! `
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use super::{is_user_range, AddressSpace, UserError, USER_END};
+use crate::thread;
+
+/// end of the user stack (initial stack region top).
+pub const USER_STACK_TOP: u64 = USER_END;
+/// mapped user stack bytes, a guard page below stay unmapped.
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+/// lowest address a segment may end at: below the stack guard page.
+pub const USER_IMAGE_END: u64 = USER_STACK_TOP - USER_STACK_SIZE - PAGE_SIZE;
+
+const PAGE_SIZE: u64 = 4096;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+/// loadable segment.
+pub const PT_LOAD: u32 = 1;
+/// dynamic linker path: not supported.
+pub const PT_INTERP: u32 = 3;
+/// segment flags.
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// auxiliary vector keys.
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
+
+/// define ELF loading errors.
+#[derive(Debug)]
+pub enum ElfError {
+    /// not an ELF file.
+    BadMagic,
+    /// not a 64 bit little endian x86_64 executable.
+    Unsupported,
+    /// header or segment past the end of the file.
+    Truncated,
+    /// inconsistent PT_LOAD (filesz > memsz, vaddr / offset not congruent).
+    BadSegment,
+    /// segment outside the user image region.
+    NotUserRange,
+    /// two PT_LOAD segments share a page.
+    Overlap,
+    /// no PT_LOAD segment.
+    NoLoadSegment,
+    /// entry point not in an executable segment.
+    BadEntry,
+    /// argv / envp don't fit the user stack.
+    StackTooLarge,
+    User(UserError),
+}
+
+impl From<UserError> for ElfError {
+    fn from(error: UserError) -> Self {
+        ElfError::User(error)
+    }
+}
+
+/// define one program header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    // [first page, end page) of the segment
+    fn page_range(&self) -> (u64, u64) {
+        let start = self.vaddr & !(PAGE_SIZE - 1);
+        let end = (self.vaddr + self.memsz + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        (start, end)
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        (self.vaddr..self.vaddr + self.memsz).contains(&addr)
+    }
+
+    /// page flags of p_flags (R always: x86 pages are readable).
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= no_execute_flag();
+        }
+        flags
+    }
+}
+
+/// define a parsed and validated ELF64 executable.
+#[derive(Debug)]
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    phoff: u64,
+    pub headers: Vec<ProgramHeader>,
+}
+
+impl<'a> ElfFile<'a> {
+    /// parse and validate the headers of `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        let ident = data.get(..16).ok_or(ElfError::Truncated)?;
+        if ident[..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if ident[4] != ELFCLASS64 || ident[5] != ELFDATA2LSB || ident[6] != EV_CURRENT {
+            return Err(ElfError::Unsupported);
+        }
+        if data.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if read_u16(data, 16)? != ET_EXEC || read_u16(data, 18)? != EM_X86_64 {
+            return Err(ElfError::Unsupported);
+        }
+        let entry = read_u64(data, 24)?;
+        let phoff = read_u64(data, 32)?;
+        let phentsize = read_u16(data, 54)? as usize;
+        let phnum = read_u16(data, 56)? as usize;
+        if phentsize != PHDR_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+
+        let headers = (0..phnum)
+            .map(|index| {
+                let base = usize::try_from(phoff).ok()
+                    .and_then(|phoff| phoff.checked_add(index * PHDR_SIZE))
+                    .ok_or(ElfError::Truncated)?;
+                Ok(ProgramHeader {
+                    p_type: read_u32(data, base)?,
+                    flags: read_u32(data, base + 4)?,
+                    offset: read_u64(data, base + 8)?,
+                    vaddr: read_u64(data, base + 16)?,
+                    filesz: read_u64(data, base + 32)?,
+                    memsz: read_u64(data, base + 40)?,
+                    align: read_u64(data, base + 48)?,
+                })
+            })
+            .collect::<Result<Vec<_>, ElfError>>()?;
+
+        let elf = ElfFile { data, entry, phoff, headers };
+        elf.validate()?;
+        Ok(elf)
+    }
+
+    /// PT_LOAD program headers.
+    pub fn segments(&self) -> impl Iterator<Item = &ProgramHeader> {
+        self.headers.iter().filter(|header| header.p_type == PT_LOAD)
+    }
+
+    fn validate(&self) -> Result<(), ElfError> {
+        if self.headers.iter().any(|header| header.p_type == PT_INTERP) {
+            return Err(ElfError::Unsupported);
+        }
+        let mut pages: Vec<(u64, u64)> = Vec::new();
+        for segment in self.segments() {
+            if segment.filesz > segment.memsz || segment.vaddr % PAGE_SIZE != segment.offset % PAGE_SIZE {
+                return Err(ElfError::BadSegment);
+            }
+            let file_end = segment.offset.checked_add(segment.filesz).ok_or(ElfError::Truncated)?;
+            if file_end > self.data.len() as u64 {
+                return Err(ElfError::Truncated);
+            }
+            let in_image = is_user_range(VirtAddr::try_new(segment.vaddr).map_err(|_| ElfError::NotUserRange)?, segment.memsz)
+                && segment.vaddr + segment.memsz <= USER_IMAGE_END;
+            if !in_image {
+                return Err(ElfError::NotUserRange);
+            }
+            let (start, end) = segment.page_range();
+            if pages.iter().any(|&(other_start, other_end)| start < other_end && other_start < end) {
+                return Err(ElfError::Overlap);
+            }
+            pages.push((start, end));
+        }
+        if pages.is_empty() {
+            return Err(ElfError::NoLoadSegment);
+        }
+        if !self.segments().any(|segment| segment.flags & PF_X != 0 && segment.contains(self.entry)) {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(())
+    }
+
+    // user address of the program headers: inside the PT_LOAD covering them, else 0 (AT_PHDR absent)
+    fn phdr_addr(&self) -> u64 {
+        self.segments()
+            .find(|segment| (segment.offset..segment.offset + segment.filesz).contains(&self.phoff))
+            .map_or(0, |segment| segment.vaddr + (self.phoff - segment.offset))
+    }
+}
+
+/// define a loaded program: its address space, entry point and initial stack pointer.
+#[derive(Debug)]
+pub struct Program {
+    pub space: Arc<AddressSpace>,
+    pub entry: VirtAddr,
+    pub stack_pointer: VirtAddr,
+}
+
+impl Program {
+    /// run the program in a new thread, ended by `SYS_EXIT` or a fault.
+    pub fn spawn(self) -> thread::JoinHandle<()> {
+        super::spawn(self.space, self.entry, self.stack_pointer, 0)
+    }
+
+    /// replace the running thread with the program, never return.
+    pub fn exec(self) -> ! {
+        let Program { space, entry, stack_pointer } = self;
+        space.activate();
+        drop(space);
+        unsafe { super::enter_user_mode(entry, stack_pointer, 0) }
+    }
+}
+
+/// load the ELF64 executable `image` into a fresh address space with a stack holding `argv`, `envp`.
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, ElfError> {
+    let elf = ElfFile::parse(image)?;
+    let space = AddressSpace::new()?;
+
+    for segment in elf.segments().filter(|segment| segment.memsz > 0) {
+        let (start, end) = segment.page_range();
+        // zeroed frames: bytes past filesz (.bss) stay zero
+        space.map(VirtAddr::new(start), end - start, segment.page_flags())?;
+        let offset = segment.offset as usize;
+        space.write(VirtAddr::new(segment.vaddr), &image[offset..offset + segment.filesz as usize])?;
+    }
+
+    let auxv = [
+        (AT_PHDR, elf.phdr_addr()),
+        (AT_PHENT, PHDR_SIZE as u64),
+        (AT_PHNUM, elf.headers.len() as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, elf.entry),
+    ];
+    let stack_pointer = build_stack(&space, argv, envp, &auxv)?;
+    Ok(Program { space, entry: VirtAddr::new(elf.entry), stack_pointer })
+}
+
+// map the user stack, write the SysV initial stack, return rsp (16 byte aligned, pointing at argc)
+fn build_stack(space: &AddressSpace, argv: &[&str], envp: &[&str], auxv: &[(u64, u64)]) -> Result<VirtAddr, ElfError> {
+    let bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    space.map(VirtAddr::new(bottom), USER_STACK_SIZE, PageTableFlags::WRITABLE | no_execute_flag())?;
+
+    // NUL terminated strings at the top
+    let mut strings = Vec::new();
+    let mut offsets = Vec::with_capacity(argv.len() + envp.len());
+    for string in argv.iter().chain(envp) {
+        offsets.push(strings.len() as u64);
+        strings.extend_from_slice(string.as_bytes());
+        strings.push(0);
+    }
+    let strings_start = (USER_STACK_TOP - strings.len() as u64) & !0xf;
+
+    let mut words: Vec<u64> = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend(offsets[..argv.len()].iter().map(|offset| strings_start + offset));
+    words.push(0);
+    words.extend(offsets[argv.len()..].iter().map(|offset| strings_start + offset));
+    words.push(0);
+    for &(key, value) in auxv.iter().chain(&[(AT_NULL, 0)]) {
+        words.push(key);
+        words.push(value);
+    }
+    let stack_pointer = strings_start.checked_sub(words.len() as u64 * 8).ok_or(ElfError::StackTooLarge)? & !0xf;
+    // keep at least one page for the program
+    if stack_pointer < bottom + PAGE_SIZE {
+        return Err(ElfError::StackTooLarge);
+    }
+
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_ne_bytes()).collect();
+    space.write(VirtAddr::new(stack_pointer), &bytes)?;
+    space.write(VirtAddr::new(strings_start), &strings)?;
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+// NO_EXECUTE is a reserved bit (page fault) unless EFER.NXE is set
+fn no_execute_flag() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(offset..offset + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = data.get(offset..offset + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = data.get(offset..offset + 8).ok_or(ElfError::Truncated)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+
+#[test_case]
+fn test_elf_header_rejected() {
+    let mut header = [0u8; EHDR_SIZE];
+    assert!(matches!(ElfFile::parse(&header[..8]), Err(ElfError::Truncated)));
+    assert!(matches!(ElfFile::parse(&header), Err(ElfError::BadMagic)));
+    header[..4].copy_from_slice(&ELF_MAGIC);
+    header[4] = 1; // 32 bit
+    assert!(matches!(ElfFile::parse(&header), Err(ElfError::Unsupported)));
+    header[4..7].copy_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+    header[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    header[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+    header[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    // valid header, no program header
+    assert!(matches!(ElfFile::parse(&header), Err(ElfError::NoLoadSegment)));
+    header[56..58].copy_from_slice(&1u16.to_le_bytes()); // one header past the end
+    header[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+    assert!(matches!(ElfFile::parse(&header), Err(ElfError::Truncated)));
+}
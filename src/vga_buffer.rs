@@ -3,7 +3,8 @@
 use core::fmt;
 use lazy_static::lazy_static;
 use volatile::Volatile;
-use spin::Mutex;
+
+use crate::sync::IrqSpinlock;
 
 /// define the VGA Colors
 #[allow(dead_code)]
@@ -145,12 +146,9 @@ impl fmt::Write for Writer {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    // debug(hardware Timer): print res deal lock 
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(||{
-        WRITER.lock().write_fmt(args).expect("Printing to vga failed.");
-    });
+    // IrqSpinlock: interrupt handler print can't deadlock on the held lock
+    WRITER.lock().write_fmt(args).expect("Printing to vga failed.");
 }
 
 #[macro_export]
@@ -165,9 +163,9 @@ macro_rules! println {
 }
 
 
-// define global writer: spinlock and interior-mutability 
+// define global writer: spinlock(interrupts disabled while held) and interior-mutability 
 lazy_static!{
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: IrqSpinlock<Writer> = IrqSpinlock::new(Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
@@ -191,15 +189,13 @@ fn test_println_many() {
 #[test_case]
 fn test_println_output() {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     let s = "Some test string that fits on a single line";
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
-        }
-    });
+    // interrupts disabled while held: no print between write and check
+    let mut writer = WRITER.lock();
+    writeln!(writer, "\n{}", s).expect("writeln failed");
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        assert_eq!(char::from(screen_char.ascii_character), c);
+    }
 }
\ No newline at end of file
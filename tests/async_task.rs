@@ -0,0 +1,193 @@
+//! test async tasks and the executor
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(async_main);
+
+fn async_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, idt, interrupt)
+    kros::init();
+
+    // memory mapper
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator: tasks are boxed futures
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    kros::deferred::init();
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use kros::deferred::{self, WorkItem};
+use kros::task::{executor::{Executor, SpawnError, TASK_QUEUE_SIZE}, yield_now, Task};
+
+#[test_case]
+fn tasks_run_to_completion() {
+    let mut executor = Executor::new();
+    let result = Rc::new(RefCell::new(0));
+    for i in 1..=3 {
+        let result = result.clone();
+        executor.spawn(Task::new(async move {
+            *result.borrow_mut() += i;
+        })).expect("spawn task");
+    }
+    executor.run_until_idle();
+    assert_eq!(*result.borrow(), 6);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn yield_now_interleave_tasks() {
+    let mut executor = Executor::new();
+    let order = Rc::new(RefCell::new(Vec::new()));
+    for id in 0..2 {
+        let order = order.clone();
+        executor.spawn(Task::new(async move {
+            for step in 0..2 {
+                order.borrow_mut().push((id, step));
+                yield_now().await;
+            }
+        })).expect("spawn task");
+    }
+    executor.run_until_idle();
+    assert_eq!(*order.borrow(), [(0, 0), (1, 0), (0, 1), (1, 1)]);
+}
+
+// waker stored by the future, woken by deferred work (like an interrupt bottom half)
+static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+static READY: AtomicBool = AtomicBool::new(false);
+
+struct WaitDeferred;
+
+impl Future for WaitDeferred {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if READY.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        *WAKER.lock() = Some(context.waker().clone());
+        Poll::Pending
+    }
+}
+
+fn wake_waiter(_: usize) {
+    READY.store(true, Ordering::SeqCst);
+    if let Some(waker) = WAKER.lock().take() {
+        waker.wake();
+    }
+}
+
+#[test_case]
+fn waker_from_deferred_work() {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(WaitDeferred)).expect("spawn task");
+    executor.run_until_idle();
+    assert_eq!(executor.task_count(), 1);
+
+    deferred::schedule(WorkItem::new(wake_waiter, 0)).expect("queue full");
+    executor.run_until_idle();
+    assert_eq!(executor.task_count(), 0);
+}
+
+// woken many times before the executor poll it again
+struct WakeRepeatedly {
+    polls: Rc<RefCell<usize>>,
+}
+
+impl Future for WakeRepeatedly {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        *self.polls.borrow_mut() += 1;
+        if *self.polls.borrow() == 2 {
+            return Poll::Ready(());
+        }
+        for _ in 0..TASK_QUEUE_SIZE * 2 {
+            context.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn repeated_wakes_queue_task_once() {
+    let mut executor = Executor::new();
+    let polls = Rc::new(RefCell::new(0));
+    executor.spawn(Task::new(WakeRepeatedly { polls: polls.clone() })).expect("spawn task");
+    executor.run_until_idle();
+    assert_eq!(*polls.borrow(), 2);
+    assert_eq!(executor.task_count(), 0);
+}
+
+#[test_case]
+fn spawn_refuse_above_queue_size() {
+    let mut executor = Executor::new();
+    for _ in 0..TASK_QUEUE_SIZE {
+        executor.spawn(Task::new(core::future::pending())).expect("spawn task");
+    }
+    assert_eq!(executor.spawn(Task::new(async {})), Err(SpawnError::Full));
+    executor.run_until_idle();
+    assert_eq!(executor.task_count(), TASK_QUEUE_SIZE);
+}
+
+#[test_case]
+fn scancode_stream_wake_consumer() {
+    use futures_util::stream::StreamExt;
+    use kros::keyboard::{self, ScancodeStream};
+
+    let mut executor = Executor::new();
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let consumer = received.clone();
+    executor.spawn(Task::new(async move {
+        let mut scancodes = ScancodeStream::new();
+        while let Some(scancode) = scancodes.next().await {
+            consumer.borrow_mut().push(scancode);
+        }
+    })).expect("spawn task");
+    executor.run_until_idle();
+    assert!(received.borrow().is_empty());
+
+    // `a` press / release, as the interrupt handler push them
+    keyboard::add_scancode(0x1e);
+    keyboard::add_scancode(0x9e);
+    executor.run_until_idle();
+    assert_eq!(*received.borrow(), [0x1e, 0x9e]);
+}
@@ -0,0 +1,66 @@
+//! shared fixture of the user program tests
+//! `
+//!     - layout: code at CODE, one data page at DATA, one stack page below STACK_TOP
+//!     - load: a `global_asm!` program between two labels -> address space / Program
+//!     - free_frames / assert_no_leak: frames, processes back after repeated runs
+//! `
+// each test crate use a part of it
+#![allow(dead_code)]
+
+use alloc::sync::Arc;
+use kros::memory;
+use kros::process;
+use kros::user::{AddressSpace, Program, USER_START};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+pub const CODE: u64 = USER_START;
+pub const DATA: u64 = USER_START + 0x8_0000;
+pub const STACK_BOTTOM: u64 = USER_START + 0x10_0000;
+pub const STACK_TOP: u64 = STACK_BOTTOM + 4096;
+
+/// label of a `global_asm!` program.
+pub type Label = unsafe extern "C" fn();
+
+/// bytes between the labels `start` and `end`.
+pub fn code(start: Label, end: Label) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(start as usize as *const u8, end as usize - start as usize) }
+}
+
+/// address space with `start..end` as read-only code, a data page and a stack page.
+pub fn load_space(start: Label, end: Label) -> Arc<AddressSpace> {
+    let code = code(start, end);
+    let space = AddressSpace::new().expect("address space");
+    space.map(VirtAddr::new(CODE), code.len() as u64, PageTableFlags::empty()).expect("map code");
+    space.write(VirtAddr::new(CODE), code).expect("copy code");
+    space.map(VirtAddr::new(DATA), 4096, PageTableFlags::WRITABLE).expect("map data");
+    space.map(VirtAddr::new(STACK_BOTTOM), 4096, PageTableFlags::WRITABLE).expect("map stack");
+    space
+}
+
+/// `load_space` as a program: entry CODE, stack pointer STACK_TOP.
+pub fn load(start: Label, end: Label) -> Program {
+    Program { space: load_space(start, end), entry: VirtAddr::new(CODE), stack_pointer: VirtAddr::new(STACK_TOP) }
+}
+
+pub fn read_u64(space: &AddressSpace, addr: u64) -> u64 {
+    let mut bytes = [0u8; 8];
+    space.read(VirtAddr::new(addr), &mut bytes).expect("read user memory");
+    u64::from_ne_bytes(bytes)
+}
+
+pub fn free_frames() -> usize {
+    memory::with_kernel_memory(|memory| memory.frame_allocator.free_frames()).unwrap()
+}
+
+/// run `round` `rounds + 1` times: after the first (may map page tables for the stack region)
+/// every frame and process table entry come back.
+pub fn assert_no_leak(rounds: usize, mut round: impl FnMut()) {
+    round();
+    let before = free_frames();
+    for _ in 0..rounds {
+        round();
+    }
+    assert!(process::list().is_empty());
+    assert_eq!(free_frames(), before);
+}
@@ -0,0 +1,83 @@
+//! test deferred work queue in lib.rs
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+entry_point!(deferred_main);
+
+fn deferred_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, idt, interrupt)
+    kros::init();
+
+    // memory mapper
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    kros::deferred::init();
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+use kros::deferred::{self, WorkItem, WORK_QUEUE_SIZE};
+
+static SUM: AtomicUsize = AtomicUsize::new(0);
+
+fn add_to_sum(value: usize) {
+    SUM.fetch_add(value, Ordering::SeqCst);
+}
+
+#[test_case]
+fn run_scheduled_work() {
+    SUM.store(0, Ordering::SeqCst);
+    for i in 1..=10 {
+        deferred::schedule(WorkItem::new(add_to_sum, i)).expect("queue full");
+    }
+    // work only run in worker context
+    assert_eq!(SUM.load(Ordering::SeqCst), 0);
+    assert_eq!(deferred::run_pending(), 10);
+    assert_eq!(SUM.load(Ordering::SeqCst), 55);
+    assert!(!deferred::has_pending());
+}
+
+#[test_case]
+fn full_queue_drops_work() {
+    SUM.store(0, Ordering::SeqCst);
+    let dropped = deferred::dropped();
+    for _ in 0..WORK_QUEUE_SIZE {
+        deferred::schedule(WorkItem::new(add_to_sum, 1)).expect("queue full");
+    }
+    assert!(deferred::schedule(WorkItem::new(add_to_sum, 1)).is_err());
+    assert_eq!(deferred::dropped(), dropped + 1);
+    assert_eq!(deferred::run_pending(), WORK_QUEUE_SIZE);
+    assert_eq!(SUM.load(Ordering::SeqCst), WORK_QUEUE_SIZE);
+}
@@ -0,0 +1,246 @@
+//! test the ELF64 program loader
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(elf_loader_main);
+
+fn elf_loader_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, syscall, idt, interrupt)
+    kros::init();
+
+    // memory mapper
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator: thread table, elf images
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    // thread stacks and user page tables are mapped at runtime
+    memory::init_kernel_memory(mapper, frame_allocator);
+    thread::init().expect("thread init failed");
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+mod common;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use common::{assert_no_leak, code, read_u64};
+use core::arch::global_asm;
+use kros::memory;
+use kros::syscall;
+use kros::thread;
+use kros::user::elf::{self, ElfFile, AT_ENTRY, AT_NULL, AT_PAGESZ, PF_R, PF_W, PF_X, PT_INTERP, PT_LOAD};
+use kros::user::{self, ElfError, FaultKind, USER_START};
+use x86_64::structures::idt::PageFaultErrorCode;
+
+const CODE_VADDR: u64 = USER_START + 0x40_0000;
+const DATA_VADDR: u64 = CODE_VADDR + 0x10_0000;
+const DATA_MAGIC: u64 = 0x1122_3344_5566_7788;
+// .data: magic + 8 bytes; .bss: up to the second page
+const DATA_FILESZ: u64 = 16;
+const DATA_MEMSZ: u64 = 0x2000;
+
+// program: copy what it see of its initial state to .bss, then exit
+global_asm!(
+    ".global elf_program_start",
+    ".global elf_program_end",
+    "elf_program_start:",
+    "    mov rbx, {data}",
+    "    mov rax, [rsp]",           // argc
+    "    mov [rbx + 16], rax",
+    "    mov rax, [rsp + 8]",       // argv[0]
+    "    mov rcx, [rax]",
+    "    mov [rbx + 24], rcx",
+    "    mov rdx, [rsp]",           // envp[0]: after argv[] and its NULL
+    "    mov rax, [rsp + rdx * 8 + 16]",
+    "    mov rcx, [rax]",
+    "    mov [rbx + 32], rcx",
+    "    mov rax, [rbx + 0x1800]",  // .bss in the second page
+    "    mov [rbx + 40], rax",
+    "    mov rax, [rbx]",           // .data copied from the file
+    "    mov [rbx + 48], rax",
+    "    mov [rbx + 56], rsp",
+    "    mov rdi, [rsp + 8]",
+    "    mov rsi, 8",
+    "    mov rax, {write}",
+    "    syscall",
+    "    mov [rbx + 64], rax",
+    "    mov rdi, 0",
+    "    mov rax, {exit}",
+    "    syscall",
+    "elf_program_end:",
+    ".global elf_self_write_start",
+    ".global elf_self_write_end",
+    "elf_self_write_start:",
+    "    lea rax, [rip]",
+    "    mov qword ptr [rax], 0",   // code segment not writable: page fault
+    "2:  jmp 2b",
+    "elf_self_write_end:",
+    data = const DATA_VADDR,
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT,
+);
+
+extern "C" {
+    fn elf_program_start();
+    fn elf_program_end();
+    fn elf_self_write_start();
+    fn elf_self_write_end();
+}
+
+// one program header: type, flags, vaddr, file bytes, memsz
+struct Segment<'a>(u32, u32, u64, &'a [u8], u64);
+
+// ELF64 executable: header, program headers at 64, segment i at file offset (i + 1) pages
+fn build(segments: &[Segment], entry: u64) -> Vec<u8> {
+    let size = (segments.len() + 1) * 0x1000 + 0x1000;
+    let mut image = vec![0u8; size];
+    image[..4].copy_from_slice(b"\x7fELF");
+    image[4..7].copy_from_slice(&[2, 1, 1]); // 64 bit, little endian, version 1
+    image[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    image[18..20].copy_from_slice(&62u16.to_le_bytes()); // x86_64
+    image[20..24].copy_from_slice(&1u32.to_le_bytes());
+    image[24..32].copy_from_slice(&entry.to_le_bytes());
+    image[32..40].copy_from_slice(&64u64.to_le_bytes());
+    image[52..54].copy_from_slice(&64u16.to_le_bytes());
+    image[54..56].copy_from_slice(&56u16.to_le_bytes());
+    image[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+    for (index, Segment(p_type, flags, vaddr, data, memsz)) in segments.iter().enumerate() {
+        let offset = (index as u64 + 1) * 0x1000 + vaddr % 0x1000;
+        let header = 64 + index * 56;
+        image[header..header + 4].copy_from_slice(&p_type.to_le_bytes());
+        image[header + 4..header + 8].copy_from_slice(&flags.to_le_bytes());
+        image[header + 8..header + 16].copy_from_slice(&offset.to_le_bytes());
+        image[header + 16..header + 24].copy_from_slice(&vaddr.to_le_bytes());
+        image[header + 24..header + 32].copy_from_slice(&vaddr.to_le_bytes());
+        image[header + 32..header + 40].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        image[header + 40..header + 48].copy_from_slice(&memsz.to_le_bytes());
+        image[header + 48..header + 56].copy_from_slice(&0x1000u64.to_le_bytes());
+        image[offset as usize..offset as usize + data.len()].copy_from_slice(data);
+    }
+    image
+}
+
+fn program_image() -> Vec<u8> {
+    let program = code(elf_program_start, elf_program_end);
+    let mut data = [0u8; DATA_FILESZ as usize];
+    data[..8].copy_from_slice(&DATA_MAGIC.to_ne_bytes());
+    build(&[
+        Segment(PT_LOAD, PF_R | PF_X, CODE_VADDR, program, program.len() as u64),
+        Segment(PT_LOAD, PF_R | PF_W, DATA_VADDR, &data, DATA_MEMSZ),
+    ], CODE_VADDR)
+}
+
+#[test_case]
+fn elf_program_initial_state() {
+    let image = program_image();
+    let program = elf::load(&image, &["kros-elf", "arg1"], &["HOME=/"]).expect("load failed");
+    let space = program.space.clone();
+    let stack_pointer = program.stack_pointer.as_u64();
+    assert_eq!(program.entry.as_u64(), CODE_VADDR);
+    assert_eq!(stack_pointer % 16, 0);
+
+    // auxv after argc, argv[2] NULL, envp[1] NULL
+    let mut auxv = stack_pointer + 8 * (1 + 3 + 2);
+    let (mut entry, mut page_size) = (0, 0);
+    loop {
+        let (key, value) = (read_u64(&space, auxv), read_u64(&space, auxv + 8));
+        match key {
+            AT_NULL => break,
+            AT_ENTRY => entry = value,
+            AT_PAGESZ => page_size = value,
+            _ => {}
+        }
+        auxv += 16;
+    }
+    assert_eq!((entry, page_size), (CODE_VADDR, 4096));
+
+    assert_eq!(program.spawn().join(), None); // ended by exit
+    assert_eq!(read_u64(&space, DATA_VADDR + 16), 2); // argc
+    assert_eq!(&read_u64(&space, DATA_VADDR + 24).to_ne_bytes(), b"kros-elf");
+    assert_eq!(&read_u64(&space, DATA_VADDR + 32).to_ne_bytes(), b"HOME=/\0\0");
+    assert_eq!(read_u64(&space, DATA_VADDR + 40), 0); // .bss zero filled
+    assert_eq!(read_u64(&space, DATA_VADDR + 48), DATA_MAGIC);
+    assert_eq!(read_u64(&space, DATA_VADDR + 56), stack_pointer);
+    assert_eq!(syscall::decode(read_u64(&space, DATA_VADDR + 64)), Ok(8));
+}
+
+#[test_case]
+fn elf_code_segment_read_only() {
+    let program = code(elf_self_write_start, elf_self_write_end);
+    let image = build(&[Segment(PT_LOAD, PF_R | PF_X, CODE_VADDR, program, program.len() as u64)], CODE_VADDR);
+    let handle = elf::load(&image, &["self-write"], &[]).expect("load failed").spawn();
+    assert_eq!(handle.join(), None);
+    match user::last_fault().expect("no user fault").kind {
+        FaultKind::PageFault { addr, error_code } => {
+            assert_eq!(addr.as_u64(), CODE_VADDR + 7); // after the 7 byte lea
+            assert!(error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::PROTECTION_VIOLATION));
+        }
+        kind => panic!("unexpected fault {:?}", kind),
+    }
+}
+
+#[test_case]
+fn elf_invalid_images_rejected() {
+    let program = code(elf_program_start, elf_program_end);
+    let size = program.len() as u64;
+    let parse = |segments: &[Segment], entry| ElfFile::parse(&build(segments, entry)).map(|_| ());
+
+    // kernel address
+    let kernel = kros::allocator::HEAP_START as u64;
+    assert!(matches!(parse(&[Segment(PT_LOAD, PF_R | PF_X, kernel, program, size)], kernel), Err(ElfError::NotUserRange)));
+    // two segments in one page
+    assert!(matches!(parse(&[
+        Segment(PT_LOAD, PF_R | PF_X, CODE_VADDR, program, size),
+        Segment(PT_LOAD, PF_R | PF_W, CODE_VADDR + 0x800, &[1], 8),
+    ], CODE_VADDR), Err(ElfError::Overlap)));
+    // entry in a non executable segment
+    assert!(matches!(parse(&[Segment(PT_LOAD, PF_R | PF_W, CODE_VADDR, program, size)], CODE_VADDR), Err(ElfError::BadEntry)));
+    // filesz > memsz
+    assert!(matches!(parse(&[Segment(PT_LOAD, PF_R | PF_X, CODE_VADDR, program, 1)], CODE_VADDR), Err(ElfError::BadSegment)));
+    // dynamic executable
+    assert!(matches!(parse(&[
+        Segment(PT_INTERP, PF_R, 0, b"/lib/ld.so\0", 11),
+        Segment(PT_LOAD, PF_R | PF_X, CODE_VADDR, program, size),
+    ], CODE_VADDR), Err(ElfError::Unsupported)));
+    // cut file
+    let image = program_image();
+    assert!(matches!(ElfFile::parse(&image[..0x1800]), Err(ElfError::Truncated)));
+}
+
+#[test_case]
+fn elf_frames_not_leaked() {
+    let image = program_image();
+    assert_no_leak(3, || {
+        elf::load(&image, &["kros-elf"], &[]).expect("load failed").spawn().join();
+    });
+}
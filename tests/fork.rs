@@ -0,0 +1,195 @@
+//! test copy-on-write fork
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(fork_main);
+
+fn fork_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, syscall, idt, interrupt)
+    kros::init();
+
+    // memory mapper
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator: thread and process tables, frame references
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    // thread stacks and user page tables are mapped at runtime
+    memory::init_kernel_memory(mapper, frame_allocator);
+    thread::init().expect("thread init failed");
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+mod common;
+
+use alloc::sync::Arc;
+use common::{assert_no_leak, load, read_u64, CODE, DATA};
+use core::arch::global_asm;
+use kros::memory;
+use kros::process::{self, ExitStatus};
+use kros::syscall;
+use kros::thread;
+use kros::user::address_space::{frame_references, COPY_ON_WRITE};
+use kros::user::AddressSpace;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+// parent and child write the same data page: each see its own value
+global_asm!(
+    ".global fork_program_start",
+    ".global fork_program_end",
+    "fork_program_start:",
+    "    mov rbx, {data}",
+    "    mov qword ptr [rbx], 1",
+    "    push 5",                   // stack page: copy-on-write too
+    "    mov rax, {fork}",
+    "    syscall",
+    "    test rax, rax",
+    "    jz 2f",
+    // parent
+    "    mov [rbx + 24], rax",      // child pid
+    "    mov rdi, rax",
+    "    mov rax, {wait}",
+    "    syscall",
+    "    mov [rbx + 8], rax",       // child wait status
+    "    mov rax, [rbx]",
+    "    mov [rbx + 16], rax",      // still 1
+    "    pop rax",
+    "    mov [rbx + 32], rax",      // still 5
+    "    mov rax, {getpid}",
+    "    syscall",
+    "    mov [rbx + 40], rax",
+    "    mov rdi, 0",
+    "    mov rax, {exit}",
+    "    syscall",
+    // child: exit code 2 + 40 if it see its own writes
+    "2:  mov qword ptr [rbx], 2",
+    "    pop rax",
+    "    add rax, 35",
+    "    mov [rsp - 8], rax",
+    "    mov rdi, [rbx]",
+    "    add rdi, [rsp - 8]",
+    "    mov rax, {exit}",
+    "    syscall",
+    "fork_program_end:",
+    data = const DATA,
+    fork = const syscall::SYS_FORK,
+    wait = const syscall::SYS_WAIT,
+    getpid = const syscall::SYS_GETPID,
+    exit = const syscall::SYS_EXIT,
+);
+
+extern "C" {
+    fn fork_program_start();
+    fn fork_program_end();
+}
+
+fn frame_of(space: &AddressSpace, addr: u64) -> PhysFrame {
+    PhysFrame::containing_address(space.translate(VirtAddr::new(addr)).expect("not mapped"))
+}
+
+#[test_case]
+fn fork_parent_and_child_diverge() {
+    let program = load(fork_program_start, fork_program_end);
+    let space = program.space.clone();
+    let pid = process::spawn(program).expect("spawn failed");
+    assert_eq!(process::wait(pid).unwrap(), ExitStatus::Exited(0));
+
+    let child = read_u64(&space, DATA + 24);
+    assert_ne!(child, 0);
+    assert_ne!(child, read_u64(&space, DATA + 40));
+    assert_eq!(read_u64(&space, DATA + 8), ExitStatus::Exited(42).wait_status());
+    assert_eq!(read_u64(&space, DATA + 16), 1);
+    assert_eq!(read_u64(&space, DATA + 32), 5);
+}
+
+#[test_case]
+fn fork_shares_frames_until_write() {
+    let space = AddressSpace::new().expect("address space");
+    space.map(VirtAddr::new(DATA), 4096, PageTableFlags::WRITABLE).expect("map data");
+    space.write(VirtAddr::new(DATA), b"parent").expect("write");
+    let frame = frame_of(&space, DATA);
+
+    let child = space.fork().expect("fork failed");
+    assert_eq!(frame_of(&child, DATA), frame);
+    assert_eq!(frame_references(frame), 2);
+    assert!(!space.is_mapped(VirtAddr::new(DATA), 8, PageTableFlags::WRITABLE));
+    assert!(space.is_mapped(VirtAddr::new(DATA), 8, COPY_ON_WRITE));
+
+    // the writer get a copy, the other space keep the frame (last sharer: writable again)
+    child.write(VirtAddr::new(DATA), b"child!").expect("write");
+    assert_ne!(frame_of(&child, DATA), frame);
+    assert_eq!(frame_references(frame), 1);
+    let mut bytes = [0u8; 6];
+    space.read(VirtAddr::new(DATA), &mut bytes).unwrap();
+    assert_eq!(&bytes, b"parent");
+    child.read(VirtAddr::new(DATA), &mut bytes).unwrap();
+    assert_eq!(&bytes, b"child!");
+    assert!(space.copy_on_write(VirtAddr::new(DATA)));
+    assert_eq!(frame_of(&space, DATA), frame);
+    assert!(space.is_mapped(VirtAddr::new(DATA), 8, PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn fork_write_read_only_page() {
+    let space = AddressSpace::new().expect("address space");
+    space.map(VirtAddr::new(CODE), 4096, PageTableFlags::empty()).expect("map code");
+    space.write(VirtAddr::new(CODE), b"parent").expect("write");
+    let frame = frame_of(&space, CODE);
+
+    // read-only: shared, not copy-on-write. a kernel write copy it, still read-only
+    let child = space.fork().expect("fork failed");
+    assert_eq!(frame_references(frame), 2);
+    child.write(VirtAddr::new(CODE), b"child!").expect("write");
+    assert_ne!(frame_of(&child, CODE), frame);
+    assert_eq!(frame_references(frame), 1);
+    assert!(!child.is_mapped(VirtAddr::new(CODE), 8, PageTableFlags::WRITABLE));
+    let mut bytes = [0u8; 6];
+    space.read(VirtAddr::new(CODE), &mut bytes).unwrap();
+    assert_eq!(&bytes, b"parent");
+    child.read(VirtAddr::new(CODE), &mut bytes).unwrap();
+    assert_eq!(&bytes, b"child!");
+}
+
+#[test_case]
+fn fork_frames_not_leaked() {
+    assert_no_leak(8, || {
+        let pid = process::spawn(load(fork_program_start, fork_program_end)).expect("spawn failed");
+        assert_eq!(process::wait(pid).unwrap(), ExitStatus::Exited(0));
+        // shared frames freed with the last space mapping them
+        let space = load(fork_program_start, fork_program_end).space;
+        let forks: [Arc<AddressSpace>; 4] = core::array::from_fn(|_| space.fork().expect("fork failed"));
+        drop(space);
+        drop(forks);
+    });
+    assert_eq!(thread::spawn(|| 7).join(), Some(7));
+}
@@ -0,0 +1,93 @@
+//! test ACPI discovery and the HPET driver
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(hpet_main);
+
+fn hpet_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, idt, interrupt, PIT)
+    kros::init();
+
+    // memory mapper: ACPI tables by physical memory offset, HPET registers by map_mmio
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    kros::time::init_hpet(&mut mapper, &mut frame_allocator).expect("HPET init failed");
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+use kros::acpi;
+use kros::time::{self, hpet, Clocksource, Duration, Instant};
+
+#[test_case]
+fn acpi_tables_found() {
+    let (_, rsdp) = acpi::find_rsdp().expect("no RSDP");
+    assert_eq!(&rsdp.signature, b"RSD PTR ");
+    let (_, header) = acpi::find_table(b"HPET").expect("no HPET table");
+    assert_eq!(&header.signature, b"HPET");
+    assert_eq!(acpi::find_table(b"NONE").map(|_| ()), Err(acpi::AcpiError::NotFound(*b"NONE")));
+}
+
+#[test_case]
+fn main_counter_clocksource() {
+    assert!(hpet::is_available());
+    // QEMU HPET: 100 MHz
+    assert!(hpet::frequency().unwrap() >= 10_000_000);
+    assert_ne!(time::clocksource(), Clocksource::Pit);
+
+    let start = time::monotonic_ns();
+    let counter = hpet::counter().unwrap();
+    time::sleep(Duration::from_millis(10));
+    assert!(hpet::counter().unwrap() > counter);
+    let elapsed = time::monotonic_ns() - start;
+    assert!((9_000_000..=13_000_000).contains(&elapsed), "slept 10ms, clocksource: {}ns", elapsed);
+}
+
+#[test_case]
+fn periodic_ticks_replace_pit() {
+    time::use_hpet_ticks(500).expect("HPET periodic failed");
+    assert_eq!(time::frequency(), 500);
+    let start = Instant::now();
+    let hpet_start = hpet::counter().unwrap();
+    time::sleep(Duration::from_millis(40));
+    let ticks = Instant::now().ticks() - start.ticks();
+    let counted = (hpet::counter().unwrap() - hpet_start) * 1000 / hpet::frequency().unwrap();
+
+    // one shot on timer 1 -> IRQ8
+    let one_shots = hpet::one_shots();
+    hpet::one_shot(Duration::from_millis(2)).expect("one shot failed");
+    time::sleep(Duration::from_millis(6));
+    assert_eq!(hpet::one_shots(), one_shots + 1);
+
+    time::use_pit_ticks().unwrap();
+    assert_eq!(time::frequency(), time::DEFAULT_FREQUENCY);
+    // 40ms at 500 Hz: 20 ticks
+    assert!((20..=21).contains(&ticks), "{} HPET ticks in 40ms", ticks);
+    assert!((40..=44).contains(&counted), "main counter: {}ms", counted);
+}
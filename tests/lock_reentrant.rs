@@ -0,0 +1,42 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use kros::{QemuExitCode, exit_qemu, serial_println, serial_print};
+use kros::sync::Spinlock;
+
+static LOCK: Spinlock<u64> = Spinlock::new(0);
+// set just before the second lock: panic from the deadlock report, not elsewhere
+static RELOCKING: AtomicBool = AtomicBool::new(false);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("lock_reentrant::relock_reported... ");
+    relock();      // single test
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop{}
+}
+
+fn relock() {
+    let mut first = LOCK.lock();
+    *first += 1;
+    RELOCKING.store(true, Ordering::SeqCst);
+    // debug build: reported with both locations instead of spinning forever
+    let second = LOCK.lock();
+    drop(second);
+    drop(first);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    if RELOCKING.load(Ordering::SeqCst) && LOCK.holder().is_some() {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
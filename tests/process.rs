@@ -0,0 +1,205 @@
+//! test processes: exit status, wait and frame cleanup
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(process_main);
+
+fn process_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, syscall, idt, interrupt)
+    kros::init();
+
+    // memory mapper
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator: thread and process tables
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    // thread stacks and user page tables are mapped at runtime
+    memory::init_kernel_memory(mapper, frame_allocator);
+    thread::init().expect("thread init failed");
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+mod common;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use common::{assert_no_leak, load};
+use core::arch::global_asm;
+use kros::memory;
+use kros::process::signal::Signal;
+use kros::process::{self, ExitStatus, Pid, ProcessError, ProcessState};
+use kros::syscall;
+use kros::thread;
+use kros::time::{Duration, Instant};
+use kros::user::{self, FaultKind};
+
+const EXIT_BASE: u64 = 100;
+
+// user programs: position independent, copied to a user page
+global_asm!(
+    ".global process_exit_pid_start",
+    ".global process_exit_pid_end",
+    "process_exit_pid_start:",
+    "    mov rax, {getpid}",
+    "    syscall",
+    "    lea rdi, [rax + {base}]",  // exit code: pid + EXIT_BASE
+    "    mov rax, {exit}",
+    "    syscall",
+    "process_exit_pid_end:",
+    ".global process_sleep_start",
+    ".global process_sleep_end",
+    "process_sleep_start:",
+    "    mov rdi, 20",
+    "    mov rax, {sleep}",
+    "    syscall",
+    "    mov rdi, 7",
+    "    mov rax, {exit}",
+    "    syscall",
+    "process_sleep_end:",
+    ".global process_fault_start",
+    ".global process_fault_end",
+    "process_fault_start:",
+    "    mov rax, [0]",             // null pointer: page fault
+    "2:  jmp 2b",
+    "process_fault_end:",
+    ".global process_orphan_start",
+    ".global process_orphan_end",
+    "process_orphan_start:",
+    "    mov rax, {fork}",
+    "    syscall",
+    "    test rax, rax",
+    "    jz 2f",
+    "    mov rdi, 0",               // parent: exit at once, the child outlive it
+    "    mov rax, {exit}",
+    "    syscall",
+    "2:  mov rdi, 50",
+    "    mov rax, {sleep}",
+    "    syscall",
+    "    mov rdi, 3",
+    "    mov rax, {exit}",
+    "    syscall",
+    "process_orphan_end:",
+    getpid = const syscall::SYS_GETPID,
+    exit = const syscall::SYS_EXIT,
+    sleep = const syscall::SYS_SLEEP,
+    fork = const syscall::SYS_FORK,
+    base = const EXIT_BASE,
+);
+
+extern "C" {
+    fn process_exit_pid_start();
+    fn process_exit_pid_end();
+    fn process_sleep_start();
+    fn process_sleep_end();
+    fn process_fault_start();
+    fn process_fault_end();
+    fn process_orphan_start();
+    fn process_orphan_end();
+}
+
+#[test_case]
+fn process_exit_code() {
+    let pid = process::spawn(load(process_exit_pid_start, process_exit_pid_end)).expect("spawn failed");
+    assert_eq!(process::wait(pid).unwrap(), ExitStatus::Exited(pid.as_u64() + EXIT_BASE));
+    // reaped: gone from the table
+    assert!(process::info(pid).is_none());
+    assert!(matches!(process::wait(pid), Err(ProcessError::NoProcess)));
+}
+
+#[test_case]
+fn process_wait_blocks_until_exit() {
+    let start = Instant::now();
+    let pid = process::spawn(load(process_sleep_start, process_sleep_end)).expect("spawn failed");
+    let info = process::info(pid).expect("process missing");
+    assert_eq!(info.parent, None); // spawned by the kernel
+    assert_eq!(info.state, ProcessState::Running);
+    assert_eq!(info.resources, 1); // the console
+    assert!(process::list().iter().any(|info| info.pid == pid));
+
+    assert_eq!(process::wait(pid).unwrap(), ExitStatus::Exited(7));
+    assert!(start.elapsed().as_millis() >= 19);
+}
+
+#[test_case]
+fn process_fault_status() {
+    let pid = process::spawn(load(process_fault_start, process_fault_end)).expect("spawn failed");
+    // SIGSEGV, default action: core dump
+    assert_eq!(process::wait(pid).unwrap(), ExitStatus::Signaled { signal: Signal::SIGSEGV, core_dumped: true });
+    match user::last_fault().map(|fault| fault.kind) {
+        Some(FaultKind::PageFault { addr, .. }) => assert_eq!(addr.as_u64(), 0),
+        fault => panic!("unexpected fault {:?}", fault),
+    }
+    // the kernel keep running
+    assert_eq!(thread::spawn(|| 7).join(), Some(7));
+}
+
+#[test_case]
+fn process_address_space_freed_on_exit() {
+    let program = load(process_exit_pid_start, process_exit_pid_end);
+    let space = Arc::downgrade(&program.space);
+    let pid = process::spawn(program).expect("spawn failed");
+    while space.strong_count() > 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    // user frames already back, before the parent wait
+    assert!(matches!(process::info(pid).unwrap().state, ProcessState::Zombie(ExitStatus::Exited(_))));
+    assert_eq!(process::info(pid).unwrap().resources, 0);
+    process::wait(pid).unwrap();
+}
+
+#[test_case]
+fn processes_not_leaked() {
+    assert_no_leak(4, || {
+        let pids: Vec<Pid> = (0..16)
+            .map(|_| process::spawn(load(process_exit_pid_start, process_exit_pid_end)).expect("spawn failed"))
+            .collect();
+        for pid in pids {
+            assert_eq!(process::wait(pid).unwrap(), ExitStatus::Exited(pid.as_u64() + EXIT_BASE));
+        }
+    });
+}
+
+#[test_case]
+fn fork_orphan_reaped() {
+    let pid = process::spawn(load(process_orphan_start, process_orphan_end)).expect("spawn failed");
+    assert_eq!(process::wait(pid).unwrap(), ExitStatus::Exited(0));
+    // the child is still sleeping: adopted, nobody can wait for it
+    let orphan = process::list().first().copied().expect("orphan already gone");
+    assert_eq!(orphan.parent, None);
+    assert!(process::wait(orphan.pid).is_err());
+    let deadline = Instant::now() + Duration::from_millis(1000);
+    while !process::list().is_empty() {
+        assert!(Instant::now() < deadline, "orphan entry leaked");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
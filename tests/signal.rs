@@ -0,0 +1,301 @@
+//! test signals: handlers, sigreturn, masks, default actions
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(signal_main);
+
+fn signal_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, syscall, idt, interrupt)
+    kros::init();
+
+    // memory mapper
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator: thread and process tables
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    // thread stacks and user page tables are mapped at runtime
+    memory::init_kernel_memory(mapper, frame_allocator);
+    thread::init().expect("thread init failed");
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+mod common;
+
+use common::{assert_no_leak, load, read_u64, DATA};
+use core::arch::global_asm;
+use kros::memory;
+use kros::process::signal::{self, Signal, SIG_BLOCK, SIG_IGN, SIG_UNBLOCK};
+use kros::process::{self, ExitStatus, Pid};
+use kros::syscall::{self, SyscallError};
+use kros::thread;
+const MARKER: u64 = 0x1234;
+// SignalFrame: signal, blocked, then the TrapFrame (rbx: 14th register)
+const FRAME_RBX: u64 = 16 + 13 * 8;
+
+// user programs: position independent, r12 = data page
+global_asm!(
+    // SIGUSR1 handler: record its arguments, clobber registers, return to the restorer
+    ".global signal_handler_start",
+    ".global signal_handler_end",
+    "signal_handler_start:",
+    "    mov r12, {data}",
+    "    mov rdi, {sigusr1}",
+    "    lea rsi, [rip + 3f]",
+    "    lea rdx, [rip + 4f]",
+    "    mov rax, {sigaction}",
+    "    syscall",
+    "    mov [r12], rax",           // old handler: SIG_DFL
+    "    mov rbx, {marker}",
+    "    mov rax, {getpid}",
+    "    syscall",
+    "    mov rdi, rax",
+    "    mov rsi, {sigusr1}",
+    "    mov rax, {kill}",
+    "    syscall",                  // handler run before kill return
+    "    mov [r12 + 24], rax",      // kill result, not the sigreturn one
+    "    mov [r12 + 32], rbx",      // restored
+    "    mov rdi, 0",
+    "    mov rax, {exit}",
+    "    syscall",
+    "3:  mov [r12 + 8], rdi",       // signal number
+    "    mov rax, [rsi + {frame_rbx}]",
+    "    mov [r12 + 16], rax",      // interrupted rbx
+    "    xor ebx, ebx",
+    "    xor r12d, r12d",
+    "    ret",
+    "4:  mov rax, {sigreturn}",
+    "    syscall",
+    "    ud2",
+    "signal_handler_end:",
+    // SIGSEGV handler: exit(signal + 100), never back to the fault
+    ".global signal_segv_start",
+    ".global signal_segv_end",
+    "signal_segv_start:",
+    "    mov rdi, {sigsegv}",
+    "    lea rsi, [rip + 3f]",
+    "    lea rdx, [rip + 4f]",
+    "    mov rax, {sigaction}",
+    "    syscall",
+    "    mov rax, [0]",
+    "    ud2",
+    "3:  add rdi, 100",
+    "    mov rax, {exit}",
+    "    syscall",
+    "4:  ud2",
+    "signal_segv_end:",
+    // blocked SIGUSR1 stay pending until unblocked
+    ".global signal_block_start",
+    ".global signal_block_end",
+    "signal_block_start:",
+    "    mov r12, {data}",
+    "    mov rdi, {sig_block}",
+    "    mov rsi, {sigusr1_bit}",
+    "    mov rax, {sigprocmask}",
+    "    syscall",
+    "    mov [r12], rax",           // old mask: empty
+    "    mov rax, {getpid}",
+    "    syscall",
+    "    mov rdi, rax",
+    "    mov rsi, {sigusr1}",
+    "    mov rax, {kill}",
+    "    syscall",
+    "    mov qword ptr [r12 + 8], 1",
+    "    mov rdi, {sig_unblock}",
+    "    mov rsi, {sigusr1_bit}",
+    "    mov rax, {sigprocmask}",
+    "    syscall",                  // SIGUSR1 default: terminate here
+    "    mov qword ptr [r12 + 16], 1",
+    "    mov rdi, 0",
+    "    mov rax, {exit}",
+    "    syscall",
+    "signal_block_end:",
+    // sleep until a signal end it
+    ".global signal_sleep_start",
+    ".global signal_sleep_end",
+    "signal_sleep_start:",
+    "2:  mov rdi, 1",
+    "    mov rax, {sleep}",
+    "    syscall",
+    "    jmp 2b",
+    "signal_sleep_end:",
+    // SIGCHLD ignored by default, SIGKILL action can't change: exit(child code)
+    ".global signal_child_start",
+    ".global signal_child_end",
+    "signal_child_start:",
+    "    mov r12, {data}",
+    "    mov rdi, {sigkill}",
+    "    mov rsi, {sig_ign}",
+    "    xor edx, edx",
+    "    mov rax, {sigaction}",
+    "    syscall",
+    "    mov [r12], rax",           // -EINVAL
+    "    mov rax, {fork}",
+    "    syscall",
+    "    test rax, rax",
+    "    jz 2f",
+    "    mov rdi, rax",
+    "    mov rax, {wait}",
+    "    syscall",
+    "    mov rdi, rax",
+    "    shr rdi, 8",
+    "    mov rax, {exit}",
+    "    syscall",
+    "2:  mov rdi, 5",
+    "    mov rax, {exit}",
+    "    syscall",
+    "signal_child_end:",
+    data = const DATA,
+    marker = const MARKER,
+    frame_rbx = const FRAME_RBX,
+    sigusr1 = const Signal::SIGUSR1.number(),
+    sigusr1_bit = const 1u64 << Signal::SIGUSR1.number(),
+    sigsegv = const Signal::SIGSEGV.number(),
+    sigkill = const Signal::SIGKILL.number(),
+    sig_ign = const SIG_IGN,
+    sig_block = const SIG_BLOCK,
+    sig_unblock = const SIG_UNBLOCK,
+    getpid = const syscall::SYS_GETPID,
+    exit = const syscall::SYS_EXIT,
+    sleep = const syscall::SYS_SLEEP,
+    fork = const syscall::SYS_FORK,
+    wait = const syscall::SYS_WAIT,
+    kill = const syscall::SYS_KILL,
+    sigaction = const syscall::SYS_SIGACTION,
+    sigprocmask = const syscall::SYS_SIGPROCMASK,
+    sigreturn = const syscall::SYS_SIGRETURN,
+);
+
+extern "C" {
+    fn signal_handler_start();
+    fn signal_handler_end();
+    fn signal_segv_start();
+    fn signal_segv_end();
+    fn signal_block_start();
+    fn signal_block_end();
+    fn signal_sleep_start();
+    fn signal_sleep_end();
+    fn signal_child_start();
+    fn signal_child_end();
+}
+
+fn signaled(signal: Signal, core_dumped: bool) -> ExitStatus {
+    ExitStatus::Signaled { signal, core_dumped }
+}
+
+#[test_case]
+fn signal_handler_and_sigreturn() {
+    let program = load(signal_handler_start, signal_handler_end);
+    let space = program.space.clone();
+    let pid = process::spawn(program).expect("spawn failed");
+    assert_eq!(process::wait(pid).unwrap(), ExitStatus::Exited(0));
+
+    assert_eq!(read_u64(&space, DATA), signal::SIG_DFL);
+    assert_eq!(read_u64(&space, DATA + 8), Signal::SIGUSR1.number());
+    assert_eq!(read_u64(&space, DATA + 16), MARKER);
+    // registers of the interrupted code back after sigreturn
+    assert_eq!(read_u64(&space, DATA + 24), 0);
+    assert_eq!(read_u64(&space, DATA + 32), MARKER);
+}
+
+#[test_case]
+fn signal_default_action_terminates() {
+    let pid = process::spawn(load(signal_sleep_start, signal_sleep_end)).expect("spawn failed");
+    signal::send(pid, Signal::SIGTERM).expect("send failed");
+    assert_eq!(process::wait(pid).unwrap(), signaled(Signal::SIGTERM, false));
+    assert_eq!(signaled(Signal::SIGTERM, false).wait_status(), 15);
+    assert!(signal::send(pid, Signal::SIGTERM).is_err());
+}
+
+#[test_case]
+fn signal_segv_handler() {
+    let pid = process::spawn(load(signal_segv_start, signal_segv_end)).expect("spawn failed");
+    assert_eq!(process::wait(pid).unwrap(), ExitStatus::Exited(Signal::SIGSEGV.number() + 100));
+}
+
+#[test_case]
+fn signal_blocked_stays_pending() {
+    let program = load(signal_block_start, signal_block_end);
+    let space = program.space.clone();
+    let pid = process::spawn(program).expect("spawn failed");
+    assert_eq!(process::wait(pid).unwrap(), signaled(Signal::SIGUSR1, false));
+
+    assert_eq!(read_u64(&space, DATA), 0);
+    assert_eq!(read_u64(&space, DATA + 8), 1); // kill returned: blocked
+    assert_eq!(read_u64(&space, DATA + 16), 0); // delivered once unblocked
+}
+
+#[test_case]
+fn signal_interrupt_foreground() {
+    signal::set_foreground(None);
+    assert!(!signal::interrupt_foreground());
+
+    let pid = process::spawn(load(signal_sleep_start, signal_sleep_end)).expect("spawn failed");
+    signal::set_foreground(Some(pid));
+    assert_eq!(signal::foreground(), Some(pid));
+    assert!(signal::interrupt_foreground());
+    assert_eq!(process::wait(pid).unwrap(), signaled(Signal::SIGINT, false));
+    // reaped: nobody to interrupt
+    assert!(!signal::interrupt_foreground());
+    signal::set_foreground(None);
+}
+
+#[test_case]
+fn signal_sigchld_ignored() {
+    let program = load(signal_child_start, signal_child_end);
+    let space = program.space.clone();
+    let pid = process::spawn(program).expect("spawn failed");
+    assert_eq!(process::wait(pid).unwrap(), ExitStatus::Exited(5));
+    assert_eq!(syscall::decode(read_u64(&space, DATA)), Err(SyscallError::Invalid));
+}
+
+#[test_case]
+fn signal_kernel_thread_not_process() {
+    assert!(signal::send(Pid::from_u64(u64::MAX), Signal::SIGTERM).is_err());
+    assert!(signal::set_action(Signal::SIGKILL, signal::Action::Ignore).is_err());
+    assert!(signal::pending().is_err());
+}
+
+#[test_case]
+fn signal_frames_not_leaked() {
+    assert_no_leak(4, || {
+        for (start, end) in [
+            (signal_handler_start as common::Label, signal_handler_end as common::Label),
+            (signal_segv_start, signal_segv_end),
+            (signal_block_start, signal_block_end),
+        ] {
+            let pid = process::spawn(load(start, end)).expect("spawn failed");
+            process::wait(pid).unwrap();
+        }
+    });
+    assert_eq!(thread::spawn(|| 7).join(), Some(7));
+}
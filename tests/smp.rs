@@ -0,0 +1,104 @@
+//! test SMP bring-up
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(smp_main);
+
+fn smp_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, syscall, idt, interrupt)
+    kros::init();
+
+    // memory mapper: MADT by physical memory offset, local APIC by map_mmio
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator: processor list, GDT / TSS
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    // boot and IST stacks, trampoline identity mapping
+    memory::init_kernel_memory(mapper, frame_allocator);
+    thread::init().expect("thread init failed");
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+use alloc::vec::Vec;
+use kros::acpi;
+use kros::memory;
+use kros::smp;
+use kros::thread;
+
+// QEMU test-args: -smp 4
+const CPUS: usize = 4;
+
+#[test_case]
+fn madt_local_apics() {
+    let mut apic_ids = Vec::new();
+    let base = acpi::local_apics(|local| apic_ids.push(local.apic_id)).expect("no MADT");
+    assert_eq!(base.as_u64(), 0xfee0_0000);
+    assert_eq!(apic_ids.len(), CPUS);
+    assert!(memory::with_kernel_memory(|memory| memory.frame_allocator.real_mode_frame()).unwrap().is_some());
+}
+
+#[test_case]
+fn application_processors_online() {
+    assert_eq!(smp::online_count(), 1);
+    assert_eq!(smp::init().expect("SMP init failed"), CPUS);
+    assert!(smp::current_ap().is_none());
+
+    let cpus = smp::cpus();
+    assert_eq!(cpus.len(), CPUS);
+    assert!(cpus.iter().all(|cpu| cpu.online));
+    assert!(cpus[0].bootstrap && cpus[0].gdt.is_none() && cpus[0].tss.is_none());
+    for (index, cpu) in cpus.iter().enumerate() {
+        assert_eq!(cpu.index, index);
+        assert!(cpus[..index].iter().all(|other| other.apic_id != cpu.apic_id));
+    }
+    // each application processor with its own GDT and TSS
+    let aps = &cpus[1..];
+    assert!(aps.iter().all(|cpu| !cpu.bootstrap && cpu.gdt.is_some() && cpu.tss.is_some()));
+    for (index, cpu) in aps.iter().enumerate() {
+        assert!(aps[..index].iter().all(|other| other.gdt != cpu.gdt && other.tss != cpu.tss));
+    }
+}
+
+#[test_case]
+fn init_twice() {
+    assert_eq!(smp::init().expect("SMP init failed"), CPUS);
+    assert_eq!(smp::cpus().len(), CPUS);
+}
+
+#[test_case]
+fn bootstrap_still_schedules() {
+    let handles: Vec<_> = (0..4u64).map(|n| thread::spawn(move || n * 2)).collect();
+    let results: Vec<_> = handles.into_iter().map(|handle| handle.join()).collect();
+    assert_eq!(results, [Some(0), Some(2), Some(4), Some(6)]);
+    assert!(smp::current_ap().is_none());
+}
@@ -0,0 +1,169 @@
+//! test sleeping synchronization primitives with kernel threads
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(sync_main);
+
+fn sync_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{self, BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, idt, interrupt)
+    kros::init();
+
+    // memory mapper
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator: threads and wait queues
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    // thread stacks are mapped on spawn
+    memory::init_kernel_memory(mapper, frame_allocator);
+    thread::init().expect("thread init failed");
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kros::sync::{Condvar, Mutex, RwLock, Semaphore};
+use kros::thread::{self, ThreadState};
+use kros::time::Duration;
+
+#[test_case]
+fn mutex_serialize_increments() {
+    static COUNTER: Mutex<u64> = Mutex::new(0);
+    *COUNTER.lock() = 0;
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(|| {
+                for _ in 0..50 {
+                    let mut counter = COUNTER.lock();
+                    let value = *counter;
+                    // switch inside the critical section: the others must block, not race
+                    thread::yield_now();
+                    *counter = value + 1;
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join();
+    }
+    assert_eq!(*COUNTER.lock(), 200);
+}
+
+#[test_case]
+fn mutex_waiter_blocked_not_spinning() {
+    static LOCK: Mutex<()> = Mutex::new(());
+    let guard = LOCK.lock();
+    let waiter = thread::spawn(|| drop(LOCK.lock()));
+    thread::sleep(Duration::from_millis(20));
+    let stats = thread::stats_of(waiter.id()).unwrap();
+    assert_eq!(stats.state, ThreadState::Blocked);
+    // parked once: not switched in again while the lock is held
+    assert!(stats.switches <= 1);
+    drop(guard);
+    waiter.join();
+    assert!(!LOCK.is_locked());
+}
+
+#[test_case]
+fn semaphore_limit_concurrency() {
+    static SLOTS: Semaphore = Semaphore::new(2);
+    static INSIDE: AtomicUsize = AtomicUsize::new(0);
+    static MAX_INSIDE: AtomicUsize = AtomicUsize::new(0);
+    let workers: Vec<_> = (0..5)
+        .map(|_| {
+            thread::spawn(|| {
+                SLOTS.acquire();
+                let inside = INSIDE.fetch_add(1, Ordering::SeqCst) + 1;
+                MAX_INSIDE.fetch_max(inside, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(5));
+                INSIDE.fetch_sub(1, Ordering::SeqCst);
+                SLOTS.release();
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join();
+    }
+    assert_eq!(MAX_INSIDE.load(Ordering::SeqCst), 2);
+    assert_eq!(SLOTS.available(), 2);
+}
+
+#[test_case]
+fn condvar_producer_consumer() {
+    static QUEUE: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+    static NOT_EMPTY: Condvar = Condvar::new();
+    let consumer = thread::spawn(|| {
+        let mut sum = 0;
+        for _ in 0..10 {
+            let mut queue = NOT_EMPTY.wait_while(QUEUE.lock(), |queue| queue.is_empty());
+            sum += queue.remove(0);
+        }
+        sum
+    });
+    for value in 1..=10 {
+        QUEUE.lock().push(value);
+        NOT_EMPTY.notify_one();
+        if value % 3 == 0 {
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+    assert_eq!(consumer.join(), Some(55));
+}
+
+#[test_case]
+fn rwlock_shared_readers_exclusive_writer() {
+    static DATA: RwLock<u64> = RwLock::new(0);
+    static READERS_IN: AtomicUsize = AtomicUsize::new(0);
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            thread::spawn(|| {
+                let data = DATA.read();
+                READERS_IN.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                *data
+            })
+        })
+        .collect();
+    thread::sleep(Duration::from_millis(10));
+    // all readers inside at once
+    assert_eq!(DATA.reader_count(), 3);
+    assert!(DATA.try_write().is_none());
+
+    let writer = thread::spawn(|| *DATA.write() = 7);
+    for reader in readers {
+        assert_eq!(reader.join(), Some(0));
+    }
+    writer.join();
+    assert_eq!(READERS_IN.load(Ordering::SeqCst), 3);
+    assert_eq!(*DATA.read(), 7);
+    assert!(!DATA.is_write_locked());
+}
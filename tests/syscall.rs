@@ -0,0 +1,148 @@
+//! test system calls from ring 3
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(syscall_main);
+
+fn syscall_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, syscall, idt, interrupt)
+    kros::init();
+
+    // memory mapper
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator: thread table
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    // thread stacks and user page tables are mapped at runtime
+    memory::init_kernel_memory(mapper, frame_allocator);
+    thread::init().expect("thread init failed");
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+mod common;
+
+use common::{assert_no_leak, load_space, read_u64, CODE, STACK_TOP};
+use core::arch::global_asm;
+use kros::memory;
+use kros::syscall::{self, SyscallError};
+use kros::thread;
+use kros::user::{self, AddressSpace};
+use x86_64::VirtAddr;
+
+// user program: every result pushed on its stack, then exit
+global_asm!(
+    ".global user_calls_start",
+    ".global user_calls_end",
+    "user_calls_start:",
+    "    mov r12, rdi",            // kernel address from the spawner
+    "    lea rdi, [rip + 3f]",
+    "    mov rsi, 6",
+    "    mov rax, {write}",
+    "    syscall",
+    "    push rax",                // [top - 8]: write -> 6
+    "    mov rax, {time}",
+    "    syscall",
+    "    push rax",                // [top - 16]: t0
+    "    mov rdi, 20",
+    "    mov rax, {sleep}",
+    "    syscall",
+    "    mov rax, {time}",
+    "    syscall",
+    "    push rax",                // [top - 24]: t1 >= t0 + 20 ms
+    "    mov rax, {yield_now}",
+    "    syscall",
+    "    push rax",                // [top - 32]: yield -> 0
+    "    mov rdi, r12",
+    "    mov rsi, 8",
+    "    mov rax, {write}",
+    "    syscall",
+    "    push rax",                // [top - 40]: kernel pointer -> -EFAULT
+    "    mov rax, 99",
+    "    syscall",
+    "    push rax",                // [top - 48]: unknown number -> -ENOSYS
+    "    mov rax, {time}",
+    "    int 0x80",
+    "    push rax",                // [top - 56]: time through the gate
+    "    push r12",                // [top - 64]: callee registers kept across calls
+    "    mov rdi, 0",
+    "    mov rax, {exit}",
+    "    syscall",
+    "    ud2",                     // never reached
+    "3:  .ascii \"hello\\n\"",
+    "user_calls_end:",
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT,
+    yield_now = const syscall::SYS_YIELD,
+    sleep = const syscall::SYS_SLEEP,
+    time = const syscall::SYS_TIME,
+);
+
+extern "C" {
+    fn user_calls_start();
+    fn user_calls_end();
+}
+
+static KERNEL_SECRET: u64 = 0x5ec2e7;
+
+// value pushed at [STACK_TOP - 8 * slot]
+fn pushed(space: &AddressSpace, slot: u64) -> u64 {
+    read_u64(space, STACK_TOP - 8 * slot)
+}
+
+#[test_case]
+fn user_system_calls() {
+    let space = load_space(user_calls_start, user_calls_end);
+    let secret = &KERNEL_SECRET as *const u64 as u64;
+    let faults = user::last_fault();
+    let handle = user::spawn(space.clone(), VirtAddr::new(CODE), VirtAddr::new(STACK_TOP), secret);
+    // ended by exit, not by a fault
+    assert_eq!(handle.join(), None);
+    assert_eq!(user::last_fault(), faults);
+
+    assert_eq!(syscall::decode(pushed(&space, 1)), Ok(6));
+    let (t0, t1) = (pushed(&space, 2), pushed(&space, 3));
+    assert!(t1 - t0 >= 19_000_000, "slept {} ns", t1 - t0);
+    assert_eq!(syscall::decode(pushed(&space, 4)), Ok(0));
+    assert_eq!(syscall::decode(pushed(&space, 5)), Err(SyscallError::Fault));
+    assert_eq!(syscall::decode(pushed(&space, 6)), Err(SyscallError::NoSys));
+    assert!(pushed(&space, 7) >= t1);
+    assert_eq!(pushed(&space, 8), secret);
+}
+
+#[test_case]
+fn system_call_frames_not_leaked() {
+    assert_no_leak(3, || {
+        let space = load_space(user_calls_start, user_calls_end);
+        user::spawn(space, VirtAddr::new(CODE), VirtAddr::new(STACK_TOP), 0).join();
+    });
+}
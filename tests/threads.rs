@@ -0,0 +1,223 @@
+//! test preemptive kernel threads
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(threads_main);
+
+fn threads_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, idt, interrupt)
+    kros::init();
+
+    // memory mapper
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator: closures and thread table
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    // thread stacks are mapped on spawn
+    memory::init_kernel_memory(mapper, frame_allocator);
+    thread::init().expect("thread init failed");
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use kros::memory;
+use kros::thread::{self, Priority, ThreadState, WaitQueue, BOOT_THREAD};
+use kros::time::{self, Duration, Instant};
+
+static STOP: AtomicBool = AtomicBool::new(false);
+static COUNTERS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+// times a thread saw the other counter move between two of its own increments
+static INTERLEAVED: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+// busy loop, never yield: only the timer interrupt can switch away
+fn count(me: usize) {
+    let other = 1 - me;
+    let mut last_other = COUNTERS[other].load(Ordering::SeqCst);
+    while !STOP.load(Ordering::SeqCst) {
+        COUNTERS[me].fetch_add(1, Ordering::SeqCst);
+        let now_other = COUNTERS[other].load(Ordering::SeqCst);
+        if now_other != last_other {
+            INTERLEAVED[me].fetch_add(1, Ordering::SeqCst);
+            last_other = now_other;
+        }
+    }
+}
+
+#[test_case]
+fn preemption_interleave_counters() {
+    STOP.store(false, Ordering::SeqCst);
+    let switches = thread::context_switches();
+    let first = thread::spawn(|| count(0));
+    let second = thread::spawn(|| count(1));
+
+    // boot thread sleep: the two busy threads share the CPU by time slice
+    time::sleep(Duration::from_millis(200));
+    STOP.store(true, Ordering::SeqCst);
+    for handle in [&first, &second] {
+        let stats = thread::stats_of(handle.id()).expect("thread removed before join");
+        assert!(stats.runtime > Duration::ZERO);
+        assert!(stats.switches > 1);
+    }
+    first.join();
+    second.join();
+    assert!(thread::context_switches() > switches + 4);
+
+    assert!(COUNTERS[0].load(Ordering::SeqCst) > 0);
+    assert!(COUNTERS[1].load(Ordering::SeqCst) > 0);
+    // each thread was preempted, the other ran, then it resumed: several times
+    assert!(INTERLEAVED[0].load(Ordering::SeqCst) > 1);
+    assert!(INTERLEAVED[1].load(Ordering::SeqCst) > 1);
+}
+
+#[test_case]
+fn join_return_value() {
+    let handle = thread::spawn(|| {
+        assert_ne!(thread::current_id(), BOOT_THREAD);
+        thread::current_id().as_u64() * 2
+    });
+    let id = handle.id();
+    assert_eq!(handle.join(), Some(id.as_u64() * 2));
+    assert_eq!(thread::current_id(), BOOT_THREAD);
+}
+
+#[test_case]
+fn exit_wake_joiner() {
+    let handle = thread::spawn(|| -> u64 {
+        thread::yield_now();
+        thread::exit();
+    });
+    assert_eq!(handle.join(), None);
+}
+
+#[test_case]
+fn yield_alternate_threads() {
+    static TURN: AtomicU64 = AtomicU64::new(0);
+    TURN.store(0, Ordering::SeqCst);
+    // take turns: wait for its turn by yielding to the other thread
+    let player = |me: u64| {
+        move || {
+            for round in 0..5 {
+                while TURN.load(Ordering::SeqCst) != 2 * round + me {
+                    thread::yield_now();
+                }
+                TURN.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    };
+    let ping = thread::spawn(player(0));
+    let pong = thread::spawn(player(1));
+    ping.join();
+    pong.join();
+    assert_eq!(TURN.load(Ordering::SeqCst), 10);
+}
+
+#[test_case]
+fn stacks_reused_after_join() {
+    let free_frames = || memory::with_kernel_memory(|memory| memory.frame_allocator.free_frames()).unwrap();
+    // first spawn may map new page tables for the stack region
+    thread::spawn(|| ()).join();
+    let before = free_frames();
+    for i in 0..20u64 {
+        assert_eq!(thread::spawn(move || i + 1).join(), Some(i + 1));
+    }
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn higher_priority_never_preempted_by_lower() {
+    static LOW_COUNT: AtomicU64 = AtomicU64::new(0);
+    STOP.store(false, Ordering::SeqCst);
+    let low = thread::spawn_with_priority(Priority::Low, || {
+        while !STOP.load(Ordering::SeqCst) {
+            LOW_COUNT.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+    // boot thread blocked: the low thread is the only one ready
+    thread::sleep(Duration::from_millis(20));
+    assert!(LOW_COUNT.load(Ordering::SeqCst) > 0);
+
+    // high busy thread: several time slices, the low thread never run in between
+    let high = thread::spawn_with_priority(Priority::High, || {
+        let before = LOW_COUNT.load(Ordering::SeqCst);
+        let deadline = Instant::now() + Duration::from_millis(50);
+        while Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+        LOW_COUNT.load(Ordering::SeqCst) - before
+    });
+    assert_eq!(high.join(), Some(0));
+    STOP.store(true, Ordering::SeqCst);
+    low.join();
+}
+
+#[test_case]
+fn sleeping_thread_blocked_until_deadline() {
+    let sleeper = thread::spawn(|| {
+        let start = Instant::now();
+        thread::sleep(Duration::from_millis(50));
+        start.elapsed()
+    });
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(thread::stats_of(sleeper.id()).unwrap().state, ThreadState::Blocked);
+    assert!(sleeper.join().unwrap() >= Duration::from_millis(49));
+}
+
+#[test_case]
+fn wait_queue_wake_one() {
+    static QUEUE: WaitQueue = WaitQueue::new();
+    static READY: AtomicBool = AtomicBool::new(false);
+    READY.store(false, Ordering::SeqCst);
+    let waiter = thread::spawn(|| QUEUE.wait_until(|| READY.load(Ordering::SeqCst)));
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(QUEUE.len(), 1);
+    READY.store(true, Ordering::SeqCst);
+    assert!(QUEUE.wake_one());
+    waiter.join();
+    assert!(QUEUE.is_empty());
+}
+
+#[test_case]
+fn wait_queue_woken_from_interrupt() {
+    use kros::time::rtc;
+
+    let start = rtc::periodic_ticks();
+    rtc::enable_periodic(10).expect("bad rate"); // 64 Hz
+    let waiter = thread::spawn(|| {
+        for _ in 0..3 {
+            rtc::wait_periodic();
+        }
+    });
+    waiter.join();
+    rtc::disable_periodic();
+    assert!(rtc::periodic_ticks() >= start + 3);
+}
@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use bootloader::{entry_point, BootInfo};
+use kros::time::{self, Duration, Instant};
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    kros::init(); // PIT at time::DEFAULT_FREQUENCY
+    test_main();
+    kros::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+#[test_case]
+fn configured_frequency() {
+    assert_eq!(time::frequency(), time::DEFAULT_FREQUENCY);
+}
+
+#[test_case]
+fn ticks_advance() {
+    let start = time::ticks();
+    while time::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn sleep_elapsed_ticks_match_rate() {
+    let start = Instant::now();
+    time::sleep(Duration::from_millis(100));
+    let ticks = Instant::now().ticks() - start.ticks();
+    // 100ms at 1000 Hz: ~100 ticks, allow the partial tick at both ends
+    let expected = u64::from(time::frequency()) / 10;
+    assert!((expected..=expected + 2).contains(&ticks), "slept {} ticks, expected {}", ticks, expected);
+    assert!(start.elapsed() >= Duration::from_millis(99));
+}
+
+#[test_case]
+fn instant_arithmetic() {
+    let start = Instant::now();
+    let deadline = start + Duration::from_millis(5);
+    assert!(deadline > start);
+    assert_eq!(start - deadline, Duration::ZERO);
+    time::sleep_until(deadline);
+    assert!(Instant::now() >= deadline);
+}
+
+#[test_case]
+fn wall_clock_from_rtc() {
+    let boot = time::boot_time();
+    assert!(boot.year >= 2000 && (1..=12).contains(&boot.month) && (1..=31).contains(&boot.day));
+    assert!(boot.hour < 24 && boot.minute < 60 && boot.second < 60);
+    // now = boot epoch + ticks, never behind boot and close to RTC
+    let now = time::now();
+    assert!(now >= boot);
+    let rtc = time::rtc::read();
+    assert!(rtc.unix_timestamp().abs_diff(now.unix_timestamp()) <= 2, "rtc {} now {}", rtc, now);
+}
+
+#[test_case]
+fn rtc_periodic_interrupt() {
+    let start = time::rtc::periodic_ticks();
+    time::rtc::enable_periodic(6).expect("rate 6 is 1024 Hz"); // 1024 Hz
+    time::sleep(Duration::from_millis(50));
+    time::rtc::disable_periodic();
+    let count = time::rtc::periodic_ticks() - start;
+    // ~51 interrupts in 50ms
+    assert!((40..=60).contains(&count), "{} rtc interrupts in 50ms", count);
+    assert_eq!(time::rtc::enable_periodic(2), Err(time::rtc::BadRate(2)));
+}
+
+#[test_case]
+fn monotonic_ns_follow_ticks() {
+    let start = time::monotonic_ns();
+    time::sleep(Duration::from_millis(20));
+    let elapsed = time::monotonic_ns() - start;
+    // tsc or pit: ~20ms, pit resolution 1 tick
+    assert!((18_000_000..=25_000_000).contains(&elapsed), "{:?}: {}ns", time::clocksource(), elapsed);
+}
@@ -0,0 +1,104 @@
+//! test timer wheel callbacks run by the deferred worker
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+entry_point!(timer_wheel_main);
+
+fn timer_wheel_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, idt, interrupt, PIT)
+    kros::init();
+
+    // memory mapper
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator, deferred queue run the timer callbacks
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    kros::deferred::init();
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+use kros::deferred::{self, WorkItem};
+use kros::time::{Duration, Instant};
+use kros::timer;
+
+static FIRED: AtomicUsize = AtomicUsize::new(0);
+
+fn record(value: usize) {
+    // callback in deferred context: interrupts enabled
+    assert!(x86_64::instructions::interrupts::are_enabled());
+    FIRED.fetch_add(value, Ordering::SeqCst);
+}
+
+// sleep and run the deferred work like the kernel worker loop
+fn run_for(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        deferred::run_pending();
+        x86_64::instructions::hlt();
+    }
+    deferred::run_pending();
+}
+
+#[test_case]
+fn one_shot_fires_after_deadline() {
+    FIRED.store(0, Ordering::SeqCst);
+    timer::schedule(Instant::now() + Duration::from_millis(20), WorkItem::new(record, 1)).expect("timer pool full");
+    run_for(Duration::from_millis(10));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+    run_for(Duration::from_millis(20));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+    assert_eq!(timer::armed(), 0);
+}
+
+#[test_case]
+fn cancelled_timer_never_fires() {
+    FIRED.store(0, Ordering::SeqCst);
+    let handle = timer::schedule(Instant::now() + Duration::from_millis(10), WorkItem::new(record, 1)).unwrap();
+    assert!(timer::cancel(handle));
+    assert!(!timer::cancel(handle));
+    run_for(Duration::from_millis(20));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+}
+
+#[test_case]
+fn periodic_fires_every_period() {
+    FIRED.store(0, Ordering::SeqCst);
+    let period = Duration::from_millis(10);
+    let handle = timer::schedule_periodic(Instant::now() + period, period, WorkItem::new(record, 1)).unwrap();
+    run_for(Duration::from_millis(105));
+    assert!(timer::cancel(handle));
+    let fired = FIRED.load(Ordering::SeqCst);
+    assert!((9..=11).contains(&fired), "periodic fired {} times in 105ms", fired);
+    assert_eq!(timer::schedule_periodic(Instant::now(), Duration::ZERO, WorkItem::new(record, 1)), Err(timer::TimerError::ZeroPeriod));
+}
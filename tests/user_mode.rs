@@ -0,0 +1,141 @@
+//! test user mode (ring 3) execution
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kros::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(user_mode_main);
+
+fn user_mode_main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+    use kros::allocator;
+    use kros::memory::{BootInfoFrameAllocator, OffsetPageTableWarper};
+
+    // init (gdt, idt, interrupt)
+    kros::init();
+
+    // memory mapper
+    let mut mapper = unsafe {
+        OffsetPageTableWarper::init(
+            VirtAddr::new(
+                boot_info.physical_memory_offset
+            )
+        )
+    };
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    // heap allocator: thread table
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap init failed");
+    // thread stacks and user page tables are mapped at runtime
+    memory::init_kernel_memory(mapper, frame_allocator);
+    thread::init().expect("thread init failed");
+
+    // test
+    test_main();
+    kros::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kros::test_panic_handler(info)
+}
+
+
+// test lib.rs
+mod common;
+
+use common::{free_frames, load_space, CODE, STACK_TOP};
+use core::arch::global_asm;
+use kros::memory;
+use kros::thread;
+use kros::user::{self, AddressSpace, FaultKind};
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+// user programs: position independent, copied to a user page
+global_asm!(
+    ".global user_probe_start",
+    ".global user_probe_end",
+    "user_probe_start:",
+    "    push 42",              // own stack: allowed
+    "    mov rax, [rdi]",       // kernel memory: page fault
+    "2:  jmp 2b",
+    "user_probe_end:",
+    ".global user_cli_start",
+    ".global user_cli_end",
+    "user_cli_start:",
+    "    cli",                  // privileged instruction: general protection
+    "2:  jmp 2b",
+    "user_cli_end:",
+);
+
+extern "C" {
+    fn user_probe_start();
+    fn user_probe_end();
+    fn user_cli_start();
+    fn user_cli_end();
+}
+
+static KERNEL_SECRET: u64 = 0x5ec2e7;
+
+#[test_case]
+fn user_code_fault_on_kernel_memory() {
+    // first spawn may map new page tables for the stack region
+    thread::spawn(|| ()).join();
+    let before = free_frames();
+
+    let space = load_space(user_probe_start, user_probe_end);
+    let secret = &KERNEL_SECRET as *const u64 as u64;
+    let handle = user::spawn(space.clone(), VirtAddr::new(CODE), VirtAddr::new(STACK_TOP), secret);
+    let id = handle.id();
+    // ended by the fault, not by return
+    assert_eq!(handle.join(), None);
+
+    let fault = user::last_fault().expect("no user fault recorded");
+    assert_eq!(fault.thread, id);
+    match fault.kind {
+        FaultKind::PageFault { addr, error_code } => {
+            assert_eq!(addr.as_u64(), secret);
+            // ring 3 touched a present kernel page
+            assert!(error_code.contains(PageFaultErrorCode::USER_MODE | PageFaultErrorCode::PROTECTION_VIOLATION));
+        }
+        kind => panic!("unexpected fault {:?}", kind),
+    }
+    // the user code ran before the fault: its push hit its own stack
+    let mut pushed = [0u8; 8];
+    space.read(VirtAddr::new(STACK_TOP - 8), &mut pushed).expect("read stack");
+    assert_eq!(u64::from_ne_bytes(pushed), 42);
+    assert_eq!(KERNEL_SECRET, 0x5ec2e7);
+
+    // page tables, code and stack frames back to the allocator
+    assert!(!space.is_active());
+    drop(space);
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn user_privileged_instruction_fault() {
+    let space = load_space(user_cli_start, user_cli_end);
+    let handle = user::spawn(space, VirtAddr::new(CODE), VirtAddr::new(STACK_TOP), 0);
+    assert_eq!(handle.join(), None);
+    assert!(matches!(user::last_fault().unwrap().kind, FaultKind::GeneralProtection { .. }));
+
+    // kernel threads still run after the user faults
+    assert_eq!(thread::spawn(|| 7).join(), Some(7));
+}
+
+#[test_case]
+fn user_mapping_outside_user_region_refused() {
+    let space = AddressSpace::new().expect("address space");
+    let kernel = VirtAddr::new(kros::allocator::HEAP_START as u64);
+    assert!(space.map(kernel, 4096, PageTableFlags::WRITABLE).is_err());
+    assert!(space.write(VirtAddr::new(CODE), &[0]).is_err()); // not mapped
+}